@@ -2,6 +2,8 @@ use std::time::Duration;
 
 pub struct Config {
     default_working_directory: Option<String>,
+    locale: Option<String>,
+    report_directory: Option<String>,
     default_termination_timeout: Duration,
     timeout: Duration,
     scenario_timeout: Duration,
@@ -16,6 +18,8 @@ impl Config {
     pub fn new() -> Config {
         Config {
             default_working_directory: None,
+            locale: None,
+            report_directory: None,
             default_termination_timeout: Duration::from_secs(5),
             timeout: Duration::from_secs(3600),
             scenario_timeout: Duration::from_secs(7200),
@@ -38,6 +42,28 @@ impl Config {
         };
     }
 
+    pub fn set_locale(&mut self, locale: Option<&str>) {
+        self.locale = match locale {
+            None => None,
+            Some(s) => Some(s.to_string()),
+        };
+    }
+
+    pub fn locale(&self) -> &Option<String> {
+        &self.locale
+    }
+
+    pub fn set_report_directory(&mut self, dir: Option<&str>) {
+        self.report_directory = match dir {
+            None => None,
+            Some(s) => Some(s.to_string()),
+        };
+    }
+
+    pub fn report_directory(&self) -> &Option<String> {
+        &self.report_directory
+    }
+
     pub fn default_termination_timeout(&self) -> &Duration {
         &self.default_termination_timeout
     }
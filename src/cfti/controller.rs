@@ -1,13 +1,16 @@
 extern crate bus;
+extern crate uuid;
 use std::thread;
 use std::fmt;
 use std::time;
 use std::sync::mpsc;
 use std::sync::{Arc, Mutex};
-use std::ops::DerefMut;
 use std::sync::mpsc::Sender;
 
 use cfti::testset::TestSetCommand;
+use cfti::locale::LocalizedString;
+use cfti::measurement::Measurement;
+use cfti::repeat::TestTally;
 use cfti::types::unit::Unit;
 
 #[derive(Clone, Debug)]
@@ -15,8 +18,9 @@ pub enum BroadcastMessageContents {
     // Hello(String),
     Log(String),
 
-    /// DESCRIBE [type] [field] [item] [value]
-    Describe(String, String, String, String),
+    /// DESCRIBE [type] [field] [item] [value] -- The value carries every translation,
+    /// and each interface picks the one for its own locale.
+    Describe(String, String, String, LocalizedString),
 
     /// SCENARIO [string] -- Sets the scenario to the specified id
     Scenario(String),
@@ -31,7 +35,8 @@ pub enum BroadcastMessageContents {
     Shutdown(String),
 
     /// PING [string] -- Sends a challenge.  Must respond with PONG [string]
-    // Ping(String),
+    Ping(String),
+
     /// TESTS [list of tests] -- Report the tests associated with a scenario
     Tests(String, Vec<String>),
 
@@ -52,25 +57,112 @@ pub enum BroadcastMessageContents {
 
     /// FAIL [test] [reason] -- Report when a test has failed
     Fail(String, String),
+
+    /// STATUS [unit] [state] [reason] -- Report when a long-running unit changes state
+    Status(String, String, String),
+
+    /// UPDATE [updater] [status] [message] -- Report progress of an update bundle
+    Update(String, String, String),
+
+    /// MEASURE [test] [name] [value] [unit] [limit] [result] -- Report a value a test measured
+    Measure(Measurement),
+
+    /// PROGRESS [test] [percent] [message] -- Report how far along a test is
+    Progress(String, u32, String),
+
+    /// ATTACH [test] [path] [description] -- Report a file a test produced
+    Attach(String, String, String),
+
+    /// PROMPT [id] [timeout] [choices] [text] -- Ask the operator a question for a test.
+    /// If there are no choices, any text is a valid answer.
+    Prompt(String, u64, Vec<String>, String),
+
+    /// WAITING [test] [resource] [holder] -- A test is ready to run, but is waiting
+    /// for another test to give up a resource
+    Waiting(String, String, String),
+
+    /// RETRY [test] [attempt] [attempts] [reason] -- Attempt [attempt] of [attempts] at a test
+    /// failed, and it will be run again
+    Retry(String, u32, u32, String),
+
+    /// ITERATION [scenario] [iteration] [iterations] -- A repeated scenario is starting
+    /// another run.  [iterations] is 0 if there's no limit
+    Iteration(String, u32, u32),
+
+    /// SUMMARY [scenario] [passed] [failed] [tallies] -- How each test fared across
+    /// every iteration of a repeated scenario
+    Summary(String, u32, u32, Vec<TestTally>),
+}
+
+impl BroadcastMessageContents {
+    /// The lowercase name of this message, as used by the IPC verbs and by
+    /// the "type" field of JSON records.
+    pub fn verb(&self) -> &'static str {
+        match *self {
+            BroadcastMessageContents::Log(_) => "log",
+            BroadcastMessageContents::Describe(_, _, _, _) => "describe",
+            BroadcastMessageContents::Scenario(_) => "scenario",
+            BroadcastMessageContents::Scenarios(_) => "scenarios",
+            BroadcastMessageContents::Jig(_) => "jig",
+            BroadcastMessageContents::Shutdown(_) => "shutdown",
+            BroadcastMessageContents::Ping(_) => "ping",
+            BroadcastMessageContents::Tests(_, _) => "tests",
+            BroadcastMessageContents::Start(_) => "start",
+            BroadcastMessageContents::Finish(_, _, _) => "finish",
+            BroadcastMessageContents::Skip(_, _) => "skip",
+            BroadcastMessageContents::Running(_) => "running",
+            BroadcastMessageContents::Pass(_, _) => "pass",
+            BroadcastMessageContents::Fail(_, _) => "fail",
+            BroadcastMessageContents::Status(_, _, _) => "status",
+            BroadcastMessageContents::Update(_, _, _) => "update",
+            BroadcastMessageContents::Measure(_) => "measure",
+            BroadcastMessageContents::Progress(_, _, _) => "progress",
+            BroadcastMessageContents::Attach(_, _, _) => "attach",
+            BroadcastMessageContents::Prompt(_, _, _, _) => "prompt",
+            BroadcastMessageContents::Waiting(_, _, _) => "waiting",
+            BroadcastMessageContents::Retry(_, _, _, _) => "retry",
+            BroadcastMessageContents::Iteration(_, _, _) => "iteration",
+            BroadcastMessageContents::Summary(_, _, _, _) => "summary",
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
 pub enum ControlMessageContents {
     Log(String),
     Hello(String),
+
+    /// LOCALE [locale] -- Sets the language this interface wants to receive.
+    Locale(String),
+
+    /// An interface missed a PING deadline or exited, and must be relaunched.
+    RestartInterface(String),
     Scenario(String),
     Pong(String),
     GetScenarios,
     GetJig,
-    GetTests(Option<String>),
     /// TESTS
-    StartScenario(Option<String>),
+    GetTests(Option<String>),
+    /// START [scenario] [count] -- Run a scenario, [count] times if given.
+    StartScenario(Option<String>, Option<u32>),
     AbortTests,
     Shutdown(Option<String>),
 
     /// Causes the currently-executing Scenario to move to the next step.
     AdvanceScenario,
 
+    /// A test failed, but has attempts left, and wants to be run again.
+    RetryTest,
+
+    /// A repeated scenario finished an iteration, and wants to run the next one.
+    RepeatScenario,
+
+    /// An updater has staged a bundle, and wants it installed when it's safe to do so.
+    InstallUpdate,
+
+    /// ANSWER [id] [answer] -- The operator's reply to a PROMPT.
+    Answer(String, String),
+
     /// Sets the communications channel to control the TestSet
     SetTestsetChannel(Sender<TestSetCommand>),
 }
@@ -93,6 +185,19 @@ pub struct BroadcastMessage {
     /// Number of nanoseconds since the epoch
     pub unix_time_nsecs: u32,
 
+    /// Number of seconds since jig-20 started, which never goes backwards
+    pub monotonic_time: u64,
+
+    /// Number of nanoseconds since jig-20 started
+    pub monotonic_time_nsecs: u32,
+
+    /// Position of this message on the bus.  Each message is one more than the last,
+    /// so a listener can tell if it missed any.
+    pub sequence: u64,
+
+    /// The scenario run that was going on when this message was sent, if any.
+    pub run_id: Option<String>,
+
     /// The actual contents of the message being sent.
     pub message: BroadcastMessageContents,
 }
@@ -114,6 +219,12 @@ pub struct ControlMessage {
     /// Number of nanoseconds since the epoch
     pub unix_time_nsecs: u32,
 
+    /// Number of seconds since jig-20 started, which never goes backwards
+    pub monotonic_time: u64,
+
+    /// Number of nanoseconds since jig-20 started
+    pub monotonic_time_nsecs: u32,
+
     /// The actual contents of the message being sent.
     pub message: ControlMessageContents,
 }
@@ -122,10 +233,34 @@ pub struct ControlMessage {
 pub enum ControllerError {
 }
 
+/// The broadcast bus, along with what gets stamped onto each message sent on it.
+pub struct BroadcastBus {
+    bus: bus::Bus<BroadcastMessage>,
+
+    /// The sequence number of the next message.
+    sequence: u64,
+
+    /// The scenario run currently going on, if any.
+    run_id: Option<String>,
+}
+
+impl BroadcastBus {
+    /// Number the message, tag it with the current run, and send it out.
+    fn send(&mut self, mut msg: BroadcastMessage) {
+        msg.sequence = self.sequence;
+        msg.run_id = self.run_id.clone();
+        self.sequence = self.sequence + 1;
+        self.bus.broadcast(msg);
+    }
+}
+
 #[derive(Clone)]
 pub struct Controller {
-    broadcast: Arc<Mutex<bus::Bus<BroadcastMessage>>>,
+    broadcast: Arc<Mutex<BroadcastBus>>,
     control: mpsc::Sender<ControlMessage>,
+
+    /// When the controller was created, which monotonic times are measured from.
+    epoch: time::Instant,
 }
 
 impl fmt::Debug for Controller {
@@ -137,10 +272,15 @@ impl fmt::Debug for Controller {
 impl Controller {
     pub fn new() -> Result<Controller, ControllerError> {
         let (tx, rx) = mpsc::channel();
-        let bus = Arc::new(Mutex::new(bus::Bus::new(4096)));
+        let bus = Arc::new(Mutex::new(BroadcastBus {
+            bus: bus::Bus::new(4096),
+            sequence: 0,
+            run_id: None,
+        }));
         let controller = Controller {
             broadcast: bus.clone(),
             control: tx,
+            epoch: time::Instant::now(),
         };
 
         // The controller runs in its own thread.
@@ -151,8 +291,7 @@ impl Controller {
         Ok(controller)
     }
 
-    pub fn controller_thread(rx: mpsc::Receiver<ControlMessage>,
-                             bus: Arc<Mutex<bus::Bus<BroadcastMessage>>>) {
+    pub fn controller_thread(rx: mpsc::Receiver<ControlMessage>, bus: Arc<Mutex<BroadcastBus>>) {
         let mut testset_opt: Option<Sender<TestSetCommand>> = None;
         loop {
             let msg = match rx.recv() {
@@ -184,9 +323,13 @@ impl Controller {
                         unit_type: msg.unit_type,
                         unix_time: msg.unix_time,
                         unix_time_nsecs: msg.unix_time_nsecs,
+                        monotonic_time: msg.monotonic_time,
+                        monotonic_time_nsecs: msg.monotonic_time_nsecs,
+                        sequence: 0,
+                        run_id: None,
                         message: BroadcastMessageContents::Log(l),
                     };
-                    bus.lock().unwrap().deref_mut().broadcast(bc_msg);
+                    bus.lock().unwrap().send(bc_msg);
                 }
 
                 // Get the current jig information and broadcast it on the bus.
@@ -205,6 +348,11 @@ impl Controller {
                     testset.send(TestSetCommand::SetInterfaceHello(msg.unit_id, s)).unwrap();
                 }
 
+                // An interface wants to hear things in a different language.
+                ControlMessageContents::Locale(s) => {
+                    testset.send(TestSetCommand::SetInterfaceLocale(msg.unit_id, s)).unwrap();
+                }
+
                 ControlMessageContents::Shutdown(s) => {
                     let reason = match s {
                         Some(s) => s,//println!("Shutdown called: {}", s),
@@ -216,18 +364,26 @@ impl Controller {
                         unit_type: msg.unit_type,
                         unix_time: msg.unix_time,
                         unix_time_nsecs: msg.unix_time_nsecs,
+                        monotonic_time: msg.monotonic_time,
+                        monotonic_time_nsecs: msg.monotonic_time_nsecs,
+                        sequence: 0,
+                        run_id: None,
                         message: BroadcastMessageContents::Shutdown(reason),
                     };
-                    bus.lock().unwrap().deref_mut().broadcast(bc_msg);
+                    bus.lock().unwrap().send(bc_msg);
                     testset.send(TestSetCommand::Shutdown).unwrap();
                 }
 
-                // Respond to a PING command.  Unimplemented.
+                // Responses to PING are checked by each interface's own watchdog.
                 ControlMessageContents::Pong(_) => (),
 
+                ControlMessageContents::RestartInterface(reason) => {
+                    testset.send(TestSetCommand::RestartInterface(msg.unit_id, reason)).unwrap();
+                }
+
                 // Start running tests.
-                ControlMessageContents::StartScenario(s) => {
-                    testset.send(TestSetCommand::StartScenario(s)).unwrap();
+                ControlMessageContents::StartScenario(s, count) => {
+                    testset.send(TestSetCommand::StartScenario(s, count)).unwrap();
                 }
                 ControlMessageContents::AbortTests => {
                     testset.send(TestSetCommand::AbortTests).unwrap()
@@ -235,6 +391,18 @@ impl Controller {
                 ControlMessageContents::AdvanceScenario => {
                     testset.send(TestSetCommand::AdvanceScenario).unwrap();
                 }
+                ControlMessageContents::RetryTest => {
+                    testset.send(TestSetCommand::RetryTest(msg.unit_id)).unwrap();
+                }
+                ControlMessageContents::RepeatScenario => {
+                    testset.send(TestSetCommand::RepeatScenario).unwrap();
+                }
+                ControlMessageContents::InstallUpdate => {
+                    testset.send(TestSetCommand::InstallUpdate(msg.unit_id)).unwrap();
+                }
+                ControlMessageContents::Answer(id, answer) => {
+                    testset.send(TestSetCommand::Answer(id, answer)).unwrap();
+                }
 
                 ControlMessageContents::GetScenarios => {
                     testset.send(TestSetCommand::SendScenarios).unwrap()
@@ -264,8 +432,9 @@ impl Controller {
         where F: Send + 'static + FnMut(BroadcastMessage) -> Result<(), String>
     {
 
-        let mut console_rx_channel = self.broadcast.lock().unwrap().deref_mut().add_rx();
+        let mut console_rx_channel = self.broadcast.lock().unwrap().bus.add_rx();
         let broadcaster = self.broadcast.clone();
+        let epoch = self.epoch;
         let builder = thread::Builder::new().name("B-Hook".into());
         builder.spawn(move ||
             loop {
@@ -273,6 +442,7 @@ impl Controller {
                     Err(e) => { println!("DEBUG!! Channel closed, probably quitting.  Err: {:?}", e); return; },
                     Ok(msg) => if let Err(e) = broadcast_func(msg) {
                         Self::do_broadcast_class(&broadcaster,
+                                    &epoch,
                                     "debug",
                                     "controller",
                                     "controller",
@@ -290,11 +460,21 @@ impl Controller {
                          unit_name: &str,
                          unit_type: &str,
                          contents: &ControlMessageContents) {
-        Self::do_control_class(&self.control, message_class, unit_name, unit_type, contents)
+        Self::do_control_class(&self.control,
+                               &self.epoch,
+                               message_class,
+                               unit_name,
+                               unit_type,
+                               contents)
     }
 
     pub fn control(&self, unit_name: &str, unit_type: &str, contents: &ControlMessageContents) {
-        Self::do_control_class(&self.control, "standard", unit_name, unit_type, contents)
+        Self::do_control_class(&self.control,
+                               &self.epoch,
+                               "standard",
+                               unit_name,
+                               unit_type,
+                               contents)
     }
 
     pub fn control_class_unit<T: Unit + ?Sized>(message_class: &str,
@@ -309,6 +489,7 @@ impl Controller {
 
     pub fn shutdown(&self, msg: &str) {
         Self::do_control_class(&self.control,
+                               &self.epoch,
                                "system",
                                "none",
                                "none",
@@ -321,6 +502,7 @@ impl Controller {
                            unit_type: &str,
                            contents: &BroadcastMessageContents) {
         Self::do_broadcast_class(&self.broadcast,
+                                 &self.epoch,
                                  message_class,
                                  unit_name,
                                  unit_type,
@@ -341,7 +523,12 @@ impl Controller {
                      unit_name: &str,
                      unit_type: &str,
                      contents: &BroadcastMessageContents) {
-        Self::do_broadcast_class(&self.broadcast, "standard", unit_name, unit_type, contents)
+        Self::do_broadcast_class(&self.broadcast,
+                                 &self.epoch,
+                                 "standard",
+                                 unit_name,
+                                 unit_type,
+                                 contents)
     }
 
     // pub fn debug(&self, unit_name: &str, unit_type: &str, msg: String) {
@@ -360,7 +547,18 @@ impl Controller {
         Self::broadcast_class_unit("warning", unit, &BroadcastMessageContents::Log(msg))
     }
 
-    fn do_broadcast_class(bus: &Arc<Mutex<bus::Bus<BroadcastMessage>>>,
+    /// Tag every message from now on with `run_id`, or with nothing if it's None.
+    pub fn set_run_id(&self, run_id: Option<String>) {
+        self.broadcast.lock().unwrap().run_id = run_id;
+    }
+
+    /// Make up a new, unique id for a scenario run.
+    pub fn new_run_id() -> String {
+        format!("{}", uuid::Uuid::new_v4())
+    }
+
+    fn do_broadcast_class(bus: &Arc<Mutex<BroadcastBus>>,
+                          epoch: &time::Instant,
                           message_class: &str,
                           unit_name: &str,
                           unit_type: &str,
@@ -371,17 +569,25 @@ impl Controller {
             Err(_) => time::Duration::new(0, 0),
         };
 
-        bus.lock().unwrap().deref_mut().broadcast(BroadcastMessage {
+        // Read the clock under the lock, so that our monotonic times rise with sequence numbers.
+        let mut bus = bus.lock().unwrap();
+        let monotonic = epoch.elapsed();
+        bus.send(BroadcastMessage {
             message_class: message_class.to_string(),
             unit_id: unit_name.to_string(),
             unit_type: unit_type.to_string(),
             unix_time: elapsed.as_secs(),
             unix_time_nsecs: elapsed.subsec_nanos(),
+            monotonic_time: monotonic.as_secs(),
+            monotonic_time_nsecs: monotonic.subsec_nanos(),
+            sequence: 0,
+            run_id: None,
             message: contents.clone(),
         });
     }
 
     fn do_control_class(control: &mpsc::Sender<ControlMessage>,
+                        epoch: &time::Instant,
                         message_class: &str,
                         unit_name: &str,
                         unit_type: &str,
@@ -392,6 +598,7 @@ impl Controller {
             Ok(d) => d,
             Err(_) => time::Duration::new(0, 0),
         };
+        let monotonic = epoch.elapsed();
 
         control.send(ControlMessage {
                 message_class: message_class.to_string(),
@@ -399,6 +606,8 @@ impl Controller {
                 unit_type: unit_type.to_string(),
                 unix_time: elapsed.as_secs(),
                 unix_time_nsecs: elapsed.subsec_nanos(),
+                monotonic_time: monotonic.as_secs(),
+                monotonic_time_nsecs: monotonic.subsec_nanos(),
                 message: contents.clone(),
             })
             .unwrap();
@@ -0,0 +1,152 @@
+/// Filters that let loggers and interfaces pick which messages they receive,
+/// by message class and by the unit that sent them.
+
+use cfti::controller::BroadcastMessage;
+use cfti::unitfile::UnitFile;
+
+#[derive(Debug, Clone)]
+pub struct MessageFilter {
+    /// classes: Only pass these message classes, or every class if None.
+    classes: Option<Vec<String>>,
+
+    /// exclude_classes: Never pass these message classes, even if they're in `classes`.
+    exclude_classes: Vec<String>,
+
+    /// units: Only pass messages from these units, or from every unit if None.
+    units: Option<Vec<String>>,
+
+    /// unit_types: Only pass messages from these kinds of units, or from every kind if None.
+    unit_types: Option<Vec<String>>,
+}
+
+fn parse_list(s: &str) -> Vec<String> {
+    s.split(|c| c == ',' || c == ' ')
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+impl MessageFilter {
+    /// Read the Classes, ExcludeClasses, Units, and UnitTypes keys from `section`.
+    pub fn from_unit_file(unit_file: &UnitFile, section: &str) -> MessageFilter {
+        MessageFilter {
+            classes: unit_file.get(section, "Classes").map(parse_list),
+            exclude_classes: match unit_file.get(section, "ExcludeClasses") {
+                None => vec![],
+                Some(s) => parse_list(s),
+            },
+            units: unit_file.get(section, "Units").map(parse_list),
+            unit_types: unit_file.get(section, "UnitTypes").map(parse_list),
+        }
+    }
+
+    /// Returns true if `msg` should be passed on.
+    pub fn matches(&self, msg: &BroadcastMessage) -> bool {
+        if self.exclude_classes.contains(&msg.message_class) {
+            return false;
+        }
+        if let Some(ref classes) = self.classes {
+            if !classes.contains(&msg.message_class) {
+                return false;
+            }
+        }
+        if let Some(ref units) = self.units {
+            if !units.contains(&msg.unit_id) {
+                return false;
+            }
+        }
+        if let Some(ref unit_types) = self.unit_types {
+            if !unit_types.contains(&msg.unit_type) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cfti::controller::BroadcastMessageContents;
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+
+    fn load_filter(name: &str, section: &str) -> MessageFilter {
+        let path = env::temp_dir().join(format!("jig-20-filter-{}.logger", name));
+        fs::File::create(&path)
+            .unwrap()
+            .write_all(format!("[Logger]\nName=Filter\n{}", section).as_bytes())
+            .unwrap();
+        let unit_file = UnitFile::new(path.to_str().unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+        MessageFilter::from_unit_file(&unit_file, "Logger")
+    }
+
+    fn message(class: &str, unit_id: &str, unit_type: &str) -> BroadcastMessage {
+        BroadcastMessage {
+            message_class: class.to_string(),
+            unit_id: unit_id.to_string(),
+            unit_type: unit_type.to_string(),
+            unix_time: 0,
+            unix_time_nsecs: 0,
+            monotonic_time: 0,
+            monotonic_time_nsecs: 0,
+            sequence: 0,
+            run_id: None,
+            message: BroadcastMessageContents::Log("hello".to_string()),
+        }
+    }
+
+    #[test]
+    fn everything_passes_by_default() {
+        let filter = load_filter("default", "");
+        assert!(filter.matches(&message("normal", "power", "test")));
+        assert!(filter.matches(&message("internal-debug", "file", "logger")));
+    }
+
+    #[test]
+    fn classes() {
+        let filter = load_filter("classes", "Classes=normal, internal-status\n");
+        assert!(filter.matches(&message("normal", "power", "test")));
+        assert!(filter.matches(&message("internal-status", "power", "test")));
+        assert!(!filter.matches(&message("internal-debug", "power", "test")));
+    }
+
+    #[test]
+    fn exclude_classes_overrides_classes() {
+        let filter = load_filter("exclude",
+                                 "Classes=normal internal-debug\nExcludeClasses=internal-debug\n");
+        assert!(filter.matches(&message("normal", "power", "test")));
+        assert!(!filter.matches(&message("internal-debug", "power", "test")));
+
+        let filter = load_filter("exclude-only", "ExcludeClasses=internal-debug\n");
+        assert!(filter.matches(&message("anything", "power", "test")));
+        assert!(!filter.matches(&message("internal-debug", "power", "test")));
+    }
+
+    #[test]
+    fn units() {
+        let filter = load_filter("units", "Units=power,usb\n");
+        assert!(filter.matches(&message("normal", "power", "test")));
+        assert!(filter.matches(&message("normal", "usb", "test")));
+        assert!(!filter.matches(&message("normal", "wifi", "test")));
+    }
+
+    #[test]
+    fn unit_types() {
+        let filter = load_filter("unit-types", "UnitTypes=test scenario\n");
+        assert!(filter.matches(&message("normal", "power", "test")));
+        assert!(filter.matches(&message("normal", "factory", "scenario")));
+        assert!(!filter.matches(&message("normal", "file", "logger")));
+    }
+
+    #[test]
+    fn every_key_must_match() {
+        let filter = load_filter("all", "Classes=normal\nUnits=power\nUnitTypes=test\n");
+        assert!(filter.matches(&message("normal", "power", "test")));
+        assert!(!filter.matches(&message("internal-status", "power", "test")));
+        assert!(!filter.matches(&message("normal", "usb", "test")));
+        assert!(!filter.matches(&message("normal", "power", "interface")));
+    }
+}
@@ -0,0 +1,467 @@
+/// A built-in HTTP interface, serving the state of the jig as JSON and
+/// streaming the broadcast bus over a WebSocket.
+
+extern crate base64;
+extern crate json;
+extern crate ring;
+extern crate tiny_http;
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, TrySendError};
+use std::thread;
+
+use self::ring::digest;
+use self::tiny_http::{Header, Method, Request, Response, Server};
+
+use cfti::controller::{BroadcastMessage, BroadcastMessageContents, ControlMessageContents};
+use cfti::filter::MessageFilter;
+use cfti::locale::LocalizedString;
+use cfti::types::Interface;
+use cfti::types::unit::{Unit, SimpleUnit};
+
+/// Appended to the client's key to form the Sec-WebSocket-Accept header, per RFC 6455.
+const WEBSOCKET_GUID: &'static str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// The WebSocket frame opcode for text, per RFC 6455.
+const OPCODE_TEXT: u8 = 0x1;
+
+/// How many frames may be waiting on a WebSocket client that isn't keeping up,
+/// before it's dropped.
+const WEBSOCKET_QUEUE_SIZE: usize = 1024;
+
+#[derive(Debug)]
+pub enum HttpError {
+    BindFailed(String),
+    SpawnFailed(String),
+}
+
+/// The result of a single test, as last heard on the bus.
+#[derive(Debug, Clone)]
+struct TestResult {
+    state: String,
+    message: String,
+}
+
+/// A question a test is waiting on the operator to answer.
+#[derive(Debug, Clone)]
+struct PendingPrompt {
+    test: String,
+    id: String,
+    timeout: u64,
+    choices: Vec<String>,
+    text: String,
+}
+
+/// Everything the HTTP interface knows, built up by watching broadcasts.
+#[derive(Debug)]
+struct RunState {
+    jig: Option<String>,
+    scenarios: Vec<String>,
+    scenario: Option<String>,
+    tests: HashMap<String, Vec<String>>,
+
+    /// Names and descriptions, keyed by (type, id, field).
+    descriptions: HashMap<(String, String, String), LocalizedString>,
+
+    /// "idle", "running", or "finished".
+    state: String,
+    running: Option<String>,
+    results: HashMap<String, TestResult>,
+    finish: Option<(u32, String)>,
+    prompt: Option<PendingPrompt>,
+}
+
+impl RunState {
+    fn new() -> RunState {
+        RunState {
+            jig: None,
+            scenarios: vec![],
+            scenario: None,
+            tests: HashMap::new(),
+            descriptions: HashMap::new(),
+            state: "idle".to_string(),
+            running: None,
+            results: HashMap::new(),
+            finish: None,
+            prompt: None,
+        }
+    }
+
+    fn update(&mut self, msg: &BroadcastMessage) {
+        match msg.message {
+            BroadcastMessageContents::Jig(ref j) => self.jig = Some(j.clone()),
+            BroadcastMessageContents::Describe(ref class, ref field, ref name, ref value) => {
+                self.descriptions.insert((class.to_lowercase(), name.clone(), field.clone()),
+                                         value.clone());
+            }
+            BroadcastMessageContents::Scenarios(ref list) => self.scenarios = list.clone(),
+            BroadcastMessageContents::Scenario(ref s) => self.scenario = Some(s.clone()),
+            BroadcastMessageContents::Tests(ref scenario, ref list) => {
+                self.tests.insert(scenario.clone(), list.clone());
+            }
+            BroadcastMessageContents::Start(ref scenario) => {
+                self.scenario = Some(scenario.clone());
+                self.state = "running".to_string();
+                self.running = None;
+                self.results.clear();
+                self.finish = None;
+                self.prompt = None;
+            }
+            BroadcastMessageContents::Running(ref test) => {
+                self.running = Some(test.clone());
+                self.set_result(test, "running", "");
+            }
+            BroadcastMessageContents::Pass(ref test, ref message) => {
+                self.set_result(test, "pass", message)
+            }
+            BroadcastMessageContents::Fail(ref test, ref message) => {
+                self.set_result(test, "fail", message)
+            }
+            BroadcastMessageContents::Skip(ref test, ref message) => {
+                self.set_result(test, "skip", message)
+            }
+            BroadcastMessageContents::Finish(_, code, ref reason) => {
+                self.state = "finished".to_string();
+                self.running = None;
+                self.finish = Some((code, reason.clone()));
+                self.prompt = None;
+            }
+            BroadcastMessageContents::Prompt(ref id, timeout, ref choices, ref text) => {
+                self.prompt = Some(PendingPrompt {
+                    test: msg.unit_id.clone(),
+                    id: id.clone(),
+                    timeout: timeout,
+                    choices: choices.clone(),
+                    text: text.clone(),
+                });
+            }
+            _ => (),
+        }
+    }
+
+    fn set_result(&mut self, test: &str, state: &str, message: &str) {
+        // Once a test has moved on, it isn't waiting on its prompt anymore.
+        if self.prompt.as_ref().map(|p| p.test == test) == Some(true) {
+            self.prompt = None;
+        }
+        self.results.insert(test.to_string(),
+                            TestResult {
+                                state: state.to_string(),
+                                message: message.to_string(),
+                            });
+    }
+
+    fn describe(&self,
+                kind: &str,
+                id: &str,
+                locale: &Option<String>)
+                -> json::JsonValue {
+        let mut object = json::JsonValue::new_object();
+        object["id"] = id.into();
+        for field in &["name", "description"] {
+            let key = (kind.to_string(), id.to_string(), field.to_string());
+            object[*field] = match self.descriptions.get(&key) {
+                None => json::JsonValue::Null,
+                Some(s) => s.get(locale).into(),
+            };
+        }
+        object
+    }
+
+    fn jig_json(&self, locale: &Option<String>) -> json::JsonValue {
+        match self.jig {
+            None => json::JsonValue::Null,
+            Some(ref j) => self.describe("jig", j, locale),
+        }
+    }
+
+    fn scenarios_json(&self, locale: &Option<String>) -> json::JsonValue {
+        let mut scenarios = json::JsonValue::new_array();
+        for id in &self.scenarios {
+            let mut scenario = self.describe("scenario", id, locale);
+            let mut tests = json::JsonValue::new_array();
+            for test in self.tests.get(id).unwrap_or(&vec![]) {
+                tests.push(test.as_str()).ok();
+            }
+            scenario["tests"] = tests;
+            scenarios.push(scenario).ok();
+        }
+
+        let mut object = json::JsonValue::new_object();
+        object["selected"] = match self.scenario {
+            None => json::JsonValue::Null,
+            Some(ref s) => s.as_str().into(),
+        };
+        object["scenarios"] = scenarios;
+        object
+    }
+
+    fn tests_json(&self, locale: &Option<String>) -> json::JsonValue {
+        let mut tests = json::JsonValue::new_array();
+        let scenario = match self.scenario {
+            None => return tests,
+            Some(ref s) => s,
+        };
+        for id in self.tests.get(scenario).unwrap_or(&vec![]) {
+            let mut test = self.describe("test", id, locale);
+            match self.results.get(id) {
+                None => {
+                    test["state"] = "pending".into();
+                    test["message"] = "".into();
+                }
+                Some(r) => {
+                    test["state"] = r.state.as_str().into();
+                    test["message"] = r.message.as_str().into();
+                }
+            }
+            tests.push(test).ok();
+        }
+        tests
+    }
+
+    fn state_json(&self) -> json::JsonValue {
+        let mut object = json::JsonValue::new_object();
+        object["state"] = self.state.as_str().into();
+        object["scenario"] = match self.scenario {
+            None => json::JsonValue::Null,
+            Some(ref s) => s.as_str().into(),
+        };
+        object["running"] = match self.running {
+            None => json::JsonValue::Null,
+            Some(ref s) => s.as_str().into(),
+        };
+        match self.finish {
+            None => {
+                object["result"] = json::JsonValue::Null;
+                object["reason"] = json::JsonValue::Null;
+            }
+            Some((code, ref reason)) => {
+                object["result"] = code.into();
+                object["reason"] = reason.as_str().into();
+            }
+        }
+        object["prompt"] = match self.prompt {
+            None => json::JsonValue::Null,
+            Some(ref p) => {
+                let mut prompt = json::JsonValue::new_object();
+                prompt["id"] = p.id.as_str().into();
+                prompt["test"] = p.test.as_str().into();
+                prompt["timeout"] = p.timeout.into();
+                prompt["choices"] = p.choices.clone().into();
+                prompt["text"] = p.text.as_str().into();
+                prompt
+            }
+        };
+        object
+    }
+}
+
+/// Start serving HTTP on `addr`.
+///
+/// `is_current` is checked before each request, and the server stops once it returns false.
+pub fn start<F>(addr: &str,
+                unit: SimpleUnit,
+                locale: Arc<Mutex<Option<String>>>,
+                filter: MessageFilter,
+                is_current: F)
+                -> Result<(), HttpError>
+    where F: Send + 'static + Fn() -> bool
+{
+    let server = match Server::http(addr) {
+        Err(e) => return Err(HttpError::BindFailed(format!("{}: {}", addr, e))),
+        Ok(s) => s,
+    };
+
+    // Keep track of the state of the jig, so requests can be answered right away.
+    let state = Arc::new(Mutex::new(RunState::new()));
+    let thr_state = state.clone();
+    unit.controller().listen(move |msg| {
+        thr_state.lock().unwrap().update(&msg);
+        Ok(())
+    });
+
+    let builder = thread::Builder::new().name(format!("H-{}", unit.id()).into());
+    let thr_unit = unit.clone();
+    let result = builder.spawn(move || for request in server.incoming_requests() {
+        if !is_current() {
+            return;
+        }
+        if let Err(e) = handle_request(request, &thr_unit, &state, &locale, &filter) {
+            thr_unit.debug(format!("Unable to answer HTTP request: {:?}", e));
+        }
+    });
+    if let Err(e) = result {
+        return Err(HttpError::SpawnFailed(format!("{:?}", e)));
+    }
+
+    // Find out what we missed before we started listening.
+    unit.control(ControlMessageContents::GetJig);
+    unit.control(ControlMessageContents::GetScenarios);
+    unit.control(ControlMessageContents::GetTests(None));
+
+    unit.debug(format!("Serving HTTP on {}", addr));
+    Ok(())
+}
+
+fn json_response(status: u32, body: json::JsonValue) -> Response<io::Cursor<Vec<u8>>> {
+    let content_type = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..])
+        .unwrap();
+    Response::from_string(json::stringify(body))
+        .with_status_code(status)
+        .with_header(content_type)
+}
+
+fn error_response(status: u32, reason: &str) -> Response<io::Cursor<Vec<u8>>> {
+    let mut body = json::JsonValue::new_object();
+    body["error"] = reason.into();
+    json_response(status, body)
+}
+
+fn ok_response() -> Response<io::Cursor<Vec<u8>>> {
+    let mut body = json::JsonValue::new_object();
+    body["ok"] = true.into();
+    json_response(202, body)
+}
+
+fn handle_request(mut request: Request,
+                  unit: &SimpleUnit,
+                  state: &Arc<Mutex<RunState>>,
+                  locale: &Arc<Mutex<Option<String>>>,
+                  filter: &MessageFilter)
+                  -> io::Result<()> {
+    let path = request.url().split('?').next().unwrap_or("").trim_right_matches('/').to_string();
+    let shared_locale = locale;
+    let locale = locale.lock().unwrap().clone();
+
+    let mut body = String::new();
+    if *request.method() == Method::Post {
+        try!(request.as_reader().read_to_string(&mut body));
+    }
+
+    let response = match (request.method(), path.as_str()) {
+        (&Method::Get, "/api/jig") => json_response(200, state.lock().unwrap().jig_json(&locale)),
+        (&Method::Get, "/api/scenarios") => {
+            json_response(200, state.lock().unwrap().scenarios_json(&locale))
+        }
+        (&Method::Get, "/api/tests") => {
+            json_response(200, state.lock().unwrap().tests_json(&locale))
+        }
+        (&Method::Get, "/api/state") => json_response(200, state.lock().unwrap().state_json()),
+        (&Method::Get, "/api/events") => {
+            return upgrade_websocket(request, unit, shared_locale, filter)
+        }
+
+        (&Method::Post, "/api/start") => {
+            unit.control(ControlMessageContents::StartScenario(None, None));
+            ok_response()
+        }
+        (&Method::Post, p) if p.starts_with("/api/start/") => {
+            let scenario = p["/api/start/".len()..].to_lowercase();
+            unit.control(ControlMessageContents::StartScenario(Some(scenario), None));
+            ok_response()
+        }
+        (&Method::Post, "/api/abort") => {
+            unit.control(ControlMessageContents::AbortTests);
+            ok_response()
+        }
+        (&Method::Post, p) if p.starts_with("/api/scenario/") => {
+            let scenario = p["/api/scenario/".len()..].to_lowercase();
+            unit.control(ControlMessageContents::Scenario(scenario));
+            ok_response()
+        }
+        (&Method::Post, p) if p.starts_with("/api/answer/") => {
+            let id = p["/api/answer/".len()..].to_string();
+            unit.control(ControlMessageContents::Answer(id, body.trim().to_string()));
+            ok_response()
+        }
+
+        _ => error_response(404, "Not found"),
+    };
+    request.respond(response)
+}
+
+/// Turn an /api/events request into a WebSocket, and send it every broadcast as JSON.
+///
+/// Broadcasts are queued up for a thread of their own to write, so a client
+/// that stops reading can't hold up the bus.  That thread owns the connection,
+/// and frames from the client are never read.  A client that closes the
+/// connection or goes away is noticed when the next write fails.
+fn upgrade_websocket(request: Request,
+                     unit: &SimpleUnit,
+                     locale: &Arc<Mutex<Option<String>>>,
+                     filter: &MessageFilter)
+                     -> io::Result<()> {
+    let key = request.headers()
+        .iter()
+        .find(|h| h.field.equiv("Sec-WebSocket-Key"))
+        .map(|h| h.value.as_str().trim().to_string());
+    let key = match key {
+        None => return request.respond(error_response(400, "Expected a WebSocket request")),
+        Some(k) => k,
+    };
+
+    let accept = digest::digest(&digest::SHA1, format!("{}{}", key, WEBSOCKET_GUID).as_bytes());
+    let accept = base64::encode(accept.as_ref());
+    let response = Response::empty(101)
+        .with_header(Header::from_bytes(&b"Sec-WebSocket-Accept"[..], accept.as_bytes())
+            .unwrap());
+    let mut stream = request.upgrade("websocket", response);
+    let (queue, queued) = mpsc::sync_channel::<Vec<u8>>(WEBSOCKET_QUEUE_SIZE);
+
+    // Once this thread returns, the queue is disconnected and the bus listener drops off.
+    let builder = thread::Builder::new().name(format!("H-{} write", unit.id()).into());
+    try!(builder.spawn(move || {
+        for payload in queued {
+            if write_frame(&mut stream, OPCODE_TEXT, &payload).is_err() {
+                break;
+            }
+        }
+    }));
+
+    // Follow changes to the interface's locale, the same as any other client would.
+    let locale = locale.clone();
+    let filter = filter.clone();
+    unit.controller().listen(move |msg| {
+        if let BroadcastMessageContents::Log(_) = msg.message {
+            if !filter.matches(&msg) {
+                return Ok(());
+            }
+        }
+        let mut record = vec![];
+        try!(Interface::json_write(&mut record, msg, &*locale.lock().unwrap()));
+        while record.last() == Some(&b'\n') {
+            record.pop();
+        }
+        match queue.try_send(record) {
+            Ok(_) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                Err("WebSocket client stopped reading broadcasts, so it was dropped".to_string())
+            }
+            Err(TrySendError::Disconnected(_)) => Err("WebSocket closed".to_string()),
+        }
+    });
+    Ok(())
+}
+
+/// Write `payload` as a single, unmasked WebSocket frame.
+fn write_frame<W: Write + ?Sized>(stream: &mut W, opcode: u8, payload: &[u8]) -> io::Result<()> {
+    let mut header = vec![0x80 | opcode];
+    let len = payload.len();
+    if len < 126 {
+        header.push(len as u8);
+    } else if len < 65536 {
+        header.push(126);
+        header.push((len >> 8) as u8);
+        header.push(len as u8);
+    } else {
+        header.push(127);
+        for shift in (0..8).rev() {
+            header.push((len as u64 >> (shift * 8)) as u8);
+        }
+    }
+    try!(stream.write_all(&header));
+    try!(stream.write_all(payload));
+    stream.flush()
+}
@@ -0,0 +1,104 @@
+/// Localization support, for unit strings and for messages the controller generates itself.
+
+use std::collections::HashMap;
+
+/// A string along with all of its translations, so that each interface
+/// can pick the language it wants.
+#[derive(Clone, Debug)]
+pub struct LocalizedString {
+    /// The string to use when there is no matching translation.
+    default: String,
+
+    /// Translations, keyed by locale, e.g. "zh" or "zh_CN".
+    translations: HashMap<String, String>,
+}
+
+impl LocalizedString {
+    pub fn new(default: &str) -> LocalizedString {
+        LocalizedString {
+            default: default.to_string(),
+            translations: HashMap::new(),
+        }
+    }
+
+    pub fn add_translation(&mut self, locale: &str, value: &str) {
+        self.translations.insert(locale.to_string(), value.to_string());
+    }
+
+    pub fn default(&self) -> &str {
+        self.default.as_str()
+    }
+
+    /// Get the most specific translation for `locale`, or the default.
+    pub fn get(&self, locale: &Option<String>) -> &str {
+        for candidate in locale_fallbacks(locale) {
+            if let Some(s) = self.translations.get(&candidate) {
+                return s.as_str();
+            }
+        }
+        self.default.as_str()
+    }
+}
+
+/// Turn a locale such as "zh_CN.UTF-8@pinyin" into the list of
+/// translations to try, most specific first: ["zh_CN", "zh"].
+pub fn locale_fallbacks(locale: &Option<String>) -> Vec<String> {
+    let locale = match *locale {
+        None => return vec![],
+        Some(ref s) => s,
+    };
+
+    // Drop the encoding and modifier, which translations don't use.
+    let locale = locale.split(|c| c == '.' || c == '@').next().unwrap_or("");
+    if locale.is_empty() || locale == "C" || locale == "POSIX" {
+        return vec![];
+    }
+
+    let mut fallbacks = vec![locale.to_string()];
+    if let Some(language) = locale.split('_').next() {
+        if language != locale {
+            fallbacks.push(language.to_string());
+        }
+    }
+    fallbacks
+}
+
+/// Messages generated by the controller itself, along with their translations.
+/// A message may also be followed by ": " and some detail, which is left as-is.
+fn catalog(language: &str) -> Option<&'static [(&'static str, &'static str)]> {
+    static ZH: [(&'static str, &'static str); 8] = [
+        ("dependency failed", "依赖项失败"),
+        ("Test daemon exited", "测试守护进程已退出"),
+        ("Daemon exited", "守护进程已退出"),
+        ("Error in interface", "接口错误"),
+        ("At least one test failed", "至少一项测试失败"),
+        ("Finished tests", "测试已完成"),
+        ("Coupon preflight failed", "凭证预检失败"),
+        ("Okay", "正常"),
+    ];
+    match language {
+        "zh" => Some(&ZH),
+        _ => None,
+    }
+}
+
+/// Translate a controller-generated message such as a SKIP or FAIL reason.
+/// Messages that aren't in the catalog, such as output from tests, are
+/// returned unchanged.
+pub fn translate(msg: &str, locale: &Option<String>) -> String {
+    for candidate in locale_fallbacks(locale) {
+        let entries = match catalog(candidate.as_str()) {
+            None => continue,
+            Some(e) => e,
+        };
+        for &(english, translated) in entries {
+            if msg == english {
+                return translated.to_string();
+            }
+            if msg.starts_with(english) && msg[english.len()..].starts_with(": ") {
+                return format!("{}{}", translated, &msg[english.len()..]);
+            }
+        }
+    }
+    msg.to_string()
+}
@@ -0,0 +1,157 @@
+/// Files written by the built-in file logger, with size- and age-based
+/// rotation, and optionally a new file for each scenario run.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time;
+
+#[derive(Debug, Clone)]
+pub struct LogFileSettings {
+    /// path: The file to write to, or with per_run, the directory to write files into.
+    pub path: PathBuf,
+
+    /// extension: Added to the names of per-run files, e.g. "tsv".
+    pub extension: String,
+
+    /// max_size: Rotate the file once it grows to this many bytes.
+    pub max_size: Option<u64>,
+
+    /// max_age: Rotate the file once it has been open for this long.
+    pub max_age: Option<time::Duration>,
+
+    /// max_files: How many rotated files to keep, as "[file].1" through "[file].N".
+    pub max_files: u32,
+
+    /// per_run: Write each scenario run to its own file.
+    pub per_run: bool,
+}
+
+#[derive(Debug)]
+pub struct LogFile {
+    settings: LogFileSettings,
+
+    /// The path of the file currently being written.
+    path: PathBuf,
+
+    /// The file currently being written.
+    file: Option<File>,
+
+    /// How many bytes are in the current file.
+    size: u64,
+
+    /// When the current file was opened.
+    opened: time::Instant,
+}
+
+impl LogFile {
+    pub fn open(settings: LogFileSettings) -> io::Result<LogFile> {
+        let path = if settings.per_run {
+            try!(fs::create_dir_all(&settings.path));
+            settings.path.join(format!("jig-20.{}", settings.extension))
+        } else {
+            if let Some(parent) = settings.path.parent() {
+                if !parent.as_os_str().is_empty() {
+                    try!(fs::create_dir_all(parent));
+                }
+            }
+            settings.path.clone()
+        };
+
+        let mut log_file = LogFile {
+            settings: settings,
+            path: path.clone(),
+            file: None,
+            size: 0,
+            opened: time::Instant::now(),
+        };
+        try!(log_file.open_path(path));
+        Ok(log_file)
+    }
+
+    /// The path of the file currently being written.
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+
+    fn open_path(&mut self, path: PathBuf) -> io::Result<()> {
+        let file = try!(OpenOptions::new().create(true).append(true).open(&path));
+        self.size = try!(file.metadata()).len();
+        self.file = Some(file);
+        self.path = path;
+        self.opened = time::Instant::now();
+        Ok(())
+    }
+
+    /// With per_run, switch to a new file named after the scenario and the time it started.
+    pub fn start_run(&mut self, scenario: &str, unix_time: u64) -> io::Result<()> {
+        if !self.settings.per_run {
+            return Ok(());
+        }
+        let name = format!("{}-{}.{}", scenario, unix_time, self.settings.extension);
+        let path = self.settings.path.join(name);
+        self.open_path(path)
+    }
+
+    /// With per_run, go back to the file used between runs.
+    pub fn finish_run(&mut self) -> io::Result<()> {
+        if !self.settings.per_run {
+            return Ok(());
+        }
+        let path = self.settings.path.join(format!("jig-20.{}", self.settings.extension));
+        self.open_path(path)
+    }
+
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.needs_rotation() {
+            try!(self.rotate());
+        }
+        let result = match self.file {
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "log file is not open")),
+            Some(ref mut f) => writeln!(f, "{}", line),
+        };
+        try!(result);
+        self.size = self.size + line.len() as u64 + 1;
+        Ok(())
+    }
+
+    fn needs_rotation(&self) -> bool {
+        if self.size == 0 {
+            return false;
+        }
+        if let Some(max_size) = self.settings.max_size {
+            if self.size >= max_size {
+                return true;
+            }
+        }
+        if let Some(max_age) = self.settings.max_age {
+            if self.opened.elapsed() >= max_age {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Rename "[file]" to "[file].1", "[file].1" to "[file].2", and so on,
+    /// dropping the oldest, and then start a fresh "[file]".
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file = None;
+        let path = self.path.clone();
+        let numbered = |n: u32| {
+            let mut s = path.clone().into_os_string();
+            s.push(format!(".{}", n));
+            PathBuf::from(s)
+        };
+
+        if self.settings.max_files == 0 {
+            try!(fs::remove_file(&path));
+        } else {
+            fs::remove_file(numbered(self.settings.max_files)).ok();
+            for n in (1..self.settings.max_files).rev() {
+                fs::rename(numbered(n), numbered(n + 1)).ok();
+            }
+            try!(fs::rename(&path, numbered(1)));
+        }
+        self.open_path(path.clone())
+    }
+}
@@ -0,0 +1,200 @@
+/// Records waiting for a logger that isn't running, so that they can be
+/// replayed once it comes back.
+
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+
+#[derive(Debug)]
+pub struct LogQueue {
+    /// Records waiting to be sent, when they're kept in memory.
+    records: VecDeque<String>,
+
+    /// A file to keep waiting records in instead, one per line.
+    path: Option<PathBuf>,
+
+    /// How many records are waiting, in memory or on disk.
+    len: usize,
+
+    /// The most records that may be waiting at once.
+    capacity: usize,
+
+    /// How many records were thrown away because the queue was full.
+    dropped: u64,
+}
+
+impl LogQueue {
+    /// Create a new queue.  If `path` already holds records from an earlier
+    /// run, they are kept, and will be sent first.
+    pub fn new(capacity: usize, path: Option<PathBuf>) -> io::Result<LogQueue> {
+        let len = match path {
+            None => 0,
+            Some(ref p) => {
+                match File::open(p) {
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => 0,
+                    Err(e) => return Err(e),
+                    Ok(f) => BufReader::new(f).lines().count(),
+                }
+            }
+        };
+
+        Ok(LogQueue {
+            records: VecDeque::new(),
+            path: path,
+            len: len,
+            capacity: capacity,
+            dropped: 0,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Add a record to the end of the queue.  Once the queue is full, new
+    /// records are dropped, so that what does get replayed has no gaps.
+    pub fn push(&mut self, record: String) -> io::Result<()> {
+        if self.len >= self.capacity {
+            self.dropped = self.dropped + 1;
+            return Ok(());
+        }
+
+        match self.path {
+            None => self.records.push_back(record),
+            Some(ref p) => {
+                let mut file = try!(OpenOptions::new().create(true).append(true).open(p));
+                try!(writeln!(file, "{}", record));
+            }
+        }
+        self.len = self.len + 1;
+        Ok(())
+    }
+
+    /// Return how many records have been dropped since the last call.
+    pub fn take_dropped(&mut self) -> u64 {
+        let dropped = self.dropped;
+        self.dropped = 0;
+        dropped
+    }
+
+    /// Give up on the records that are waiting, because nothing will ever send
+    /// them, and return how many were lost, including any that were dropped.
+    /// Records kept in a file stay there, to be sent by the next run.
+    pub fn abandon(&mut self) -> u64 {
+        let mut lost = self.take_dropped();
+        if self.path.is_none() {
+            lost = lost + self.records.len() as u64;
+            self.records.clear();
+            self.len = 0;
+        }
+        lost
+    }
+
+    /// Write every waiting record to `writer`, oldest first, and return how
+    /// many were written.  On error, the records that weren't written stay queued.
+    pub fn drain_into<W: Write + ?Sized>(&mut self, writer: &mut W) -> io::Result<usize> {
+        if self.len == 0 {
+            return Ok(0);
+        }
+
+        let path = match self.path {
+            Some(ref p) => p.clone(),
+            None => {
+                let mut written = 0;
+                while let Some(record) = self.records.pop_front() {
+                    if let Err(e) = writeln!(writer, "{}", record) {
+                        self.records.push_front(record);
+                        self.len = self.records.len();
+                        return Err(e);
+                    }
+                    written = written + 1;
+                }
+                self.len = 0;
+                return Ok(written);
+            }
+        };
+
+        let records: Vec<String> = {
+            let file = try!(File::open(&path));
+            try!(BufReader::new(file).lines().collect::<io::Result<Vec<String>>>())
+        };
+
+        for (written, record) in records.iter().enumerate() {
+            if let Err(e) = writeln!(writer, "{}", record) {
+                // Keep whatever didn't make it for next time.
+                let mut file = try!(File::create(&path));
+                for record in &records[written..] {
+                    try!(writeln!(file, "{}", record));
+                }
+                self.len = records.len() - written;
+                return Err(e);
+            }
+        }
+
+        try!(fs::remove_file(&path));
+        self.len = 0;
+        Ok(records.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    /// A writer that accepts a few lines and then fails.
+    struct FailAfter {
+        written: String,
+        limit: usize,
+    }
+
+    impl Write for FailAfter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.written.matches('\n').count() >= self.limit {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "closed"));
+            }
+            self.written.push_str(&String::from_utf8_lossy(buf));
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn drain_into_requeues_on_error() {
+        let mut queue = LogQueue::new(10, None).unwrap();
+        for i in 0..3 {
+            queue.push(format!("record {}", i)).unwrap();
+        }
+
+        let mut writer = FailAfter {
+            written: String::new(),
+            limit: 1,
+        };
+        assert!(queue.drain_into(&mut writer).is_err());
+        assert_eq!(writer.written, "record 0\n");
+        assert_eq!(queue.len, 2);
+
+        let mut writer = FailAfter {
+            written: String::new(),
+            limit: 10,
+        };
+        assert_eq!(queue.drain_into(&mut writer).unwrap(), 2);
+        assert_eq!(writer.written, "record 1\nrecord 2\n");
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn abandon_counts_dropped_records() {
+        let mut queue = LogQueue::new(2, None).unwrap();
+        for i in 0..5 {
+            queue.push(format!("record {}", i)).unwrap();
+        }
+        assert_eq!(queue.abandon(), 5);
+        assert!(queue.is_empty());
+        assert_eq!(queue.abandon(), 0);
+    }
+}
@@ -0,0 +1,222 @@
+/// Measurements reported by tests, and the limits they're checked against.
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MeasurementError {
+    MissingName,
+    MissingValue(String),
+    InvalidValue(String, String),
+}
+
+/// The range a measurement must fall within.  Either end may be left open.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Limit {
+    pub low: Option<f64>,
+    pub high: Option<f64>,
+}
+
+impl Limit {
+    /// Parse a range such as "3.2..3.4", "3.2..", or "..3.4".
+    pub fn from_str(s: &str) -> Option<Limit> {
+        let mut parts = s.splitn(2, "..");
+        let low = parts.next().unwrap_or("").trim();
+        let high = match parts.next() {
+            None => return None,
+            Some(h) => h.trim(),
+        };
+
+        let parse = |s: &str| -> Result<Option<f64>, ()> {
+            if s.is_empty() {
+                return Ok(None);
+            }
+            match s.parse::<f64>() {
+                Ok(n) if n.is_finite() => Ok(Some(n)),
+                _ => Err(()),
+            }
+        };
+
+        let limit = match (parse(low), parse(high)) {
+            (Ok(low), Ok(high)) => {
+                Limit {
+                    low: low,
+                    high: high,
+                }
+            }
+            _ => return None,
+        };
+        if limit.low.is_none() && limit.high.is_none() {
+            return None;
+        }
+        Some(limit)
+    }
+
+    /// Returns true if `value` is within the limit, inclusive of both ends.
+    pub fn contains(&self, value: f64) -> bool {
+        if let Some(low) = self.low {
+            if value < low {
+                return false;
+            }
+        }
+        if let Some(high) = self.high {
+            if value > high {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(low) = self.low {
+            try!(write!(f, "{}", low));
+        }
+        try!(write!(f, ".."));
+        if let Some(high) = self.high {
+            try!(write!(f, "{}", high));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Measurement {
+    /// The test that took the measurement.
+    pub test: String,
+
+    /// What was measured, e.g. "vdd_3v3".
+    pub name: String,
+
+    pub value: f64,
+
+    /// The unit of the value, e.g. "V".  May be empty.
+    pub unit: String,
+
+    /// The limit from the test's Limit.[name]= field, if it has one.
+    pub limit: Option<Limit>,
+}
+
+impl Measurement {
+    /// Parse a "MEASURE [name] [value] [unit]" line printed by `test`.
+    /// Returns None if the line isn't a measurement at all.
+    pub fn parse(test: &str, line: &str) -> Option<Result<Measurement, MeasurementError>> {
+        let mut words = line.split_whitespace();
+        if words.next() != Some("MEASURE") {
+            return None;
+        }
+
+        let name = match words.next() {
+            None => return Some(Err(MeasurementError::MissingName)),
+            Some(n) => n.to_string(),
+        };
+        let value = match words.next() {
+            None => return Some(Err(MeasurementError::MissingValue(name))),
+            Some(v) => {
+                match v.parse::<f64>() {
+                    Ok(v) if v.is_finite() => v,
+                    _ => return Some(Err(MeasurementError::InvalidValue(name, v.to_string()))),
+                }
+            }
+        };
+        let unit = words.collect::<Vec<&str>>().join(" ");
+
+        Some(Ok(Measurement {
+            test: test.to_string(),
+            name: name,
+            value: value,
+            unit: unit,
+            limit: None,
+        }))
+    }
+
+    /// Returns true if there's no limit, or if the value is within it.
+    pub fn in_limits(&self) -> bool {
+        match self.limit {
+            None => true,
+            Some(ref l) => l.contains(self.value),
+        }
+    }
+
+    /// "pass" if the value is within its limit, or "fail" if it isn't.
+    pub fn result(&self) -> &'static str {
+        if self.in_limits() { "pass" } else { "fail" }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limit_from_str() {
+        assert_eq!(Limit::from_str("3.2..3.4"),
+                   Some(Limit {
+                       low: Some(3.2),
+                       high: Some(3.4),
+                   }));
+        assert_eq!(Limit::from_str(" -5 .. -1 "),
+                   Some(Limit {
+                       low: Some(-5.0),
+                       high: Some(-1.0),
+                   }));
+        assert_eq!(Limit::from_str("3.2.."),
+                   Some(Limit {
+                       low: Some(3.2),
+                       high: None,
+                   }));
+        assert_eq!(Limit::from_str("..3.4"),
+                   Some(Limit {
+                       low: None,
+                       high: Some(3.4),
+                   }));
+    }
+
+    #[test]
+    fn limit_from_str_rejects_malformed() {
+        assert_eq!(Limit::from_str(".."), None);
+        assert_eq!(Limit::from_str("3.2"), None);
+        assert_eq!(Limit::from_str("a..b"), None);
+        assert_eq!(Limit::from_str("1..NaN"), None);
+        assert_eq!(Limit::from_str("-inf..0"), None);
+    }
+
+    #[test]
+    fn limit_contains_both_ends() {
+        let limit = Limit::from_str("1..2").unwrap();
+        assert!(limit.contains(1.0));
+        assert!(limit.contains(2.0));
+        assert!(!limit.contains(0.5));
+        assert!(!limit.contains(2.5));
+        assert!(Limit::from_str("..2").unwrap().contains(-1000.0));
+    }
+
+    #[test]
+    fn measurement_parse() {
+        let m = Measurement::parse("power", "MEASURE vdd_3v3 3.31 V").unwrap().unwrap();
+        assert_eq!(m.test, "power");
+        assert_eq!(m.name, "vdd_3v3");
+        assert_eq!(m.value, 3.31);
+        assert_eq!(m.unit, "V");
+        assert_eq!(m.limit, None);
+
+        let m = Measurement::parse("power", "MEASURE temp -4").unwrap().unwrap();
+        assert_eq!(m.value, -4.0);
+        assert_eq!(m.unit, "");
+    }
+
+    #[test]
+    fn measurement_parse_errors() {
+        assert_eq!(Measurement::parse("power", "vdd_3v3 is 3.31"), None);
+        assert_eq!(Measurement::parse("power", "MEASURE"),
+                   Some(Err(MeasurementError::MissingName)));
+        assert_eq!(Measurement::parse("power", "MEASURE vdd"),
+                   Some(Err(MeasurementError::MissingValue("vdd".to_string()))));
+        for value in &["high", "NaN", "inf"] {
+            let line = format!("MEASURE vdd {}", value);
+            assert_eq!(Measurement::parse("power", &line),
+                       Some(Err(MeasurementError::InvalidValue("vdd".to_string(),
+                                                               value.to_string()))));
+        }
+    }
+}
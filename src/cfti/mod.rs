@@ -4,5 +4,17 @@ pub mod process;
 pub mod config;
 pub mod controller;
 pub mod unitfile;
+pub mod signing;
+pub mod locale;
+pub mod filter;
+pub mod measurement;
+pub mod resources;
+pub mod logfile;
+pub mod logqueue;
+pub mod results;
+pub mod resultpipe;
+pub mod http;
+pub mod report;
+pub mod repeat;
 
 pub use self::testset::TestSet;
\ No newline at end of file
@@ -1,4 +1,5 @@
 extern crate runny;
+extern crate shlex;
 
 use std::io::{self, BufRead};
 use std::time::Duration;
@@ -11,6 +12,9 @@ use self::runny::running::Running;
 use cfti::controller::{Controller, ControlMessageContents};
 use cfti::types::unit::Unit;
 
+/// Extra environment variables to pass to a child process, as (KEY, VALUE) pairs.
+pub type Environment = Vec<(String, String)>;
+
 #[derive(Debug)]
 pub enum CommandError {
     SpawnError(String, String),
@@ -64,15 +68,68 @@ pub fn watch_output<T: io::Read + Send + 'static, F, U: Unit>
     })
 }
 
-pub fn try_command<T: Unit>(unit: &T, cmd: &str, wd: &Option<String>, max: Duration) -> bool {
-    let paths = match env::var_os("PATH") {
+fn search_paths() -> Vec<String> {
+    match env::var_os("PATH") {
         Some(path) => {
             env::split_paths(&path).map(|x| x.to_str().unwrap().to_string()).collect::<Vec<_>>()
         }
         None => vec![],
-    };
+    }
+}
+
+/// Prefix `cmd_str` so that it runs with the extra variables in `environment`.
+fn with_environment(cmd_str: &str, environment: &[(String, String)]) -> String {
+    if environment.is_empty() {
+        return cmd_str.to_string();
+    }
+
+    let mut cmd = "env".to_string();
+    for &(ref key, ref value) in environment {
+        cmd.push(' ');
+        cmd.push_str(&shlex::quote(&format!("{}={}", key, value)));
+    }
+    cmd.push(' ');
+    cmd.push_str(cmd_str);
+    cmd
+}
+
+/// Parse `KEY=VALUE` lines printed by a program into an `Environment`.
+///
+/// Lines that aren't valid assignments are returned as the second value,
+/// so the caller can log them.
+pub fn parse_environment(lines: &Vec<String>) -> (Environment, Vec<String>) {
+    let mut environment = vec![];
+    let mut other = vec![];
+
+    for line in lines {
+        let mut parts = line.splitn(2, '=');
+        let key = parts.next().unwrap_or("").trim();
+        let value = parts.next();
+
+        let valid_key = !key.is_empty() &&
+                        !key.chars().next().unwrap().is_digit(10) &&
+                        key.chars().all(|c| match c {
+            'a'...'z' | 'A'...'Z' | '0'...'9' | '_' => true,
+            _ => false,
+        });
+        match value {
+            Some(v) if valid_key => environment.push((key.to_string(), v.to_string())),
+            _ => other.push(line.clone()),
+        }
+    }
+    (environment, other)
+}
+
+pub fn try_command<T: Unit>(unit: &T,
+                            cmd: &str,
+                            wd: &Option<String>,
+                            environment: &[(String, String)],
+                            max: Duration)
+                            -> bool {
+    let paths = search_paths();
+    let cmd = with_environment(cmd, environment);
 
-    let running = match Runny::new(cmd).directory(wd).timeout(max).path(paths).start() {
+    let running = match Runny::new(cmd.as_str()).directory(wd).timeout(max).path(paths).start() {
         Ok(r) => r,
         Err(e) => {
             unit.debug(format!("Unable to start command {}: {:?}", cmd, e));
@@ -89,16 +146,16 @@ pub fn try_command<T: Unit>(unit: &T, cmd: &str, wd: &Option<String>, max: Durat
 /// stdout, and stderr.
 pub fn spawn_cmd<T: Unit>(cmd_str: &str,
                           unit: &T,
-                          working_directory: &Option<String>)
+                          working_directory: &Option<String>,
+                          environment: &[(String, String)])
                           -> Result<Running, CommandError> {
-    let paths = match env::var_os("PATH") {
-        Some(path) => {
-            env::split_paths(&path).map(|x| x.to_str().unwrap().to_string()).collect::<Vec<_>>()
-        }
-        None => vec![],
-    };
+    let paths = search_paths();
+    let full_cmd = with_environment(cmd_str, environment);
 
-    let process = match Runny::new(cmd_str).directory(working_directory).path(paths).start() {
+    let process = match Runny::new(full_cmd.as_str())
+        .directory(working_directory)
+        .path(paths)
+        .start() {
         Ok(p) => p,
         Err(e) => {
             unit.debug(format!("Unable to spawn command {}: {:?}", cmd_str, e));
@@ -112,6 +169,7 @@ pub fn spawn_cmd<T: Unit>(cmd_str: &str,
 /// Tries to run `cmd`.
 ///
 /// If `wd` is specified, then runs the command in that working directory.
+/// Each pair in `environment` is added to the command's environment.
 /// Will only allow the command to run for `max` duration.
 /// When the command finishes or times out, `completion` will be called.
 ///
@@ -124,19 +182,16 @@ pub fn spawn_cmd<T: Unit>(cmd_str: &str,
 
 pub fn try_command_completion<F>(cmd_str: &str,
                                  wd: &Option<String>,
+                                 environment: &[(String, String)],
                                  max: Duration,
                                  completion: F)
                                  -> Result<Running, CommandError>
     where F: Send + 'static + FnOnce(Result<(), CommandError>)
 {
-    let mut cmd = Runny::new(cmd_str);
+    let full_cmd = with_environment(cmd_str, environment);
+    let mut cmd = Runny::new(full_cmd.as_str());
 
-    let paths = match env::var_os("PATH") {
-        Some(path) => {
-            env::split_paths(&path).map(|x| x.to_str().unwrap().to_string()).collect::<Vec<_>>()
-        }
-        None => vec![],
-    };
+    let paths = search_paths();
 
     cmd.directory(wd).timeout(max).path(paths);
 
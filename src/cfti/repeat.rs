@@ -0,0 +1,232 @@
+/// Repeated runs of a scenario, for soak testing one device over and over.
+
+use std::fmt;
+
+use cfti::types::TestState;
+
+/// How one test fared across every iteration of a repeated scenario.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestTally {
+    pub test: String,
+    pub pass: u32,
+    pub fail: u32,
+    pub skip: u32,
+}
+
+/// Formats as "[test]:[pass]/[fail]/[skip]", as sent in SUMMARY messages.
+impl fmt::Display for TestTally {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}/{}/{}", self.test, self.pass, self.fail, self.skip)
+    }
+}
+
+/// Keeps track of which iteration a repeated scenario is on, and how the
+/// iterations so far have gone.
+#[derive(Debug)]
+pub struct Iterations {
+    /// Which iteration is running, starting at 1.
+    current: u32,
+
+    /// How many iterations to run.  None means to keep going until told to stop.
+    total: Option<u32>,
+
+    /// Stop after the first iteration that fails.
+    until_failure: bool,
+
+    /// Set when the run is aborted, so no more iterations start.
+    stopped: bool,
+
+    /// Set when the next iteration has been asked for, but hasn't started yet.
+    pending: bool,
+
+    passed: u32,
+    failed: u32,
+
+    /// Tallies for each test, in the order the tests run.
+    tallies: Vec<TestTally>,
+}
+
+impl Iterations {
+    /// Start counting a new run of `total` iterations, where 0 means no limit.
+    pub fn new(total: u32, until_failure: bool) -> Iterations {
+        Iterations {
+            current: 1,
+            total: if total == 0 { None } else { Some(total) },
+            until_failure: until_failure,
+            stopped: false,
+            pending: false,
+            passed: 0,
+            failed: 0,
+            tallies: vec![],
+        }
+    }
+
+    pub fn current(&self) -> u32 {
+        self.current
+    }
+
+    /// The number of iterations, or 0 if there's no limit.
+    pub fn total(&self) -> u32 {
+        self.total.unwrap_or(0)
+    }
+
+    /// Returns true if the scenario runs more than once, and so needs
+    /// ITERATION and SUMMARY messages.
+    pub fn is_repeating(&self) -> bool {
+        self.total != Some(1)
+    }
+
+    /// Don't start any more iterations.  Returns true if one was about to start.
+    pub fn stop(&mut self) -> bool {
+        let pending = self.pending;
+        self.stopped = true;
+        self.pending = false;
+        pending
+    }
+
+    /// Count the iteration that just finished, given each test's final state.
+    pub fn record(&mut self, passed: bool, results: &[(String, TestState)]) {
+        if passed {
+            self.passed = self.passed + 1;
+        } else {
+            self.failed = self.failed + 1;
+        }
+
+        for &(ref test, ref state) in results {
+            let idx = match self.tallies.iter().position(|t| &t.test == test) {
+                Some(i) => i,
+                None => {
+                    self.tallies.push(TestTally {
+                        test: test.clone(),
+                        pass: 0,
+                        fail: 0,
+                        skip: 0,
+                    });
+                    self.tallies.len() - 1
+                }
+            };
+            let tally = &mut self.tallies[idx];
+            match *state {
+                TestState::Pass => tally.pass = tally.pass + 1,
+                TestState::Fail(_) => tally.fail = tally.fail + 1,
+                _ => tally.skip = tally.skip + 1,
+            }
+        }
+    }
+
+    /// Move on to the next iteration.  Returns false if the run is over.
+    pub fn next(&mut self) -> bool {
+        if self.stopped || (self.until_failure && self.failed > 0) {
+            return false;
+        }
+        if let Some(total) = self.total {
+            if self.current >= total {
+                return false;
+            }
+        }
+        self.current = self.current + 1;
+        self.pending = true;
+        true
+    }
+
+    /// Returns true if the next iteration has been asked for, but hasn't started.
+    pub fn pending(&self) -> bool {
+        self.pending
+    }
+
+    /// Returns true, once, if the next iteration has been asked for.
+    pub fn take_pending(&mut self) -> bool {
+        let pending = self.pending;
+        self.pending = false;
+        pending
+    }
+
+    pub fn passed(&self) -> u32 {
+        self.passed
+    }
+
+    pub fn failed(&self) -> u32 {
+        self.failed
+    }
+
+    pub fn tallies(&self) -> &Vec<TestTally> {
+        &self.tallies
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn results(states: &[(&str, TestState)]) -> Vec<(String, TestState)> {
+        states.iter().map(|&(ref t, ref s)| (t.to_string(), s.clone())).collect()
+    }
+
+    #[test]
+    fn runs_total_iterations() {
+        let mut iterations = Iterations::new(3, false);
+        assert!(iterations.is_repeating());
+        assert_eq!(iterations.current(), 1);
+
+        assert!(iterations.next());
+        assert!(iterations.pending());
+        assert!(iterations.take_pending());
+        assert!(!iterations.pending());
+        assert!(!iterations.take_pending());
+        assert_eq!(iterations.current(), 2);
+
+        assert!(iterations.next());
+        assert_eq!(iterations.current(), 3);
+        assert!(!iterations.next());
+        assert_eq!(iterations.current(), 3);
+    }
+
+    #[test]
+    fn single_run_doesnt_repeat() {
+        let mut iterations = Iterations::new(1, false);
+        assert!(!iterations.is_repeating());
+        assert!(!iterations.next());
+        assert!(!iterations.pending());
+    }
+
+    #[test]
+    fn no_limit_until_stopped() {
+        let mut iterations = Iterations::new(0, false);
+        assert_eq!(iterations.total(), 0);
+        for _ in 0..100 {
+            assert!(iterations.next());
+        }
+        assert!(iterations.stop());
+        assert!(!iterations.pending());
+        assert!(!iterations.next());
+        assert!(!iterations.stop());
+    }
+
+    #[test]
+    fn until_failure() {
+        let mut iterations = Iterations::new(0, true);
+        iterations.record(true, &[]);
+        assert!(iterations.next());
+        iterations.record(false, &[]);
+        assert!(!iterations.next());
+        assert_eq!(iterations.passed(), 1);
+        assert_eq!(iterations.failed(), 1);
+    }
+
+    #[test]
+    fn record_tallies_each_test() {
+        let mut iterations = Iterations::new(2, false);
+        iterations.record(false,
+                          &results(&[("power", TestState::Pass),
+                                     ("flash", TestState::Fail("timeout".to_string()))]));
+        iterations.record(true,
+                          &results(&[("power", TestState::Pass),
+                                     ("flash", TestState::Pass),
+                                     ("radio", TestState::Skip)]));
+        assert_eq!(iterations.passed(), 1);
+        assert_eq!(iterations.failed(), 1);
+
+        let tallies: Vec<String> = iterations.tallies().iter().map(|t| t.to_string()).collect();
+        assert_eq!(tallies, vec!["power:2/0/0", "flash:1/1/0", "radio:0/0/1"]);
+    }
+}
@@ -0,0 +1,330 @@
+/// Reports that summarise a scenario run in standard formats, so that CI
+/// systems can pick up the results.
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time;
+
+use cfti::types::{Test, TestState, Unit};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReportFormat {
+    /// JUnit XML, as understood by Jenkins, GitLab, and most other CI systems.
+    JUnit,
+
+    /// Test Anything Protocol, version 13.
+    Tap,
+}
+
+impl ReportFormat {
+    pub fn from_str(s: &str) -> Option<ReportFormat> {
+        match s.to_lowercase().as_ref() {
+            "junit" => Some(ReportFormat::JUnit),
+            "tap" => Some(ReportFormat::Tap),
+            _ => None,
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match *self {
+            ReportFormat::JUnit => "xml",
+            ReportFormat::Tap => "tap",
+        }
+    }
+}
+
+/// Everything known about a scenario run once it has finished.
+pub struct RunReport<'a> {
+    pub scenario: &'a str,
+
+    /// Which iteration this was, if the scenario is being repeated.
+    pub iteration: Option<u32>,
+    pub started: time::SystemTime,
+    pub finished: time::SystemTime,
+    pub result: u32,
+    pub reason: &'a str,
+    pub tests: &'a [Arc<Mutex<Test>>],
+}
+
+/// A snapshot of one test, taken so that no locks are held while writing.
+struct TestCase {
+    id: String,
+    name: String,
+    state: TestState,
+    duration: Option<f64>,
+    last_line: String,
+    stdout: String,
+    stderr: String,
+}
+
+fn seconds(d: time::Duration) -> f64 {
+    d.as_secs() as f64 + (d.subsec_nanos() as f64 / 1_000_000_000.0)
+}
+
+fn unix_time(t: time::SystemTime) -> u64 {
+    match t.duration_since(time::UNIX_EPOCH) {
+        Ok(d) => d.as_secs(),
+        Err(_) => 0,
+    }
+}
+
+/// Format a time as ISO 8601 in UTC, e.g. "2017-02-01T09:44:17".
+fn iso8601(t: time::SystemTime) -> String {
+    let secs = unix_time(t);
+    let time_of_day = secs % 86400;
+
+    // Convert days since the epoch to a civil date, per Howard Hinnant's algorithm.
+    let z = (secs / 86400) + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+            year,
+            month,
+            day,
+            time_of_day / 3600,
+            (time_of_day / 60) % 60,
+            time_of_day % 60)
+}
+
+/// Escape text for use in XML, dropping control characters that XML can't represent.
+fn xml_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' | '\n' | '\r' => escaped.push(c),
+            c if (c as u32) < 0x20 => (),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Quote a string for a TAP YAML block.
+fn yaml_quote(s: &str) -> String {
+    format!("\"{}\"",
+            s.replace("\\", "\\\\").replace("\"", "\\\"").replace("\n", "\\n"))
+}
+
+impl<'a> RunReport<'a> {
+    fn test_cases(&self) -> Vec<TestCase> {
+        let mut cases = vec![];
+        for test in self.tests {
+            let test = test.lock().unwrap();
+            let duration = match (test.start_time(), test.finish_time()) {
+                (Some(start), Some(finish)) => {
+                    match finish.duration_since(start) {
+                        Ok(d) => Some(seconds(d)),
+                        Err(_) => None,
+                    }
+                }
+                _ => None,
+            };
+            cases.push(TestCase {
+                id: test.id().to_string(),
+                name: test.name().to_string(),
+                state: test.state(),
+                duration: duration,
+                last_line: test.last_line(),
+                stdout: test.stdout(),
+                stderr: test.stderr(),
+            });
+        }
+        cases
+    }
+
+    fn duration(&self) -> f64 {
+        match self.finished.duration_since(self.started) {
+            Ok(d) => seconds(d),
+            Err(_) => 0.0,
+        }
+    }
+
+    /// Write the report into `directory` as "[scenario]-[unix-time].[xml|tap]",
+    /// or "[scenario]-[unix-time]-[iteration].[xml|tap]" for a repeated scenario,
+    /// and return the path it was written to.
+    pub fn write(&self, directory: &str, format: &ReportFormat) -> io::Result<PathBuf> {
+        try!(fs::create_dir_all(directory));
+        let name = match self.iteration {
+            None => format!("{}-{}", self.scenario, unix_time(self.started)),
+            Some(i) => format!("{}-{}-{}", self.scenario, unix_time(self.started), i),
+        };
+        let path = Path::new(directory).join(format!("{}.{}", name, format.extension()));
+        let mut file = try!(File::create(&path));
+        let cases = self.test_cases();
+        try!(match *format {
+            ReportFormat::JUnit => self.write_junit(&mut file, &cases),
+            ReportFormat::Tap => self.write_tap(&mut file, &cases),
+        });
+        Ok(path)
+    }
+
+    fn write_junit<W: Write>(&self, out: &mut W, cases: &[TestCase]) -> io::Result<()> {
+        let failures = cases.iter()
+            .filter(|c| match c.state {
+                TestState::Fail(_) => true,
+                _ => false,
+            })
+            .count();
+        let skipped = cases.iter()
+            .filter(|c| match c.state {
+                TestState::Skip | TestState::Pending => true,
+                _ => false,
+            })
+            .count();
+
+        try!(writeln!(out, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>"));
+        try!(writeln!(out, "<testsuites>"));
+        try!(writeln!(out,
+                      "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"0\" \
+                       skipped=\"{}\" time=\"{:.3}\" timestamp=\"{}\">",
+                      xml_escape(self.scenario),
+                      cases.len(),
+                      failures,
+                      skipped,
+                      self.duration(),
+                      iso8601(self.started)));
+        try!(writeln!(out, "    <properties>"));
+        try!(writeln!(out,
+                      "      <property name=\"result\" value=\"{}\"/>",
+                      self.result));
+        try!(writeln!(out,
+                      "      <property name=\"reason\" value=\"{}\"/>",
+                      xml_escape(self.reason)));
+        try!(writeln!(out, "    </properties>"));
+
+        for case in cases {
+            try!(write!(out,
+                        "    <testcase name=\"{}\" classname=\"{}\"",
+                        xml_escape(&case.id),
+                        xml_escape(self.scenario)));
+            if let Some(duration) = case.duration {
+                try!(write!(out, " time=\"{:.3}\"", duration));
+            }
+            try!(writeln!(out, ">"));
+
+            match case.state {
+                TestState::Fail(ref reason) => {
+                    try!(writeln!(out,
+                                  "      <failure message=\"{}\">{}</failure>",
+                                  xml_escape(reason),
+                                  xml_escape(&case.last_line)))
+                }
+                TestState::Skip => try!(writeln!(out, "      <skipped/>")),
+                TestState::Pending => {
+                    try!(writeln!(out, "      <skipped message=\"not run\"/>"))
+                }
+                TestState::Starting | TestState::Running => {
+                    try!(writeln!(out, "      <error message=\"still running\"/>"))
+                }
+                TestState::Pass => (),
+            }
+
+            if !case.stdout.is_empty() {
+                try!(writeln!(out,
+                              "      <system-out>{}</system-out>",
+                              xml_escape(&case.stdout)));
+            }
+            if !case.stderr.is_empty() {
+                try!(writeln!(out,
+                              "      <system-err>{}</system-err>",
+                              xml_escape(&case.stderr)));
+            }
+            try!(writeln!(out, "    </testcase>"));
+        }
+
+        try!(writeln!(out, "  </testsuite>"));
+        writeln!(out, "</testsuites>")
+    }
+
+    fn write_tap<W: Write>(&self, out: &mut W, cases: &[TestCase]) -> io::Result<()> {
+        try!(writeln!(out, "TAP version 13"));
+        try!(writeln!(out, "1..{}", cases.len()));
+
+        for (number, case) in cases.iter().enumerate() {
+            let number = number + 1;
+            match case.state {
+                TestState::Pass => try!(writeln!(out, "ok {} - {}", number, case.id)),
+                TestState::Skip => try!(writeln!(out, "ok {} - {} # SKIP", number, case.id)),
+                TestState::Pending => {
+                    try!(writeln!(out, "ok {} - {} # SKIP not run", number, case.id))
+                }
+                TestState::Fail(_) |
+                TestState::Starting |
+                TestState::Running => try!(writeln!(out, "not ok {} - {}", number, case.id)),
+            }
+
+            try!(writeln!(out, "  ---"));
+            try!(writeln!(out, "  name: {}", yaml_quote(&case.name)));
+            if let TestState::Fail(ref reason) = case.state {
+                try!(writeln!(out, "  message: {}", yaml_quote(reason)));
+            }
+            if let Some(duration) = case.duration {
+                try!(writeln!(out, "  duration_ms: {}", (duration * 1000.0) as u64));
+            }
+            if !case.last_line.is_empty() {
+                try!(writeln!(out, "  last_line: {}", yaml_quote(&case.last_line)));
+            }
+            if !case.stdout.is_empty() {
+                try!(writeln!(out, "  stdout: {}", yaml_quote(&case.stdout)));
+            }
+            if !case.stderr.is_empty() {
+                try!(writeln!(out, "  stderr: {}", yaml_quote(&case.stderr)));
+            }
+            try!(writeln!(out, "  ..."));
+        }
+
+        writeln!(out, "# {} {}", self.result, self.reason)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn at(secs: u64) -> time::SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn iso8601_dates() {
+        assert_eq!(iso8601(at(0)), "1970-01-01T00:00:00");
+        assert_eq!(iso8601(at(1485942257)), "2017-02-01T09:44:17");
+        assert_eq!(iso8601(at(951782400)), "2000-02-29T00:00:00");
+        assert_eq!(iso8601(at(1709251199)), "2024-02-29T23:59:59");
+        assert_eq!(iso8601(at(4107542399)), "2100-02-28T23:59:59");
+    }
+
+    #[test]
+    fn iso8601_before_epoch() {
+        assert_eq!(iso8601(UNIX_EPOCH - Duration::from_secs(1)), "1970-01-01T00:00:00");
+    }
+
+    #[test]
+    fn xml_escape_markup() {
+        assert_eq!(xml_escape("a < b && c > \"d\" 'e'"),
+                   "a &lt; b &amp;&amp; c &gt; &quot;d&quot; &apos;e&apos;");
+        assert_eq!(xml_escape("plain text"), "plain text");
+    }
+
+    #[test]
+    fn xml_escape_control_characters() {
+        assert_eq!(xml_escape("line 1\r\n\tline 2"), "line 1\r\n\tline 2");
+        assert_eq!(xml_escape("bell\u{7} escape\u{1b}[0m"), "bell escape[0m");
+        assert_eq!(xml_escape("温度"), "温度");
+    }
+}
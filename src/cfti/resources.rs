@@ -0,0 +1,261 @@
+/// Physical resources, such as a debug probe or a power supply, that tests
+/// running at the same time have to take turns with.
+
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ResourceMode {
+    /// Only one test may hold the resource at a time.
+    Exclusive,
+
+    /// Any number of tests may hold the resource together, as long as none
+    /// of them need it exclusively.
+    Shared,
+}
+
+/// A test's claim on a resource, from its Resources= field.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResourceClaim {
+    pub name: String,
+    pub mode: ResourceMode,
+}
+
+impl ResourceClaim {
+    /// Parse a claim such as "swd", "swd:exclusive", or "psu:shared".
+    pub fn from_str(s: &str) -> Option<ResourceClaim> {
+        let mut parts = s.splitn(2, ':');
+        let name = parts.next().unwrap_or("").trim();
+        if name.is_empty() {
+            return None;
+        }
+        let mode = match parts.next().map(|m| m.trim().to_lowercase()) {
+            None => ResourceMode::Exclusive,
+            Some(ref m) if m == "exclusive" => ResourceMode::Exclusive,
+            Some(ref m) if m == "shared" => ResourceMode::Shared,
+            Some(_) => return None,
+        };
+        Some(ResourceClaim {
+            name: name.to_string(),
+            mode: mode,
+        })
+    }
+}
+
+impl fmt::Display for ResourceClaim {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.mode {
+            ResourceMode::Exclusive => write!(f, "{}", self.name),
+            ResourceMode::Shared => write!(f, "{}:shared", self.name),
+        }
+    }
+}
+
+/// Who currently holds a resource.
+#[derive(Debug)]
+struct Holders {
+    mode: ResourceMode,
+    tests: Vec<String>,
+}
+
+/// What a test is waiting for.
+#[derive(Debug)]
+struct Waiter {
+    claim: ResourceClaim,
+
+    /// A test that was holding the resource when the wait began.
+    holder: String,
+}
+
+/// Keeps track of which tests hold which resources during a scenario run.
+#[derive(Debug)]
+pub struct ResourceManager {
+    held: HashMap<String, Holders>,
+
+    /// Tests that are waiting, by name.
+    waiting: HashMap<String, Waiter>,
+}
+
+impl ResourceManager {
+    pub fn new() -> ResourceManager {
+        ResourceManager {
+            held: HashMap::new(),
+            waiting: HashMap::new(),
+        }
+    }
+
+    /// Forget about every resource and waiting test, e.g. at the start of a run.
+    pub fn clear(&mut self) {
+        self.held.clear();
+        self.waiting.clear();
+    }
+
+    /// If any of the `claims` of `test` can't be had right now, return that
+    /// claim and a test that's holding it, or that's ahead of `test` in line.
+    ///
+    /// Once a test is waiting for a resource exclusively, nobody else may start
+    /// sharing it, so that a steady stream of shared claims can't starve it.
+    pub fn blocked_by(&self,
+                      test: &str,
+                      claims: &[ResourceClaim])
+                      -> Option<(ResourceClaim, String)> {
+        for claim in claims {
+            let holder = match self.held.get(&claim.name) {
+                Some(h) if !h.tests.is_empty() => {
+                    if claim.mode == ResourceMode::Exclusive || h.mode == ResourceMode::Exclusive {
+                        return Some((claim.clone(), h.tests[0].clone()));
+                    }
+                    Some(h.tests[0].clone())
+                }
+                _ => None,
+            };
+
+            if claim.mode == ResourceMode::Shared {
+                let exclusive_waiter = self.waiting.iter().find(|&(t, w)| {
+                    t != test && w.claim.name == claim.name &&
+                    w.claim.mode == ResourceMode::Exclusive
+                });
+                if let Some((waiter, _)) = exclusive_waiter {
+                    return Some((claim.clone(), holder.unwrap_or(waiter.clone())));
+                }
+            }
+        }
+        None
+    }
+
+    /// Give `claims` to `test`.  Check blocked_by() first.
+    pub fn acquire(&mut self, test: &str, claims: &[ResourceClaim]) {
+        self.waiting.remove(test);
+        for claim in claims {
+            let holders = self.held.entry(claim.name.clone()).or_insert(Holders {
+                mode: claim.mode.clone(),
+                tests: vec![],
+            });
+            if holders.tests.is_empty() {
+                holders.mode = claim.mode.clone();
+            }
+            holders.tests.push(test.to_string());
+        }
+    }
+
+    /// Give back everything `test` holds.
+    pub fn release(&mut self, test: &str) {
+        for holders in self.held.values_mut() {
+            holders.tests.retain(|t| t != test);
+        }
+    }
+
+    /// Note that `test` is waiting on `claim`, held by `holder`.
+    /// Returns true if it wasn't already waiting on that resource, so
+    /// that each wait is only reported once.
+    pub fn wait(&mut self, test: &str, claim: &ResourceClaim, holder: &str) -> bool {
+        if let Some(w) = self.waiting.get(test) {
+            if w.claim.name == claim.name {
+                return false;
+            }
+        }
+        self.waiting.insert(test.to_string(),
+                            Waiter {
+                                claim: claim.clone(),
+                                holder: holder.to_string(),
+                            });
+        true
+    }
+
+    /// Stop waiting on behalf of `test`, e.g. because it was skipped.
+    pub fn stop_waiting(&mut self, test: &str) {
+        self.waiting.remove(test);
+    }
+
+    /// Stop waiting on behalf of every test, and return what each one was waiting on.
+    pub fn take_waiting(&mut self) -> Vec<(String, String, String)> {
+        self.waiting.drain().map(|(test, w)| (test, w.claim.name, w.holder)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims(s: &[&str]) -> Vec<ResourceClaim> {
+        s.iter().map(|c| ResourceClaim::from_str(c).unwrap()).collect()
+    }
+
+    #[test]
+    fn claim_from_str() {
+        assert_eq!(ResourceClaim::from_str("swd"),
+                   Some(ResourceClaim {
+                       name: "swd".to_string(),
+                       mode: ResourceMode::Exclusive,
+                   }));
+        assert_eq!(ResourceClaim::from_str("psu:Shared"),
+                   Some(ResourceClaim {
+                       name: "psu".to_string(),
+                       mode: ResourceMode::Shared,
+                   }));
+        assert_eq!(ResourceClaim::from_str(":shared"), None);
+        assert_eq!(ResourceClaim::from_str("psu:sometimes"), None);
+    }
+
+    #[test]
+    fn exclusive_blocks_everyone() {
+        let mut resources = ResourceManager::new();
+        resources.acquire("flash", &claims(&["swd"]));
+        assert_eq!(resources.blocked_by("erase", &claims(&["swd"])),
+                   Some((ResourceClaim::from_str("swd").unwrap(), "flash".to_string())));
+        assert_eq!(resources.blocked_by("probe", &claims(&["swd:shared"])),
+                   Some((ResourceClaim::from_str("swd:shared").unwrap(), "flash".to_string())));
+        assert_eq!(resources.blocked_by("uart", &claims(&["uart0"])), None);
+
+        resources.release("flash");
+        assert_eq!(resources.blocked_by("erase", &claims(&["swd"])), None);
+    }
+
+    #[test]
+    fn shared_claims_overlap() {
+        let mut resources = ResourceManager::new();
+        resources.acquire("vdd", &claims(&["psu:shared"]));
+        assert_eq!(resources.blocked_by("current", &claims(&["psu:shared"])), None);
+        resources.acquire("current", &claims(&["psu:shared"]));
+        assert_eq!(resources.blocked_by("calibrate", &claims(&["psu"])),
+                   Some((ResourceClaim::from_str("psu").unwrap(), "vdd".to_string())));
+    }
+
+    #[test]
+    fn exclusive_waiter_holds_back_new_sharers() {
+        let mut resources = ResourceManager::new();
+        resources.acquire("vdd", &claims(&["psu:shared"]));
+
+        let calibrate = claims(&["psu"]);
+        let (claim, holder) = resources.blocked_by("calibrate", &calibrate).unwrap();
+        assert!(resources.wait("calibrate", &claim, &holder));
+        assert!(!resources.wait("calibrate", &claim, &holder));
+
+        // A new sharer has to get in line behind the exclusive claim.
+        assert_eq!(resources.blocked_by("current", &claims(&["psu:shared"])),
+                   Some((ResourceClaim::from_str("psu:shared").unwrap(), "vdd".to_string())));
+
+        resources.release("vdd");
+        assert_eq!(resources.blocked_by("calibrate", &calibrate), None);
+        assert_eq!(resources.blocked_by("current", &claims(&["psu:shared"])),
+                   Some((ResourceClaim::from_str("psu:shared").unwrap(),
+                         "calibrate".to_string())));
+
+        resources.acquire("calibrate", &calibrate);
+        resources.release("calibrate");
+        assert_eq!(resources.blocked_by("current", &claims(&["psu:shared"])), None);
+    }
+
+    #[test]
+    fn take_waiting() {
+        let mut resources = ResourceManager::new();
+        resources.acquire("daemon", &claims(&["swd"]));
+        let flash = ResourceClaim::from_str("swd").unwrap();
+        resources.wait("flash", &flash, "daemon");
+        resources.wait("erase", &flash, "daemon");
+        resources.stop_waiting("erase");
+        assert_eq!(resources.take_waiting(),
+                   vec![("flash".to_string(), "swd".to_string(), "daemon".to_string())]);
+        assert!(resources.take_waiting().is_empty());
+    }
+}
@@ -0,0 +1,172 @@
+/// An extra pipe that tests write structured result records to, on fd 3.
+///
+/// Runny doesn't let us hand a child extra file descriptors, so the pipe is a
+/// FIFO in a temporary directory.  The test command is wrapped in a shell that
+/// opens the FIFO as fd 3, and its path is also passed in CFTI_RESULT_PIPE.
+
+extern crate nix;
+extern crate shlex;
+
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+use std::sync::mpsc;
+use std::time::Duration;
+
+use self::nix::fcntl::O_NONBLOCK;
+use self::nix::sys::stat::{self, S_IFIFO, S_IRUSR, S_IWUSR};
+use self::nix::unistd;
+
+use cfti::process;
+use cfti::types::unit::Unit;
+
+/// The environment variable holding the path of the pipe.
+pub const RESULT_PIPE_VARIABLE: &'static str = "CFTI_RESULT_PIPE";
+
+/// Keeps pipe directories unique when a test runs more than once.
+static PIPE_COUNT: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// How long to wait for the reader to finish once the test has exited.
+const DRAIN_TIMEOUT_MSECS: u64 = 1000;
+
+/// Every pipe directory is named "[prefix][pid]-[unit]-[count]".
+const DIRECTORY_PREFIX: &'static str = "jig-20-";
+
+/// Remove pipe directories left behind by earlier runs of jig-20 that didn't
+/// get to clean up after themselves, e.g. because they were killed.
+///
+/// Directories belonging to a jig-20 that is still running are left alone.
+/// Processes are looked up in /proc, so nothing is removed if there isn't one.
+pub fn remove_stale() {
+    if !Path::new("/proc").is_dir() {
+        return;
+    }
+    let entries = match fs::read_dir(env::temp_dir()) {
+        Err(_) => return,
+        Ok(e) => e,
+    };
+    let our_pid = format!("{}", unistd::getpid());
+    for entry in entries {
+        let entry = match entry {
+            Err(_) => continue,
+            Ok(e) => e,
+        };
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !name.starts_with(DIRECTORY_PREFIX) {
+            continue;
+        }
+        let pid = match name[DIRECTORY_PREFIX.len()..].split('-').next() {
+            Some(p) if !p.is_empty() && p.chars().all(|c| c.is_digit(10)) => p.to_string(),
+            _ => continue,
+        };
+        if pid == our_pid || Path::new("/proc").join(&pid).exists() {
+            continue;
+        }
+        if entry.path().join("result").exists() {
+            fs::remove_dir_all(entry.path()).ok();
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ResultPipe {
+    /// The temporary directory holding the FIFO.
+    directory: PathBuf,
+
+    /// The FIFO itself.
+    path: PathBuf,
+
+    /// Signalled once the reader has reached the end of the pipe.
+    finished: Option<mpsc::Receiver<()>>,
+}
+
+/// Opens the FIFO on the first read, so that the open can block in the reader's
+/// own thread, until the test opens the other end.
+struct FifoReader {
+    path: PathBuf,
+    file: Option<File>,
+    finished: mpsc::Sender<()>,
+}
+
+impl Read for FifoReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.file.is_none() {
+            self.file = Some(try!(File::open(&self.path)));
+        }
+        self.file.as_mut().unwrap().read(buf)
+    }
+}
+
+impl Drop for FifoReader {
+    fn drop(&mut self) {
+        self.finished.send(()).ok();
+    }
+}
+
+impl ResultPipe {
+    /// Make a new, empty FIFO for a run of `unit_id`.
+    pub fn new(unit_id: &str) -> io::Result<ResultPipe> {
+        let directory = env::temp_dir().join(format!("{}{}-{}-{}",
+                                                     DIRECTORY_PREFIX,
+                                                     unistd::getpid(),
+                                                     unit_id,
+                                                     PIPE_COUNT.fetch_add(1, Ordering::SeqCst)));
+        fs::remove_dir_all(&directory).ok();
+        try!(fs::create_dir(&directory));
+
+        let path = directory.join("result");
+        if let Err(e) = stat::mknod(&path, S_IFIFO, S_IRUSR | S_IWUSR, 0) {
+            fs::remove_dir_all(&directory).ok();
+            return Err(io::Error::new(io::ErrorKind::Other, format!("{}", e)));
+        }
+
+        Ok(ResultPipe {
+            directory: directory,
+            path: path,
+            finished: None,
+        })
+    }
+
+    /// The variable to add to the test's environment.
+    pub fn environment(&self) -> (String, String) {
+        (RESULT_PIPE_VARIABLE.to_string(), self.path.to_string_lossy().into_owned())
+    }
+
+    /// Wrap `cmd` so that it runs with the pipe open as fd 3.
+    pub fn wrap_command(cmd: &str) -> String {
+        format!("sh -c {} jig-20 {}",
+                shlex::quote(&format!("exec 3>\"${}\" && exec \"$@\"", RESULT_PIPE_VARIABLE)),
+                cmd)
+    }
+
+    /// Call `msg_func` with each line the test writes to the pipe.
+    pub fn watch<F, U: Unit>(&mut self, unit: &U, msg_func: F) -> io::Result<()>
+        where F: Send + 'static + FnMut(String, &Unit) -> Result<(), ()>
+    {
+        let (tx, rx) = mpsc::channel();
+        let reader = FifoReader {
+            path: self.path.clone(),
+            file: None,
+            finished: tx,
+        };
+        try!(process::watch_output(reader, unit, msg_func));
+        self.finished = Some(rx);
+        Ok(())
+    }
+
+    /// Once the test has exited, give the reader a moment to catch up, and
+    /// then remove the FIFO.
+    pub fn close(&mut self) {
+        // If the test never opened the pipe, the reader is still waiting to open it.
+        // Opening and closing the other end lets it see the end of the pipe.
+        OpenOptions::new().write(true).custom_flags(O_NONBLOCK.bits()).open(&self.path).ok();
+
+        if let Some(finished) = self.finished.take() {
+            finished.recv_timeout(Duration::from_millis(DRAIN_TIMEOUT_MSECS)).ok();
+        }
+        fs::remove_dir_all(&self.directory).ok();
+    }
+}
@@ -0,0 +1,415 @@
+/// A built-in results store, which records every scenario run into a local
+/// SQLite database.  The schema is described in doc/Results.md.
+
+extern crate rusqlite;
+
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use self::rusqlite::{Connection, SQLITE_OPEN_READ_ONLY};
+use self::rusqlite::types::ToSql;
+
+use cfti::controller::{Controller, BroadcastMessage, BroadcastMessageContents};
+
+/// How long records may wait in an open transaction before they're committed.
+const COMMIT_INTERVAL_MS: u64 = 1000;
+
+/// Bumped whenever the schema changes, and stored in "PRAGMA user_version".
+const SCHEMA_VERSION: i32 = 1;
+
+const SCHEMA: &'static str = "
+    CREATE TABLE IF NOT EXISTS runs (
+        id INTEGER PRIMARY KEY,
+        jig TEXT,
+        scenario TEXT NOT NULL,
+        started REAL NOT NULL,
+        finished REAL,
+        duration REAL,
+        result INTEGER,
+        reason TEXT
+    );
+    CREATE TABLE IF NOT EXISTS tests (
+        id INTEGER PRIMARY KEY,
+        run_id INTEGER NOT NULL REFERENCES runs(id),
+        test TEXT NOT NULL,
+        state TEXT NOT NULL,
+        started REAL,
+        finished REAL,
+        duration REAL,
+        message TEXT,
+        last_line TEXT
+    );
+    CREATE TABLE IF NOT EXISTS logs (
+        id INTEGER PRIMARY KEY,
+        run_id INTEGER REFERENCES runs(id),
+        time REAL NOT NULL,
+        class TEXT NOT NULL,
+        unit TEXT NOT NULL,
+        unit_type TEXT NOT NULL,
+        message TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS measurements (
+        id INTEGER PRIMARY KEY,
+        run_id INTEGER REFERENCES runs(id),
+        time REAL NOT NULL,
+        test TEXT NOT NULL,
+        name TEXT NOT NULL,
+        value REAL NOT NULL,
+        unit TEXT NOT NULL,
+        low REAL,
+        high REAL,
+        result TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS runs_scenario ON runs(scenario, started);
+    CREATE INDEX IF NOT EXISTS tests_run ON tests(run_id, test);
+    CREATE INDEX IF NOT EXISTS logs_run ON logs(run_id);
+    CREATE INDEX IF NOT EXISTS measurements_name ON measurements(test, name);
+";
+
+#[derive(Debug)]
+pub enum ResultsError {
+    OpenFailed(String),
+    SchemaTooNew(i32),
+    QueryFailed(String),
+}
+
+/// One row of the "runs" table, along with the tests that failed.
+#[derive(Debug)]
+pub struct RunSummary {
+    pub id: i64,
+    pub jig: Option<String>,
+    pub scenario: String,
+    pub started: f64,
+    pub duration: Option<f64>,
+    pub result: Option<i32>,
+    pub reason: Option<String>,
+    pub failed: Vec<String>,
+}
+
+pub struct ResultsDb {
+    connection: Connection,
+
+    /// The jig we're running on, as last heard on the bus.
+    jig: Option<String>,
+
+    /// The run currently in progress, if any.
+    run_id: Option<i64>,
+
+    /// Set while records are being written inside a transaction that hasn't been committed.
+    in_transaction: bool,
+}
+
+fn timestamp(msg: &BroadcastMessage) -> f64 {
+    msg.unix_time as f64 + (msg.unix_time_nsecs as f64 / 1_000_000_000.0)
+}
+
+impl ResultsDb {
+    /// Open the database at `path`, creating it if it doesn't exist yet.
+    pub fn open(path: &str) -> Result<ResultsDb, ResultsError> {
+        let connection = match Connection::open(Path::new(path)) {
+            Err(e) => return Err(ResultsError::OpenFailed(format!("{}: {}", path, e))),
+            Ok(c) => c,
+        };
+        try!(Self::check_version(&connection, path));
+
+        // WAL keeps writes cheap on slow flash, and lets "results" read while a run goes on.
+        let setup = format!("PRAGMA journal_mode=WAL; PRAGMA synchronous=NORMAL; {} \
+                             PRAGMA user_version={};",
+                            SCHEMA,
+                            SCHEMA_VERSION);
+        if let Err(e) = connection.execute_batch(&setup) {
+            return Err(ResultsError::OpenFailed(format!("{}: {}", path, e)));
+        }
+
+        Ok(ResultsDb {
+            connection: connection,
+            jig: None,
+            run_id: None,
+            in_transaction: false,
+        })
+    }
+
+    /// Open the existing database at `path` read-only, for queries.
+    ///
+    /// Unlike open(), this never creates the database or writes to it, so
+    /// looking up results can't leave an empty database behind or change one.
+    pub fn open_for_query(path: &str) -> Result<ResultsDb, ResultsError> {
+        if !Path::new(path).is_file() {
+            return Err(ResultsError::OpenFailed(format!("{}: no such database", path)));
+        }
+        let connection = match Connection::open_with_flags(Path::new(path),
+                                                           SQLITE_OPEN_READ_ONLY) {
+            Err(e) => return Err(ResultsError::OpenFailed(format!("{}: {}", path, e))),
+            Ok(c) => c,
+        };
+        try!(Self::check_version(&connection, path));
+
+        Ok(ResultsDb {
+            connection: connection,
+            jig: None,
+            run_id: None,
+            in_transaction: false,
+        })
+    }
+
+    /// Refuse a database written by a newer jig-20, whose schema we don't know.
+    fn check_version(connection: &Connection, path: &str) -> Result<(), ResultsError> {
+        let version =
+            connection.query_row("PRAGMA user_version", &[], |row| row.get::<i32, i32>(0));
+        let version = match version {
+            Err(e) => return Err(ResultsError::OpenFailed(format!("{}: {}", path, e))),
+            Ok(v) => v,
+        };
+        if version > SCHEMA_VERSION {
+            return Err(ResultsError::SchemaTooNew(version));
+        }
+        Ok(())
+    }
+
+    /// Record every message from the bus into the database.
+    ///
+    /// Records are batched into a transaction, which is committed when a run
+    /// finishes, and otherwise at least once every COMMIT_INTERVAL_MS.
+    pub fn start(self, controller: &Controller) {
+        let db = Arc::new(Mutex::new(self));
+
+        let thr_db = db.clone();
+        let thr_controller = controller.clone();
+        controller.listen(move |msg| {
+            let mut db = thr_db.lock().unwrap();
+            let mut result = db.begin().and_then(|_| db.record(&msg));
+            match msg.message {
+                BroadcastMessageContents::Finish(_, _, _) |
+                BroadcastMessageContents::Shutdown(_) => result = result.and(db.commit()),
+                _ => (),
+            }
+            if let Err(e) = result {
+                Self::report(&thr_controller, e);
+            }
+            Ok(())
+        });
+
+        let thr_controller = controller.clone();
+        let builder = thread::Builder::new().name("R-results".into());
+        builder.spawn(move || loop {
+                thread::sleep(Duration::from_millis(COMMIT_INTERVAL_MS));
+                if let Err(e) = db.lock().unwrap().commit() {
+                    Self::report(&thr_controller, e);
+                }
+            })
+            .unwrap();
+    }
+
+    fn report(controller: &Controller, e: ResultsError) {
+        // Internal debug messages aren't recorded, so this can't loop.
+        let report = BroadcastMessageContents::Log(format!("Unable to record: {:?}", e));
+        controller.broadcast_class("debug-internal", "results", "results", &report);
+    }
+
+    fn begin(&mut self) -> Result<(), ResultsError> {
+        if self.in_transaction {
+            return Ok(());
+        }
+        match self.connection.execute_batch("BEGIN") {
+            Err(e) => Err(ResultsError::QueryFailed(format!("{}", e))),
+            Ok(_) => {
+                self.in_transaction = true;
+                Ok(())
+            }
+        }
+    }
+
+    fn commit(&mut self) -> Result<(), ResultsError> {
+        if !self.in_transaction {
+            return Ok(());
+        }
+        // If the commit fails, the transaction is still open, so try again next time.
+        match self.connection.execute_batch("COMMIT") {
+            Err(e) => Err(ResultsError::QueryFailed(format!("{}", e))),
+            Ok(_) => {
+                self.in_transaction = false;
+                Ok(())
+            }
+        }
+    }
+
+    fn execute(&self, sql: &str, params: &[&ToSql]) -> Result<i32, ResultsError> {
+        match self.connection.execute(sql, params) {
+            Err(e) => Err(ResultsError::QueryFailed(format!("{}", e))),
+            Ok(n) => Ok(n),
+        }
+    }
+
+    fn record(&mut self, msg: &BroadcastMessage) -> Result<(), ResultsError> {
+        let now = timestamp(msg);
+        match msg.message {
+            BroadcastMessageContents::Jig(ref jig) => self.jig = Some(jig.clone()),
+            BroadcastMessageContents::Start(ref scenario) => {
+                try!(self.execute("INSERT INTO runs (jig, scenario, started) VALUES (?, ?, ?)",
+                                  &[&self.jig, scenario, &now]));
+                self.run_id = Some(self.connection.last_insert_rowid());
+            }
+            BroadcastMessageContents::Finish(ref scenario, result, ref reason) => {
+                let result = result as i32;
+                match self.run_id.take() {
+                    Some(run_id) => {
+                        try!(self.execute("UPDATE runs SET finished = ?, \
+                                           duration = ? - started, result = ?, reason = ? \
+                                           WHERE id = ?",
+                                          &[&now, &now, &result, reason, &run_id]))
+                    }
+                    // A run that was refused before it started, e.g. by a coupon's
+                    // preflight check, finishes without ever sending START.
+                    None => {
+                        try!(self.execute("INSERT INTO runs (jig, scenario, started, finished, \
+                                           duration, result, reason) \
+                                           VALUES (?, ?, ?, ?, 0, ?, ?)",
+                                          &[&self.jig, scenario, &now, &now, &result, reason]))
+                    }
+                };
+            }
+            BroadcastMessageContents::Running(ref test) => {
+                if let Some(run_id) = self.run_id {
+                    try!(self.execute("INSERT INTO tests (run_id, test, state, started) \
+                                       VALUES (?, ?, 'running', ?)",
+                                      &[&run_id, test, &now]));
+                }
+            }
+            BroadcastMessageContents::Pass(ref test, ref message) => {
+                try!(self.finish_test(test, "pass", message, now))
+            }
+            BroadcastMessageContents::Fail(ref test, ref message) => {
+                try!(self.finish_test(test, "fail", message, now))
+            }
+            BroadcastMessageContents::Skip(ref test, ref message) => {
+                try!(self.finish_test(test, "skip", message, now))
+            }
+            BroadcastMessageContents::Measure(ref m) => {
+                let (low, high) = match m.limit {
+                    None => (None, None),
+                    Some(ref l) => (l.low, l.high),
+                };
+                try!(self.execute("INSERT INTO measurements (run_id, time, test, name, value, \
+                                   unit, low, high, result) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                                  &[&self.run_id,
+                                    &now,
+                                    &m.test,
+                                    &m.name,
+                                    &m.value,
+                                    &m.unit,
+                                    &low,
+                                    &high,
+                                    &m.result()]));
+            }
+            BroadcastMessageContents::Log(ref message) => {
+                if msg.message_class == "debug-internal" {
+                    return Ok(());
+                }
+                if let Some(run_id) = self.run_id {
+                    if msg.unit_type == "test" && msg.message_class == "stdout" {
+                        try!(self.execute("UPDATE tests SET last_line = ? \
+                                           WHERE run_id = ? AND test = ?",
+                                          &[message, &run_id, &msg.unit_id]));
+                    }
+                }
+                try!(self.execute("INSERT INTO logs (run_id, time, class, unit, unit_type, \
+                                   message) VALUES (?, ?, ?, ?, ?, ?)",
+                                  &[&self.run_id,
+                                    &now,
+                                    &msg.message_class,
+                                    &msg.unit_id,
+                                    &msg.unit_type,
+                                    message]));
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn finish_test(&self,
+                   test: &str,
+                   state: &str,
+                   message: &str,
+                   now: f64)
+                   -> Result<(), ResultsError> {
+        let run_id = match self.run_id {
+            None => return Ok(()),
+            Some(r) => r,
+        };
+        let updated = try!(self.execute("UPDATE tests SET state = ?, finished = ?, \
+                                         duration = ? - started, message = ? \
+                                         WHERE run_id = ? AND test = ?",
+                                        &[&state, &now, &now, &message, &run_id, &test]));
+
+        // Skipped tests never started running, so they don't have a row yet.
+        if updated == 0 {
+            try!(self.execute("INSERT INTO tests (run_id, test, state, finished, message) \
+                               VALUES (?, ?, ?, ?, ?)",
+                              &[&run_id, &test, &state, &now, &message]));
+        }
+        Ok(())
+    }
+
+    /// Return the most recent `count` runs, newest first.
+    pub fn recent_runs(&self,
+                       count: u32,
+                       scenario: Option<&str>)
+                       -> Result<Vec<RunSummary>, ResultsError> {
+        let mut statement = match self.connection
+            .prepare("SELECT id, jig, scenario, started, duration, result, reason FROM runs \
+                      WHERE ?1 IS NULL OR scenario = ?1 ORDER BY started DESC LIMIT ?2") {
+            Err(e) => return Err(ResultsError::QueryFailed(format!("{}", e))),
+            Ok(s) => s,
+        };
+        let count = count as i64;
+        let rows = statement.query_map(&[&scenario, &count], |row| {
+            RunSummary {
+                id: row.get(0),
+                jig: row.get(1),
+                scenario: row.get(2),
+                started: row.get(3),
+                duration: row.get(4),
+                result: row.get(5),
+                reason: row.get(6),
+                failed: vec![],
+            }
+        });
+        let rows = match rows {
+            Err(e) => return Err(ResultsError::QueryFailed(format!("{}", e))),
+            Ok(r) => r,
+        };
+
+        let mut runs = vec![];
+        for row in rows {
+            let mut run = match row {
+                Err(e) => return Err(ResultsError::QueryFailed(format!("{}", e))),
+                Ok(r) => r,
+            };
+            run.failed = try!(self.failed_tests(run.id));
+            runs.push(run);
+        }
+        Ok(runs)
+    }
+
+    fn failed_tests(&self, run_id: i64) -> Result<Vec<String>, ResultsError> {
+        let mut statement = match self.connection
+            .prepare("SELECT test FROM tests WHERE run_id = ? AND state = 'fail' ORDER BY id") {
+            Err(e) => return Err(ResultsError::QueryFailed(format!("{}", e))),
+            Ok(s) => s,
+        };
+        let rows = match statement.query_map(&[&run_id], |row| row.get(0)) {
+            Err(e) => return Err(ResultsError::QueryFailed(format!("{}", e))),
+            Ok(r) => r,
+        };
+        let mut tests = vec![];
+        for row in rows {
+            match row {
+                Err(e) => return Err(ResultsError::QueryFailed(format!("{}", e))),
+                Ok(t) => tests.push(t),
+            }
+        }
+        Ok(tests)
+    }
+}
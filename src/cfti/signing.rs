@@ -0,0 +1,218 @@
+extern crate ring;
+extern crate untrusted;
+
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::str;
+
+use self::ring::{digest, signature};
+use self::ring::signature::Ed25519KeyPair;
+
+#[derive(Debug)]
+pub enum SigningError {
+    KeyUnreadable(String),
+    KeyInvalid(String),
+    HexError(String),
+    BadSignature,
+}
+
+impl fmt::Display for SigningError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &SigningError::KeyUnreadable(ref s) => write!(f, "Unable to read key: {}", s),
+            &SigningError::KeyInvalid(ref s) => write!(f, "Invalid key: {}", s),
+            &SigningError::HexError(ref s) => write!(f, "Invalid hex string: {}", s),
+            &SigningError::BadSignature => write!(f, "Signature does not match"),
+        }
+    }
+}
+
+/// An Ed25519 private key, loaded from a PKCS#8 file on disk.
+pub struct SigningKey {
+    path: String,
+    pkcs8: Vec<u8>,
+}
+
+// Don't let the private key leak out into debug logs.
+impl fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SigningKey({})", self.path)
+    }
+}
+
+impl SigningKey {
+    /// Load a PKCS#8-encoded Ed25519 key from `path`.
+    pub fn load(path: &str) -> Result<SigningKey, SigningError> {
+        let pkcs8 = try!(read_file(path));
+
+        // Parse it once now, so that a bad key is caught when the unit is loaded
+        // rather than at the end of a scenario.
+        if let Err(e) = Ed25519KeyPair::from_pkcs8(untrusted::Input::from(&pkcs8)) {
+            return Err(SigningError::KeyInvalid(format!("{}: {:?}", path, e)));
+        }
+
+        Ok(SigningKey {
+            path: path.to_string(),
+            pkcs8: pkcs8,
+        })
+    }
+
+    fn key_pair(&self) -> Ed25519KeyPair {
+        // The key was validated in load(), so this cannot fail.
+        Ed25519KeyPair::from_pkcs8(untrusted::Input::from(&self.pkcs8)).unwrap()
+    }
+
+    /// The raw 32-byte public half of this key.
+    pub fn public_key(&self) -> Vec<u8> {
+        self.key_pair().public_key_bytes().to_vec()
+    }
+
+    pub fn sign(&self, msg: &[u8]) -> Vec<u8> {
+        self.key_pair().sign(msg).as_ref().to_vec()
+    }
+}
+
+/// Check that `sig` is a valid Ed25519 signature of `msg` made by `public_key`.
+pub fn verify(public_key: &[u8], msg: &[u8], sig: &[u8]) -> Result<(), SigningError> {
+    match signature::verify(&signature::ED25519,
+                            untrusted::Input::from(public_key),
+                            untrusted::Input::from(msg),
+                            untrusted::Input::from(sig)) {
+        Ok(_) => Ok(()),
+        Err(_) => Err(SigningError::BadSignature),
+    }
+}
+
+/// Load a public key, which is stored on disk as a hex string.
+pub fn load_public_key(path: &str) -> Result<Vec<u8>, SigningError> {
+    let contents = try!(read_file(path));
+    match String::from_utf8(contents) {
+        Ok(s) => from_hex(s.trim()),
+        Err(e) => Err(SigningError::KeyInvalid(format!("{}: {:?}", path, e))),
+    }
+}
+
+pub fn sha256(data: &[u8]) -> Vec<u8> {
+    digest::digest(&digest::SHA256, data).as_ref().to_vec()
+}
+
+pub fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(s: &str) -> Result<Vec<u8>, SigningError> {
+    if s.len() % 2 != 0 {
+        return Err(SigningError::HexError(s.to_string()));
+    }
+
+    let mut bytes = Vec::with_capacity(s.len() / 2);
+    for pair in s.as_bytes().chunks(2) {
+        let byte = match str::from_utf8(pair) {
+            Ok(p) => u8::from_str_radix(p, 16),
+            Err(_) => return Err(SigningError::HexError(s.to_string())),
+        };
+        match byte {
+            Ok(b) => bytes.push(b),
+            Err(_) => return Err(SigningError::HexError(s.to_string())),
+        }
+    }
+    Ok(bytes)
+}
+
+fn read_file(path: &str) -> Result<Vec<u8>, SigningError> {
+    let mut contents = vec![];
+    let mut file = match File::open(path) {
+        Ok(f) => f,
+        Err(e) => return Err(SigningError::KeyUnreadable(format!("{}: {:?}", path, e))),
+    };
+    if let Err(e) = file.read_to_end(&mut contents) {
+        return Err(SigningError::KeyUnreadable(format!("{}: {:?}", path, e)));
+    }
+    Ok(contents)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use super::ring::rand::SystemRandom;
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+
+    /// Write a new private key for a test to the temporary directory, and
+    /// return its path.
+    pub fn generate_key_file(name: &str) -> String {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let key_path = env::temp_dir().join(format!("cfti-test-{}.key", name));
+        fs::File::create(&key_path).unwrap().write_all(&pkcs8).unwrap();
+        key_path.to_str().unwrap().to_string()
+    }
+
+    /// Make a new key for a test, and save it along with its public half in
+    /// the temporary directory.  Returns the key and the path of the public half.
+    pub fn generate_key(name: &str) -> (SigningKey, String) {
+        let key_path = generate_key_file(name);
+        let key = SigningKey::load(&key_path).unwrap();
+        fs::remove_file(&key_path).unwrap();
+
+        let public_path = env::temp_dir().join(format!("cfti-test-{}.pub", name));
+        fs::File::create(&public_path)
+            .unwrap()
+            .write_all(format!("{}\n", to_hex(&key.public_key())).as_bytes())
+            .unwrap();
+        (key, public_path.to_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn sign_and_verify() {
+        let (key, public_path) = generate_key("signing-round-trip");
+        let public_key = load_public_key(&public_path).unwrap();
+        fs::remove_file(&public_path).unwrap();
+        assert_eq!(public_key, key.public_key());
+
+        let msg = b"CFTI-COUPON 1\ncoupon factory\n";
+        let sig = key.sign(msg);
+        assert!(verify(&public_key, msg, &sig).is_ok());
+
+        let mut tampered = msg.to_vec();
+        tampered[0] ^= 1;
+        assert!(verify(&public_key, &tampered, &sig).is_err());
+
+        let mut bad_sig = sig.clone();
+        bad_sig[10] ^= 1;
+        assert!(verify(&public_key, msg, &bad_sig).is_err());
+    }
+
+    #[test]
+    fn verify_with_wrong_key() {
+        let (key, public_path) = generate_key("signing-wrong-key");
+        fs::remove_file(&public_path).unwrap();
+        let (other, public_path) = generate_key("signing-other-key");
+        fs::remove_file(&public_path).unwrap();
+
+        let sig = key.sign(b"bundle");
+        assert!(verify(&other.public_key(), b"bundle", &sig).is_err());
+    }
+
+    #[test]
+    fn load_rejects_invalid_key() {
+        let path = env::temp_dir().join("cfti-test-signing-invalid.key");
+        fs::File::create(&path).unwrap().write_all(b"not a key").unwrap();
+        let result = SigningKey::load(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        match result {
+            Err(SigningError::KeyInvalid(_)) => (),
+            r => panic!("expected KeyInvalid, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn hex_round_trip() {
+        assert_eq!(to_hex(&[0x00, 0x7f, 0xa5, 0xff]), "007fa5ff");
+        assert_eq!(from_hex("007fA5ff").unwrap(), vec![0x00, 0x7f, 0xa5, 0xff]);
+        assert!(from_hex("abc").is_err());
+        assert!(from_hex("zz").is_err());
+        assert!(from_hex("").unwrap().is_empty());
+    }
+}
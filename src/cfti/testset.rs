@@ -2,12 +2,8 @@ extern crate termcolor;
 
 use cfti::config;
 use cfti::controller::{self, ControlMessageContents};
-use cfti::types::{Test, Scenario, Logger, Trigger, Jig, Interface};
+use cfti::types::{Test, Scenario, Logger, Trigger, Jig, Interface, Coupon, Service, Updater};
 use cfti::types::unit::Unit;
-// use cfti::types::Coupon;
-// use cfti::types::Updater;
-// use cfti::types::Service;
-//
 
 use std::collections::HashMap;
 use std::fs;
@@ -40,10 +36,21 @@ pub struct TestSet {
     /// Tests can "Provide" other tests.  This maps those.
     test_aliases: HashMap<String, String>,
 
-    // coupons: HashMap<String, Coupon>,
-    // updaters: HashMap<String, Updater>,
-    // services: HashMap<String, Service>,
-    //
+    /// Coupons, which are attached to the scenarios they are issued for.
+    coupons: HashMap<String, Arc<Mutex<Coupon>>>,
+
+    /// Background services, which run independently of any scenario.
+    services: HashMap<String, Arc<Mutex<Service>>>,
+
+    /// Updaters, which watch for new test bundles to install.
+    updaters: HashMap<String, Arc<Mutex<Updater>>>,
+
+    /// An updater whose bundle is waiting for the current scenario to finish.
+    pending_update: Option<String>,
+
+    /// The directory that the unit files were loaded from.
+    config_dir: String,
+
     /// The controller object, where messages come and go.
     controller: Controller,
 
@@ -57,11 +64,17 @@ pub enum TestSetCommand {
     AbortScenario,
     SetScenario(String),
     SetInterfaceHello(String, String),
-    StartScenario(Option<String>),
+    SetInterfaceLocale(String, String),
+    RestartInterface(String, String),
+    StartScenario(Option<String>, Option<u32>),
     AdvanceScenario,
+    RetryTest(String),
+    RepeatScenario,
     AbortTests,
     SendScenarios,
     SendTests(Option<String>),
+    InstallUpdate(String),
+    Answer(String, String),
     Shutdown,
 }
 
@@ -84,6 +97,11 @@ impl TestSet {
             jig: None,
             scenario: None,
             interfaces: HashMap::new(),
+            coupons: HashMap::new(),
+            services: HashMap::new(),
+            updaters: HashMap::new(),
+            pending_update: None,
+            config_dir: dir.to_string(),
             controller: controller.clone(),
             receiver: receiver,
         };
@@ -145,12 +163,12 @@ impl TestSet {
         test_set.load_jigs(&config, &jig_paths);
         test_set.load_loggers(&config, &logger_paths);
         test_set.load_interfaces(&config, &interface_paths);
-        // test_set.load_services(&service_paths);
-        // test_set.load_updaters(&updater_paths);
+        test_set.load_services(&config, &service_paths);
+        test_set.load_updaters(&config, &updater_paths);
         test_set.load_tests(&config, &test_paths);
         test_set.load_scenarios(&config, &scenario_paths);
         test_set.load_triggers(&config, &trigger_paths);
-        // test_set.load_coupons(&coupon_paths);
+        test_set.load_coupons(&config, &coupon_paths);
 
         Ok(test_set)
     }
@@ -267,6 +285,65 @@ impl TestSet {
         }
     }
 
+    fn load_services(&mut self, config: &config::Config, service_paths: &Vec<PathBuf>) {
+
+        // Start the service with the jig's default working directory.
+        let working_directory = match self.jig {
+            None => None,
+            Some(ref jig) => (jig.lock().unwrap().default_working_directory()).clone(),
+        };
+
+        for service_path in service_paths {
+            let item_name =
+                service_path.file_stem().unwrap_or(OsStr::new("")).to_str().unwrap_or("");
+            let path_str = service_path.to_str().unwrap_or("");
+            let new_service = match Service::new(item_name, path_str, self, config) {
+                // In this case, it just means the service is incompatible.
+                None => continue,
+                Some(s) => {
+                    match s {
+                        Err(e) => {
+                            self.debug(format!("Unable to load service {}: {:?}", item_name, e));
+                            continue;
+                        }
+                        Ok(s) => s,
+                    }
+                }
+            };
+
+            new_service.start(&working_directory);
+
+            self.services.insert(new_service.id().to_string(),
+                                 Arc::new(Mutex::new(new_service)));
+        }
+    }
+
+    fn load_updaters(&mut self, config: &config::Config, updater_paths: &Vec<PathBuf>) {
+        for updater_path in updater_paths {
+            let item_name =
+                updater_path.file_stem().unwrap_or(OsStr::new("")).to_str().unwrap_or("");
+            let path_str = updater_path.to_str().unwrap_or("");
+            let new_updater = match Updater::new(item_name, path_str, self, config) {
+                // In this case, it just means the updater is incompatible.
+                None => continue,
+                Some(s) => {
+                    match s {
+                        Err(e) => {
+                            self.debug(format!("Unable to load updater {}: {:?}", item_name, e));
+                            continue;
+                        }
+                        Ok(s) => s,
+                    }
+                }
+            };
+
+            new_updater.start();
+
+            self.updaters.insert(new_updater.id().to_string(),
+                                 Arc::new(Mutex::new(new_updater)));
+        }
+    }
+
     fn load_triggers(&mut self, config: &config::Config, trigger_paths: &Vec<PathBuf>) {
 
         // Start the trigger with the jig's default working directory.
@@ -393,6 +470,47 @@ impl TestSet {
         self.send_tests(None);
     }
 
+    fn load_coupons(&mut self, config: &config::Config, paths: &Vec<PathBuf>) {
+        for path in paths {
+            let item_name = path.file_stem().unwrap_or(OsStr::new("")).to_str().unwrap_or("");
+            let path_str = path.to_str().unwrap_or("");
+            let new_coupon = match Coupon::new(item_name, path_str, self, config) {
+                // In this case, it just means the coupon is incompatible.
+                None => continue,
+                Some(s) => {
+                    match s {
+                        Err(e) => {
+                            self.warn(format!("Unable to load coupon {}: {:?}", item_name, e));
+                            continue;
+                        }
+                        Ok(s) => s,
+                    }
+                }
+            };
+
+            if let Some(key) = new_coupon.public_key() {
+                self.log(format!("Coupon {} signs with public key {}", new_coupon.id(), key));
+            }
+
+            let new_coupon_id = new_coupon.id().to_string();
+            let new_coupon = Arc::new(Mutex::new(new_coupon));
+
+            // Attach the coupon to each scenario it is issued for.
+            for scenario_id in new_coupon.lock().unwrap().scenarios() {
+                match self.scenarios.get(scenario_id) {
+                    None => {
+                        self.debug(format!("Coupon {} refers to unknown scenario {}",
+                                           new_coupon_id,
+                                           scenario_id))
+                    }
+                    Some(s) => s.lock().unwrap().add_coupon(new_coupon.clone()),
+                }
+            }
+
+            self.coupons.insert(new_coupon_id, new_coupon);
+        }
+    }
+
     pub fn get_jig_default_scenario(&self) -> Option<String> {
         match self.jig.as_ref() {
             None => None,
@@ -421,6 +539,13 @@ impl TestSet {
         };
     }
 
+    pub fn retry_test(&self, test_id: String) {
+        // If the scenario has gone away, so has the reason to retry the test.
+        if let Some(ref s) = self.scenario {
+            s.lock().unwrap().retry(&test_id);
+        }
+    }
+
     pub fn abort_scenario(&self) {
         // If there is no scenario, that's fine.  There's nothing to do.
         if let Some(ref s) = self.scenario {
@@ -428,7 +553,8 @@ impl TestSet {
         }
     }
 
-    pub fn start_scenario(&mut self, scenario_id: Option<String>) {
+    /// Run a scenario, `count` times if given, or else as many times as its Repeat= says.
+    pub fn start_scenario(&mut self, scenario_id: Option<String>, count: Option<u32>) {
 
         // Figure out what scenario to run.  Run the default scenario if unspecified.
         let scenario: Arc<Mutex<Scenario>> = match scenario_id {
@@ -456,7 +582,66 @@ impl TestSet {
             Some(ref jig) => (jig.lock().unwrap().default_working_directory()).clone(),
         };
 
-        scenario.start(&working_directory);
+        scenario.start(&working_directory, count);
+    }
+
+    /// Run the next iteration of a repeated scenario.
+    pub fn repeat_scenario(&self) {
+        if let Some(ref s) = self.scenario {
+            s.lock().unwrap().repeat();
+        }
+    }
+
+    /// Pass `answer` on to whichever test is waiting on prompt `id`.
+    pub fn answer_prompt(&self, id: String, answer: String) {
+        for test in self.tests.values() {
+            if test.lock().unwrap().answer(&id, &answer) {
+                return;
+            }
+        }
+        self.warn(format!("No test is waiting for an answer to prompt {}", id));
+    }
+
+    /// Install a staged update bundle, unless a scenario is currently running.
+    ///
+    /// If a scenario is running, the update is deferred until it finishes.
+    pub fn install_update(&mut self, updater_id: String) {
+        let scenario_running = match self.scenario {
+            None => false,
+            Some(ref s) => s.lock().unwrap().is_running(),
+        };
+        if scenario_running {
+            self.pending_update = Some(updater_id.clone());
+            self.broadcast(BroadcastMessageContents::Update(updater_id,
+                                                            "deferred".to_string(),
+                                                            "waiting for scenario to finish"
+                                                                .to_string()));
+            return;
+        }
+        self.pending_update = None;
+
+        let updater = match self.updaters.get(&updater_id) {
+            None => {
+                self.debug(format!("Unable to find updater: {}", updater_id));
+                return;
+            }
+            Some(u) => u,
+        };
+
+        let msg = match updater.lock().unwrap().install() {
+            Err(e) => {
+                BroadcastMessageContents::Update(updater_id,
+                                                 "fail".to_string(),
+                                                 format!("{:?}", e))
+            }
+            Ok(_) => {
+                BroadcastMessageContents::Update(updater_id,
+                                                 "installed".to_string(),
+                                                 format!("restart to load the new tests in {}",
+                                                         self.config_dir))
+            }
+        };
+        self.broadcast(msg);
     }
 
     pub fn send_scenarios(&self) {
@@ -488,6 +673,44 @@ impl TestSet {
         }
     }
 
+    /// Change the language for one interface, and then describe everything
+    /// again so it gets the strings in its new language.
+    pub fn set_interface_locale(&self, id: String, locale: String) {
+        match self.interfaces.get(&id) {
+            None => return,
+            Some(s) => s.lock().unwrap().set_locale(locale),
+        }
+        self.describe_all();
+    }
+
+    /// Describe the jig, and every test and scenario.
+    fn describe_all(&self) {
+        self.describe_jig();
+        self.send_scenarios();
+        for (_, test) in self.tests.iter() {
+            test.lock().unwrap().describe();
+        }
+        for (_, scenario) in self.scenarios.iter() {
+            scenario.lock().unwrap().describe();
+        }
+    }
+
+    /// Kill an interface that has hung or exited, and launch it again.
+    pub fn restart_interface(&self, id: String, reason: String) {
+        let working_directory = match self.jig {
+            None => None,
+            Some(ref jig) => (jig.lock().unwrap().default_working_directory()).clone(),
+        };
+
+        match self.interfaces.get(&id) {
+            None => return,
+            Some(s) => s.lock().unwrap().restart(reason, &working_directory),
+        }
+
+        // The new process doesn't know anything yet.
+        self.describe_all();
+    }
+
     pub fn set_scenario(&mut self, scenario_name: &String) {
         let scenario = match self.scenarios.get(scenario_name) {
             None => {
@@ -517,23 +740,69 @@ impl TestSet {
                 TestSetCommand::AbortScenario => self.abort_scenario(),
                 TestSetCommand::SetScenario(new_scenario) => self.set_scenario(&new_scenario),
                 TestSetCommand::SetInterfaceHello(id, msg) => self.set_interface_hello(id, msg),
-                TestSetCommand::StartScenario(optional_name) => self.start_scenario(optional_name),
+                TestSetCommand::SetInterfaceLocale(id, locale) => {
+                    self.set_interface_locale(id, locale)
+                }
+                TestSetCommand::RestartInterface(id, reason) => self.restart_interface(id, reason),
+                TestSetCommand::StartScenario(optional_name, count) => {
+                    self.start_scenario(optional_name, count)
+                }
                 TestSetCommand::AdvanceScenario => self.advance_scenario(),
+                TestSetCommand::RetryTest(id) => self.retry_test(id),
+                TestSetCommand::RepeatScenario => self.repeat_scenario(),
                 TestSetCommand::AbortTests => self.abort_scenario(),
                 TestSetCommand::SendScenarios => self.send_scenarios(),
                 TestSetCommand::SendTests(optional_name) => self.send_tests(optional_name),
-                TestSetCommand::Shutdown => return,
+                TestSetCommand::InstallUpdate(updater_id) => self.install_update(updater_id),
+                TestSetCommand::Answer(id, answer) => self.answer_prompt(id, answer),
+                TestSetCommand::Shutdown => {
+                    self.stop_services();
+                    self.retire_interfaces();
+                    return;
+                }
+            }
+
+            // If an update was waiting on a scenario, see if it can go in now.
+            if let Some(updater_id) = self.pending_update.clone() {
+                let scenario_running = match self.scenario {
+                    None => false,
+                    Some(ref s) => s.lock().unwrap().is_running(),
+                };
+                if !scenario_running {
+                    self.install_update(updater_id);
+                }
             }
         }
     }
 
+    pub fn stop_services(&self) {
+        for (_, service) in self.services.iter() {
+            service.lock().unwrap().stop();
+        }
+    }
+
+    /// Stop restarting interfaces, since they're expected to exit now.
+    pub fn retire_interfaces(&self) {
+        for (_, interface) in self.interfaces.iter() {
+            interface.lock().unwrap().retire();
+        }
+    }
+
     pub fn jigs(&self) -> &HashMap<String, Arc<Mutex<Jig>>> {
         &self.jigs
     }
 
+    pub fn jig(&self) -> &Option<Arc<Mutex<Jig>>> {
+        &self.jig
+    }
+
     pub fn tests(&self) -> &HashMap<String, Arc<Mutex<Test>>> {
         &self.tests
     }
+
+    pub fn config_dir(&self) -> &str {
+        self.config_dir.as_str()
+    }
 }
 
 impl Unit for TestSet {
@@ -0,0 +1,585 @@
+extern crate runny;
+
+use std::fs::File;
+use std::io::{self, BufRead, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
+use std::time;
+
+use self::runny::running::Running;
+
+use cfti::types::unit::Unit;
+use cfti::types::test::{Test, TestState};
+use cfti::controller::Controller;
+use cfti::process;
+use cfti::config;
+use cfti::signing::{self, SigningKey};
+use cfti::testset;
+use cfti::unitfile::UnitFile;
+
+/// The first line of every coupon certificate.
+const CERTIFICATE_HEADER: &'static str = "CFTI-COUPON 1";
+
+#[derive(Debug)]
+pub enum CouponError {
+    FileLoadError(String),
+    MissingCouponSection,
+    MissingScenarios,
+    SigningKeyError(String),
+    PreflightFailed(String),
+    StopSuccessFailed(String),
+    CertificateWriteError(String),
+    CertificateInvalid(String),
+    InvalidPreflightTimeout(String),
+}
+
+#[derive(Debug)]
+pub struct Coupon {
+    /// id: The string that other units refer to this file as.
+    id: String,
+
+    /// name: Display name of this coupon.
+    name: String,
+
+    /// description: Paragraph describing this coupon.
+    description: String,
+
+    /// scenarios: The scenarios that this coupon is issued for.
+    scenarios: Vec<String>,
+
+    /// exec_preflight: A command to run before any tests are started.
+    exec_preflight: Option<String>,
+
+    /// exec_stop_success: A command to run when a scenario passes.
+    exec_stop_success: Option<String>,
+
+    /// exec_stop_failure: A command to run when a scenario fails.
+    exec_stop_failure: Option<String>,
+
+    /// working_directory: Directory to run the coupon commands from.
+    working_directory: Option<String>,
+
+    /// The key used to sign certificates.  If None, no certificate is generated.
+    signing_key: Option<SigningKey>,
+
+    /// Where certificates get written to.
+    certificate_directory: Option<String>,
+
+    /// The jig this coupon is being issued on.
+    jig_id: String,
+
+    /// How long to let each coupon command run for.
+    timeout: time::Duration,
+
+    /// How long to let ExecPreflight run for.
+    preflight_timeout: time::Duration,
+
+    /// When the current scenario run passed its preflight check.
+    start_time: Arc<Mutex<time::SystemTime>>,
+
+    /// The controller where messages come and go.
+    controller: Controller,
+}
+
+impl Coupon {
+    pub fn new(id: &str,
+               path: &str,
+               test_set: &testset::TestSet,
+               config: &config::Config)
+               -> Option<Result<Coupon, CouponError>> {
+
+        let jigs = test_set.jigs();
+
+        // Load the .ini file
+        let unitfile = match UnitFile::new(path) {
+            Err(e) => return Some(Err(CouponError::FileLoadError(format!("{:?}", e)))),
+            Ok(s) => s,
+        };
+
+        if !unitfile.has_section("Coupon") {
+            return Some(Err(CouponError::MissingCouponSection));
+        }
+
+        // Check to see if this coupon is compatible with this jig.
+        match unitfile.get("Coupon", "Jigs") {
+            None => (),
+            Some(s) => {
+                let jig_names: Vec<String> =
+                    s.split(|c| c == ',' || c == ' ').map(|s| s.to_string()).collect();
+                let mut found_it = false;
+                for jig_name in jig_names {
+                    if jigs.get(&jig_name).is_some() {
+                        found_it = true;
+                        break;
+                    }
+                }
+                if found_it == false {
+                    test_set.debug(format!("The coupon '{}' is not compatible with this jig", id));
+                    return None;
+                }
+            }
+        }
+
+        let description = match unitfile.get_localized("Coupon", "Description", config.locale()) {
+            None => "".to_string(),
+            Some(s) => s.to_string(),
+        };
+
+        let name = match unitfile.get_localized("Coupon", "Name", config.locale()) {
+            None => id.to_string(),
+            Some(s) => s.to_string(),
+        };
+
+        let scenarios: Vec<String> = match unitfile.get("Coupon", "Scenarios") {
+            None => return Some(Err(CouponError::MissingScenarios)),
+            Some(s) => {
+                s.split(|c| c == ',' || c == ' ')
+                    .map(|x| x.trim().to_string())
+                    .filter(|x| !x.is_empty())
+                    .collect()
+            }
+        };
+
+        let exec_preflight = match unitfile.get("Coupon", "ExecPreflight") {
+            None => None,
+            Some(s) => Some(s.to_string()),
+        };
+
+        let preflight_timeout = match unitfile.get("Coupon", "PreflightTimeout") {
+            None => config.scenario_start_timeout(),
+            Some(s) => {
+                match s.trim().parse() {
+                    Err(_) => return Some(Err(CouponError::InvalidPreflightTimeout(s.to_string()))),
+                    Ok(n) => time::Duration::from_secs(n),
+                }
+            }
+        };
+
+        let exec_stop_success = match unitfile.get("Coupon", "ExecStopSuccess") {
+            None => None,
+            Some(s) => Some(s.to_string()),
+        };
+
+        let exec_stop_failure = match unitfile.get("Coupon", "ExecStopFail") {
+            None => None,
+            Some(s) => Some(s.to_string()),
+        };
+
+        let working_directory = match unitfile.get("Coupon", "WorkingDirectory") {
+            None => config.default_working_directory().clone(),
+            Some(s) => Some(s.to_string()),
+        };
+
+        let signing_key = match unitfile.get("Coupon", "SigningKey") {
+            None => None,
+            Some(s) => {
+                match SigningKey::load(s) {
+                    Ok(k) => Some(k),
+                    Err(e) => return Some(Err(CouponError::SigningKeyError(format!("{}", e)))),
+                }
+            }
+        };
+
+        let certificate_directory = match unitfile.get("Coupon", "CertificateDirectory") {
+            None => working_directory.clone(),
+            Some(s) => Some(s.to_string()),
+        };
+
+        let jig_id = match test_set.jig() {
+            &None => "".to_string(),
+            &Some(ref j) => j.lock().unwrap().id().to_string(),
+        };
+
+        Some(Ok(Coupon {
+            id: id.to_string(),
+            name: name,
+            description: description,
+            scenarios: scenarios,
+            exec_preflight: exec_preflight,
+            exec_stop_success: exec_stop_success,
+            exec_stop_failure: exec_stop_failure,
+            working_directory: working_directory,
+            signing_key: signing_key,
+            certificate_directory: certificate_directory,
+            jig_id: jig_id,
+            timeout: config.timeout(),
+            preflight_timeout: preflight_timeout,
+            start_time: Arc::new(Mutex::new(time::SystemTime::now())),
+            controller: test_set.controller().clone(),
+        }))
+    }
+
+    pub fn scenarios(&self) -> &Vec<String> {
+        &self.scenarios
+    }
+
+    /// The public half of the signing key, as a hex string, if there is one.
+    pub fn public_key(&self) -> Option<String> {
+        match self.signing_key {
+            None => None,
+            Some(ref k) => Some(signing::to_hex(&k.public_key())),
+        }
+    }
+
+    fn working_directory(&self, scenario_working_directory: &Option<String>) -> Option<String> {
+        match self.working_directory {
+            Some(ref s) => Some(s.clone()),
+            None => scenario_working_directory.clone(),
+        }
+    }
+
+    /// Start the ExecPreflight command before a scenario starts.
+    ///
+    /// Once it finishes, `completion` is called with any `KEY=VALUE` lines the
+    /// command printed, which get added to the environment of every command run
+    /// for the rest of the scenario.  If it's called with an error, the scenario
+    /// must not be run.  The command is returned so that it can be stopped early.
+    pub fn preflight<F>(&self,
+                        working_directory: &Option<String>,
+                        completion: F)
+                        -> Option<Running>
+        where F: Send + 'static + FnOnce(Result<process::Environment, CouponError>)
+    {
+        *(self.start_time.lock().unwrap()) = time::SystemTime::now();
+
+        let cmd = match self.exec_preflight {
+            None => {
+                completion(Ok(vec![]));
+                return None;
+            }
+            Some(ref s) => s.clone(),
+        };
+
+        self.log(format!("Running ExecPreflight: {}", cmd));
+
+        // stdout is read on its own thread, and may still be draining when the
+        // command exits, so the completion waits for the reader to hand it over.
+        let (lines_tx, lines_rx) = mpsc::channel();
+        let unit = self.to_simple_unit();
+        let thr_cmd = cmd.clone();
+        let res = process::try_command_completion(&cmd,
+                                                  &self.working_directory(working_directory),
+                                                  &[],
+                                                  self.preflight_timeout,
+                                                  move |res: Result<(), process::CommandError>| {
+            if let Err(e) = res {
+                completion(Err(CouponError::PreflightFailed(format!("{}: {:?}", thr_cmd, e))));
+                return;
+            }
+            let lines: Vec<String> = lines_rx.recv().unwrap_or(vec![]);
+
+            let (environment, other) = process::parse_environment(&lines);
+            for line in other {
+                unit.log(line);
+            }
+            for &(ref key, ref value) in &environment {
+                unit.debug(format!("Exporting {}={}", key, value));
+            }
+            completion(Ok(environment));
+        });
+
+        // If the command couldn't be started, the completion has already been called.
+        let mut running = match res {
+            Err(_) => return None,
+            Ok(r) => r,
+        };
+
+        process::log_output(running.take_error(), self, "stderr").unwrap();
+        let stdout = running.take_output();
+        thread::spawn(move || {
+            let mut lines = vec![];
+            for line in io::BufReader::new(stdout).lines() {
+                match line {
+                    Ok(l) => lines.push(l),
+                    Err(_) => break,
+                }
+            }
+            lines_tx.send(lines).ok();
+        });
+        Some(running)
+    }
+
+    /// Called once a scenario has finished and all of its tests have been terminated.
+    ///
+    /// If the scenario passed, a certificate is issued and ExecStopSuccess is run.
+    /// An error here means the scenario as a whole must be considered a failure.
+    pub fn finish(&self,
+                  scenario_id: &str,
+                  tests: &Vec<Arc<Mutex<Test>>>,
+                  passed: bool,
+                  working_directory: &Option<String>,
+                  environment: &process::Environment)
+                  -> Result<(), CouponError> {
+        let working_directory = self.working_directory(working_directory);
+
+        if !passed {
+            if let Some(ref cmd) = self.exec_stop_failure {
+                self.log(format!("Running ExecStopFail: {}", cmd));
+                process::try_command(self, cmd, &working_directory, environment, self.timeout);
+            }
+            return Ok(());
+        }
+
+        // Let ExecStopSuccess know where the certificate went.
+        let mut environment = environment.clone();
+        if let Some(ref key) = self.signing_key {
+            let path = try!(self.write_certificate(key, scenario_id, tests, &environment));
+            self.log(format!("Wrote coupon certificate to {}", path));
+            environment.push(("CFTI_COUPON_CERTIFICATE".to_string(), path));
+        }
+
+        if let Some(ref cmd) = self.exec_stop_success {
+            self.log(format!("Running ExecStopSuccess: {}", cmd));
+            if !process::try_command(self, cmd, &working_directory, &environment, self.timeout) {
+                return Err(CouponError::StopSuccessFailed(cmd.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    fn write_certificate(&self,
+                         key: &SigningKey,
+                         scenario_id: &str,
+                         tests: &Vec<Arc<Mutex<Test>>>,
+                         environment: &process::Environment)
+                         -> Result<String, CouponError> {
+        let start_time = unix_time(&Some(*(self.start_time.lock().unwrap())));
+        let finish_time = unix_time(&Some(time::SystemTime::now()));
+
+        // The body is a series of lines, one fact per line.  Every line gets
+        // hashed, and the hash is signed.
+        let mut body = String::new();
+        body.push_str(&format!("{}\n", CERTIFICATE_HEADER));
+        body.push_str(&format!("coupon {}\n", self.id()));
+        body.push_str(&format!("scenario {}\n", scenario_id));
+        body.push_str(&format!("jig {}\n", self.jig_id));
+        body.push_str(&format!("start {}\n", start_time));
+        body.push_str(&format!("finish {}\n", finish_time));
+        for &(ref key, ref value) in environment {
+            body.push_str(&format!("env {}={}\n", key, value.replace("\n", "\\n")));
+        }
+        for test in tests {
+            let test = test.lock().unwrap();
+            let result = match test.state() {
+                TestState::Pending => "pending",
+                TestState::Starting => "starting",
+                TestState::Running => "running",
+                TestState::Pass => "pass",
+                TestState::Skip => "skip",
+                TestState::Fail(_) => "fail",
+            };
+            body.push_str(&format!("test {} {} {} {}\n",
+                                   test.id(),
+                                   result,
+                                   unix_time(&test.start_time()),
+                                   unix_time(&test.finish_time())));
+        }
+
+        let certificate = Self::sign_certificate(key, body);
+
+        let file_name = format!("{}-{}-{}.cert", self.id(), scenario_id, finish_time);
+        let path = match self.certificate_directory {
+            None => Path::new(&file_name).to_path_buf(),
+            Some(ref d) => Path::new(d).join(&file_name),
+        };
+        let path_str = path.to_str().unwrap_or("").to_string();
+
+        let mut file = match File::create(&path) {
+            Ok(f) => f,
+            Err(e) => {
+                return Err(CouponError::CertificateWriteError(format!("{}: {:?}", path_str, e)))
+            }
+        };
+        if let Err(e) = file.write_all(certificate.as_bytes()) {
+            return Err(CouponError::CertificateWriteError(format!("{}: {:?}", path_str, e)));
+        }
+        Ok(path_str)
+    }
+
+    /// Append the digest of `body`, and a signature of that digest, to make a certificate.
+    fn sign_certificate(key: &SigningKey, body: String) -> String {
+        let digest = signing::sha256(body.as_bytes());
+        let signature = key.sign(&digest);
+
+        let mut certificate = body;
+        certificate.push_str(&format!("sha256 {}\n", signing::to_hex(&digest)));
+        certificate.push_str(&format!("ed25519 {}\n", signing::to_hex(&signature)));
+        certificate
+    }
+
+    /// Check a certificate written by a coupon against a public key.
+    ///
+    /// This needs nothing other than the certificate and the key file, so it
+    /// can be done offline, long after the jig has been powered off.
+    pub fn verify_certificate(certificate_path: &str,
+                              public_key_path: &str)
+                              -> Result<(), CouponError> {
+        let public_key = match signing::load_public_key(public_key_path) {
+            Ok(k) => k,
+            Err(e) => return Err(CouponError::SigningKeyError(format!("{}", e))),
+        };
+
+        let mut contents = String::new();
+        let mut file = match File::open(certificate_path) {
+            Ok(f) => f,
+            Err(e) => return Err(CouponError::FileLoadError(format!("{:?}", e))),
+        };
+        if let Err(e) = file.read_to_string(&mut contents) {
+            return Err(CouponError::FileLoadError(format!("{:?}", e)));
+        }
+
+        if !contents.starts_with(CERTIFICATE_HEADER) {
+            return Err(CouponError::CertificateInvalid("missing header".to_string()));
+        }
+
+        // Everything up to the "sha256" line is the signed body.
+        let body_end = match contents.find("\nsha256 ") {
+            None => return Err(CouponError::CertificateInvalid("missing digest".to_string())),
+            Some(i) => i + 1,
+        };
+        let (body, trailer) = contents.split_at(body_end);
+
+        let mut digest_hex = None;
+        let mut signature_hex = None;
+        for line in trailer.lines() {
+            let mut words = line.splitn(2, ' ');
+            match (words.next(), words.next()) {
+                (Some("sha256"), Some(v)) => digest_hex = Some(v.trim().to_string()),
+                (Some("ed25519"), Some(v)) => signature_hex = Some(v.trim().to_string()),
+                _ => (),
+            }
+        }
+
+        let digest = signing::sha256(body.as_bytes());
+        match digest_hex {
+            Some(ref d) if *d == signing::to_hex(&digest) => (),
+            _ => return Err(CouponError::CertificateInvalid("digest mismatch".to_string())),
+        }
+
+        let signature = match signature_hex {
+            None => return Err(CouponError::CertificateInvalid("missing signature".to_string())),
+            Some(s) => {
+                match signing::from_hex(&s) {
+                    Ok(s) => s,
+                    Err(e) => return Err(CouponError::CertificateInvalid(format!("{}", e))),
+                }
+            }
+        };
+
+        match signing::verify(&public_key, &digest, &signature) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(CouponError::CertificateInvalid(format!("{}", e))),
+        }
+    }
+}
+
+fn unix_time(t: &Option<time::SystemTime>) -> u64 {
+    match *t {
+        None => 0,
+        Some(t) => {
+            match t.duration_since(time::UNIX_EPOCH) {
+                Ok(d) => d.as_secs(),
+                Err(_) => 0,
+            }
+        }
+    }
+}
+
+impl Unit for Coupon {
+    fn id(&self) -> &str {
+        self.id.as_str()
+    }
+
+    fn kind(&self) -> &str {
+        "coupon"
+    }
+
+    fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    fn description(&self) -> &str {
+        self.description.as_str()
+    }
+
+    fn controller(&self) -> &Controller {
+        &self.controller
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cfti::signing::tests::generate_key;
+    use std::env;
+    use std::fs;
+
+    fn certificate_body() -> String {
+        format!("{}\ncoupon factory\nscenario full\njig v1\nstart 1485942257\n\
+                 finish 1485942260\ntest power pass 1485942257 1485942258\n",
+                CERTIFICATE_HEADER)
+    }
+
+    /// Write `certificate` to a file, verify it, and clean up.
+    fn verify(name: &str, certificate: &str, public_path: &str) -> Result<(), CouponError> {
+        let path = env::temp_dir().join(format!("cfti-test-{}.cert", name));
+        File::create(&path).unwrap().write_all(certificate.as_bytes()).unwrap();
+        let result = Coupon::verify_certificate(path.to_str().unwrap(), public_path);
+        fs::remove_file(&path).unwrap();
+        result
+    }
+
+    #[test]
+    fn certificate_round_trip() {
+        let (key, public_path) = generate_key("coupon-round-trip");
+        let certificate = Coupon::sign_certificate(&key, certificate_body());
+        let result = verify("coupon-round-trip", &certificate, &public_path);
+        fs::remove_file(&public_path).unwrap();
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn certificate_with_changed_body() {
+        let (key, public_path) = generate_key("coupon-changed-body");
+        let certificate = Coupon::sign_certificate(&key, certificate_body())
+            .replace("test power pass", "test power fail");
+        let result = verify("coupon-changed-body", &certificate, &public_path);
+        fs::remove_file(&public_path).unwrap();
+        match result {
+            Err(CouponError::CertificateInvalid(_)) => (),
+            r => panic!("expected CertificateInvalid, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn certificate_with_changed_digest() {
+        // Replacing the digest as well means only the signature catches it.
+        let (key, public_path) = generate_key("coupon-changed-digest");
+        let body = certificate_body().replace("jig v1", "jig v2");
+        let digest = signing::to_hex(&signing::sha256(body.as_bytes()));
+        let certificate = Coupon::sign_certificate(&key, certificate_body());
+        let signature = certificate.lines().last().unwrap().to_string();
+        let forged = format!("{}sha256 {}\n{}\n", body, digest, signature);
+        let result = verify("coupon-changed-digest", &forged, &public_path);
+        fs::remove_file(&public_path).unwrap();
+        match result {
+            Err(CouponError::CertificateInvalid(_)) => (),
+            r => panic!("expected CertificateInvalid, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn certificate_with_wrong_key() {
+        let (key, public_path) = generate_key("coupon-signer");
+        fs::remove_file(&public_path).unwrap();
+        let (_, other_path) = generate_key("coupon-other");
+        let certificate = Coupon::sign_certificate(&key, certificate_body());
+        let result = verify("coupon-wrong-key", &certificate, &other_path);
+        fs::remove_file(&other_path).unwrap();
+        match result {
+            Err(CouponError::CertificateInvalid(_)) => (),
+            r => panic!("expected CertificateInvalid, got {:?}", r),
+        }
+    }
+}
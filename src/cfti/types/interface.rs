@@ -4,21 +4,76 @@ extern crate runny;
 use self::runny::Runny;
 use self::runny::running::Running;
 
-use cfti::types::unit::Unit;
+use cfti::types::unit::{Unit, SimpleUnit};
 use cfti::controller::{self, Controller, BroadcastMessageContents, ControlMessageContents};
 use cfti::process;
 use cfti::unitfile;
 use cfti::config;
+use cfti::locale;
 use cfti::testset;
+use cfti::http;
+use cfti::filter::MessageFilter;
 
-use std::io::Write;
+use std::cmp;
+use std::fs;
+use std::io::{self, Read, Write};
 use std::fmt::{Formatter, Display, Error};
+use std::net::{self, TcpListener, TcpStream};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
 use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, TrySendError};
+use std::thread;
+use std::time;
 
-#[derive(Debug)]
+/// How long an interface has to answer a PING before it is restarted.
+const PONG_TIMEOUT_SECS: u64 = 5;
+
+/// The longest wait before relaunching an interface that keeps going away.
+const MAX_RESTART_DELAY_SECS: u64 = 60;
+
+/// An interface process that stays up this long has its restart count start over.
+const RESTART_RESET_SECS: u64 = 60;
+
+/// How many times in a row an interface is relaunched if there's no MaxRestarts.
+const DEFAULT_MAX_RESTARTS: u32 = 5;
+
+/// How many broadcasts may be waiting on a client that isn't keeping up,
+/// before it's disconnected.
+const CLIENT_QUEUE_SIZE: usize = 1024;
+
+/// Where Format=http interfaces listen if there is no ListenStream.
+const DEFAULT_HTTP_ADDRESS: &'static str = "127.0.0.1:3000";
+
+#[derive(Debug, Clone)]
 enum InterfaceFormat {
     Text,
     JSON,
+    Http,
+}
+
+/// Where a listening interface accepts connections from.
+#[derive(Debug)]
+enum ListenStream {
+    /// An address such as "0.0.0.0:5555".
+    Tcp(String),
+
+    /// The path to a Unix socket.
+    Unix(String),
+}
+
+impl ListenStream {
+    fn from_str(s: &str) -> ListenStream {
+        if s.contains('/') {
+            ListenStream::Unix(s.to_string())
+        } else if s.parse::<u16>().is_ok() {
+            // A bare port only listens locally, since anyone who can connect can
+            // drive the jig.  Use "0.0.0.0:port" to listen on every address.
+            ListenStream::Tcp(format!("127.0.0.1:{}", s))
+        } else {
+            ListenStream::Tcp(s.to_string())
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -27,7 +82,14 @@ pub enum InterfaceError {
     MissingInterfaceSection,
     MissingExecSection,
     ExecCommandFailed,
+    ListenFailed(String),
+    ExecAndListenStream,
+    HttpExecStart,
+    HttpUnixSocket,
     InvalidType(String),
+    ParsePingIntervalError(String),
+    ParseMaxRestartsError(String),
+    ParseLogOrderingError(String),
 }
 
 impl Display for InterfaceError {
@@ -39,7 +101,24 @@ impl Display for InterfaceError {
             }
             &InterfaceError::MissingExecSection => write!(f, "Unit file is missing exec entry"),
             &InterfaceError::ExecCommandFailed => write!(f, "Unable to exec command"),
+            &InterfaceError::ListenFailed(ref s) => write!(f, "Unable to listen: {}", s),
+            &InterfaceError::ExecAndListenStream => {
+                write!(f, "Only one of ExecStart and ListenStream may be used")
+            }
+            &InterfaceError::HttpExecStart => write!(f, "HTTP interfaces can't use ExecStart"),
+            &InterfaceError::HttpUnixSocket => {
+                write!(f, "HTTP interfaces must listen on a TCP address")
+            }
             &InterfaceError::InvalidType(ref s) => write!(f, "Invalid interface type: {}", s),
+            &InterfaceError::ParsePingIntervalError(ref s) => {
+                write!(f, "Invalid ping interval: {}", s)
+            }
+            &InterfaceError::ParseMaxRestartsError(ref s) => {
+                write!(f, "Invalid maximum number of restarts: {}", s)
+            }
+            &InterfaceError::ParseLogOrderingError(ref s) => {
+                write!(f, "Invalid LogOrdering value: {}", s)
+            }
         }
     }
 }
@@ -59,7 +138,10 @@ pub struct Interface {
     format: InterfaceFormat,
 
     /// exec_start: A command to run when starting the interface.
-    exec_start: String,
+    exec_start: Option<String>,
+
+    /// listen_stream: Accept clients on a socket, rather than running a command.
+    listen_stream: Option<ListenStream>,
 
     /// working_directory: The path where the program will be run from.
     working_directory: Option<String>,
@@ -70,8 +152,39 @@ pub struct Interface {
     /// The value set by the "HELLO" command
     hello: String,
 
+    /// The language set by the "LOCALE" command, or the global one if it hasn't been set.
+    locale: Arc<Mutex<Option<String>>>,
+
     /// The currently running process
     process: Arc<Mutex<Option<Running>>>,
+
+    /// The thread accepting clients on listen_stream, if there is one.
+    listener: Arc<Mutex<Option<thread::JoinHandle<()>>>>,
+
+    /// ping_interval: How often to PING the interface, or None to never do so.
+    ping_interval: Option<time::Duration>,
+
+    /// max_restarts: How many times in a row to relaunch the interface before giving up.
+    max_restarts: u32,
+
+    /// How many times in a row the interface has been relaunched.
+    restarts: Arc<Mutex<u32>>,
+
+    /// How long we can let process.terminate() run for
+    termination_timeout: time::Duration,
+
+    /// Bumped every time the interface is started or stopped, so threads
+    /// belonging to an old process know to quit.
+    generation: Arc<Mutex<u32>>,
+
+    /// The most recent value sent with PONG.
+    pong: Arc<Mutex<Option<String>>>,
+
+    /// Which log messages to send to the interface.
+    filter: MessageFilter,
+
+    /// Whether text LOG lines end with the sequence, run id, and monotonic time.
+    log_ordering: bool,
 }
 
 impl Interface {
@@ -113,20 +226,17 @@ impl Interface {
             }
         }
 
-        let description = match unit_file.get("Interface", "Description") {
-            None => None,
-            Some(s) => Some(s.to_string()),
-        };
+        let description =
+            match unit_file.get_localized("Interface", "Description", config.locale()) {
+                None => None,
+                Some(s) => Some(s.to_string()),
+            };
 
-        let name = match unit_file.get("Interface", "Name") {
+        let name = match unit_file.get_localized("Interface", "Name", config.locale()) {
             None => id.to_string(),
             Some(s) => s.to_string(),
         };
 
-        let exec_start = match unit_file.get("Interface", "ExecStart") {
-            None => return Some(Err(InterfaceError::MissingExecSection)),
-            Some(s) => s.to_string(),
-        };
 
         let working_directory = match unit_file.get("Interface", "WorkingDirectory") {
             None => config.default_working_directory().clone(),
@@ -139,21 +249,92 @@ impl Interface {
                 match s.to_string().to_lowercase().as_ref() {
                     "text" => InterfaceFormat::Text,
                     "json" => InterfaceFormat::JSON,
+                    "http" => InterfaceFormat::Http,
                     _ => return Some(Err(InterfaceError::InvalidType(s.to_string()))),
                 }
             }
         };
 
+        let exec_start = unit_file.get("Interface", "ExecStart").map(|s| s.to_string());
+        let listen_stream = unit_file.get("Interface", "ListenStream").map(ListenStream::from_str);
+        let listen_stream = match format {
+            InterfaceFormat::Http => {
+                if exec_start.is_some() {
+                    return Some(Err(InterfaceError::HttpExecStart));
+                }
+                match listen_stream {
+                    None => Some(ListenStream::Tcp(DEFAULT_HTTP_ADDRESS.to_string())),
+                    Some(ListenStream::Unix(_)) => {
+                        return Some(Err(InterfaceError::HttpUnixSocket))
+                    }
+                    Some(l) => Some(l),
+                }
+            }
+            _ => listen_stream,
+        };
+        match (&exec_start, &listen_stream) {
+            (&None, &None) => return Some(Err(InterfaceError::MissingExecSection)),
+            (&Some(_), &Some(_)) => return Some(Err(InterfaceError::ExecAndListenStream)),
+            _ => (),
+        };
+
+        let ping_interval = match unit_file.get("Interface", "PingInterval") {
+            None => None,
+            Some(s) => {
+                match s.parse() {
+                    Err(_) => {
+                        return Some(Err(InterfaceError::ParsePingIntervalError(s.to_string())))
+                    }
+                    Ok(0) => None,
+                    Ok(n) => Some(time::Duration::from_secs(n)),
+                }
+            }
+        };
+
+        let max_restarts = match unit_file.get("Interface", "MaxRestarts") {
+            None => DEFAULT_MAX_RESTARTS,
+            Some(s) => {
+                match s.trim().parse() {
+                    Err(_) => {
+                        return Some(Err(InterfaceError::ParseMaxRestartsError(s.to_string())))
+                    }
+                    Ok(n) => n,
+                }
+            }
+        };
+
+        let log_ordering = match unit_file.get("Interface", "LogOrdering") {
+            None => false,
+            Some(s) => {
+                match s.to_lowercase().as_ref() {
+                    "yes" | "true" | "1" => true,
+                    "no" | "false" | "0" => false,
+                    _ => return Some(Err(InterfaceError::ParseLogOrderingError(s.to_string()))),
+                }
+            }
+        };
+
         Some(Ok(Interface {
             id: id.to_string(),
             name: name,
             description: description,
             exec_start: exec_start,
+            listen_stream: listen_stream,
             working_directory: working_directory,
             format: format,
             controller: test_set.controller().clone(),
             hello: "".to_string(),
+            locale: Arc::new(Mutex::new(config.locale().clone())),
             process: Arc::new(Mutex::new(None)),
+            listener: Arc::new(Mutex::new(None)),
+            ping_interval: ping_interval,
+            max_restarts: max_restarts,
+            restarts: Arc::new(Mutex::new(0)),
+            termination_timeout: config.default_termination_timeout().clone(),
+            generation: Arc::new(Mutex::new(0)),
+            pong: Arc::new(Mutex::new(None)),
+            filter: MessageFilter::from_unit_file(&unit_file, "Interface"),
+            log_ordering: log_ordering,
         }))
     }
 
@@ -161,11 +342,36 @@ impl Interface {
         self.hello = hello;
     }
 
-    fn text_write<T>(stdin: &mut T, msg: controller::BroadcastMessage) -> Result<(), String>
+    pub fn set_locale(&self, locale: String) {
+        *(self.locale.lock().unwrap()) = Some(locale);
+    }
+
+    fn text_write<T>(stdin: &mut T,
+                     msg: controller::BroadcastMessage,
+                     locale: &Option<String>,
+                     log_ordering: bool)
+                     -> Result<(), String>
         where T: Write
     {
         let result = match msg.message {
-            BroadcastMessageContents::Log(l) => {
+            BroadcastMessageContents::Log(ref l) if log_ordering => {
+                writeln!(stdin,
+                         "LOG {}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                         msg.message_class,
+                         msg.unit_id,
+                         msg.unit_type,
+                         msg.unix_time,
+                         msg.unix_time_nsecs,
+                         Self::log_escape(l),
+                         msg.sequence,
+                         match msg.run_id {
+                             Some(ref r) => r.as_str(),
+                             None => "",
+                         },
+                         msg.monotonic_time,
+                         msg.monotonic_time_nsecs)
+            }
+            BroadcastMessageContents::Log(ref l) => {
                 writeln!(stdin,
                          "LOG {}\t{}\t{}\t{}\t{}\t{}",
                          msg.message_class,
@@ -173,15 +379,16 @@ impl Interface {
                          msg.unit_type,
                          msg.unix_time,
                          msg.unix_time_nsecs,
-                         l.to_string()
-                             .replace("\\", "\\\\")
-                             .replace("\t", "\\t")
-                             .replace("\n", "\\n")
-                             .replace("\r", "\\r"))
+                         Self::log_escape(l))
             }
             BroadcastMessageContents::Jig(j) => writeln!(stdin, "JIG {}", j.to_string()),
             BroadcastMessageContents::Describe(class, field, name, value) => {
-                writeln!(stdin, "DESCRIBE {} {} {} {}", class, field, name, value)
+                writeln!(stdin,
+                         "DESCRIBE {} {} {} {}",
+                         class,
+                         field,
+                         name,
+                         value.get(locale))
             }
             BroadcastMessageContents::Scenario(name) => writeln!(stdin, "SCENARIO {}", name),
             BroadcastMessageContents::Scenarios(list) => {
@@ -189,25 +396,79 @@ impl Interface {
             }
             //            BroadcastMessageContents::Hello(name) => writeln!(stdin,
             //                                                "HELLO {}", name),
-            //            BroadcastMessageContents::Ping(val) => writeln!(stdin,
-            //                                                "PING {}", val),
+            BroadcastMessageContents::Ping(val) => writeln!(stdin, "PING {}", val),
             BroadcastMessageContents::Shutdown(reason) => writeln!(stdin, "EXIT {}", reason),
             BroadcastMessageContents::Tests(scenario, tests) => {
                 writeln!(stdin, "TESTS {} {}", scenario, tests.join(" "))
             }
             BroadcastMessageContents::Running(test) => writeln!(stdin, "RUNNING {}", test),
             BroadcastMessageContents::Skip(test, reason) => {
-                writeln!(stdin, "SKIP {} {}", test, reason)
+                writeln!(stdin, "SKIP {} {}", test, locale::translate(&reason, locale))
             }
             BroadcastMessageContents::Fail(test, reason) => {
-                writeln!(stdin, "FAIL {} {}", test, reason)
+                writeln!(stdin, "FAIL {} {}", test, locale::translate(&reason, locale))
             }
             BroadcastMessageContents::Pass(test, reason) => {
-                writeln!(stdin, "PASS {} {}", test, reason)
+                writeln!(stdin, "PASS {} {}", test, locale::translate(&reason, locale))
             }
             BroadcastMessageContents::Start(scenario) => writeln!(stdin, "START {}", scenario),
             BroadcastMessageContents::Finish(scenario, result, reason) => {
-                writeln!(stdin, "FINISH {} {} {}", scenario, result, reason)
+                writeln!(stdin,
+                         "FINISH {} {} {}",
+                         scenario,
+                         result,
+                         locale::translate(&reason, locale))
+            }
+            BroadcastMessageContents::Status(unit, state, reason) => {
+                writeln!(stdin, "STATUS {} {} {}", unit, state, reason)
+            }
+            BroadcastMessageContents::Update(updater, status, message) => {
+                writeln!(stdin, "UPDATE {} {} {}", updater, status, message)
+            }
+            BroadcastMessageContents::Measure(m) => {
+                writeln!(stdin,
+                         "MEASURE {} {} {} {} {} {}",
+                         m.test,
+                         m.name,
+                         m.value,
+                         if m.unit.is_empty() { "-" } else { m.unit.as_str() },
+                         match m.limit {
+                             None => "-".to_string(),
+                             Some(ref l) => l.to_string(),
+                         },
+                         m.result())
+            }
+            BroadcastMessageContents::Progress(test, percent, message) => {
+                writeln!(stdin, "PROGRESS {} {} {}", test, percent, message)
+            }
+            BroadcastMessageContents::Attach(test, path, description) => {
+                writeln!(stdin, "ATTACH {} {} {}", test, path, description)
+            }
+            BroadcastMessageContents::Prompt(id, timeout, choices, text) => {
+                writeln!(stdin,
+                         "PROMPT {} {} {} {}",
+                         id,
+                         timeout,
+                         if choices.is_empty() { "-".to_string() } else { choices.join(",") },
+                         text)
+            }
+            BroadcastMessageContents::Waiting(test, resource, holder) => {
+                writeln!(stdin, "WAITING {} {} {}", test, resource, holder)
+            }
+            BroadcastMessageContents::Retry(test, attempt, attempts, reason) => {
+                writeln!(stdin, "RETRY {} {} {} {}", test, attempt, attempts, reason)
+            }
+            BroadcastMessageContents::Iteration(scenario, iteration, iterations) => {
+                writeln!(stdin, "ITERATION {} {} {}", scenario, iteration, iterations)
+            }
+            BroadcastMessageContents::Summary(scenario, passed, failed, tallies) => {
+                let tallies: Vec<String> = tallies.iter().map(|t| t.to_string()).collect();
+                writeln!(stdin,
+                         "SUMMARY {} {} {} {}",
+                         scenario,
+                         passed,
+                         failed,
+                         tallies.join(" "))
             }
         };
         match result {
@@ -216,7 +477,14 @@ impl Interface {
         }
     }
 
-    fn json_write<T>(stdin: &mut T, msg: controller::BroadcastMessage) -> Result<(), String>
+    fn log_escape(s: &str) -> String {
+        s.replace("\\", "\\\\").replace("\t", "\\t").replace("\n", "\\n").replace("\r", "\\r")
+    }
+
+    pub fn json_write<T>(stdin: &mut T,
+                         msg: controller::BroadcastMessage,
+                         locale: &Option<String>)
+                         -> Result<(), String>
         where T: Write
     {
         let mut object = json::JsonValue::new_object();
@@ -225,6 +493,13 @@ impl Interface {
         object["unit_type"] = msg.unit_type.into();
         object["unix_time"] = msg.unix_time.into();
         object["unix_time_nsecs"] = msg.unix_time_nsecs.into();
+        object["monotonic_time"] = msg.monotonic_time.into();
+        object["monotonic_time_nsecs"] = msg.monotonic_time_nsecs.into();
+        object["sequence"] = msg.sequence.into();
+        object["run_id"] = match msg.run_id {
+            Some(r) => r.into(),
+            None => json::JsonValue::Null,
+        };
         match msg.message {
             BroadcastMessageContents::Log(l) => {
                 object["type"] = "log".into();
@@ -239,7 +514,7 @@ impl Interface {
                 object["class"] = class.into();
                 object["field"] = field.into();
                 object["name"] = name.into();
-                object["value"] = value.into();
+                object["value"] = value.get(locale).into();
             }
             BroadcastMessageContents::Scenario(name) => {
                 object["type"] = "scenario".into();
@@ -257,10 +532,10 @@ impl Interface {
             //                object["type"] = "hello".into();
             //                object["id"] = name.into();
             //            },
-            //            BroadcastMessageContents::Ping(val) => {
-            //                object["type"] = "ping".into();
-            //                object["val"] = val.into();
-            //            },
+            BroadcastMessageContents::Ping(val) => {
+                object["type"] = "ping".into();
+                object["val"] = val.into();
+            }
             BroadcastMessageContents::Shutdown(reason) => {
                 object["type"] = "shutdown".into();
                 object["reason"] = reason.into();
@@ -281,17 +556,17 @@ impl Interface {
             BroadcastMessageContents::Skip(test, reason) => {
                 object["type"] = "skip".into();
                 object["test"] = test.into();
-                object["reason"] = reason.into();
+                object["reason"] = locale::translate(&reason, locale).into();
             }
             BroadcastMessageContents::Fail(test, reason) => {
                 object["type"] = "fail".into();
                 object["test"] = test.into();
-                object["reason"] = reason.into();
+                object["reason"] = locale::translate(&reason, locale).into();
             }
             BroadcastMessageContents::Pass(test, reason) => {
                 object["type"] = "pass".into();
                 object["test"] = test.into();
-                object["reason"] = reason.into();
+                object["reason"] = locale::translate(&reason, locale).into();
             }
             BroadcastMessageContents::Start(scenario) => {
                 object["type"] = "start".into();
@@ -301,8 +576,92 @@ impl Interface {
                 object["type"] = "finish".into();
                 object["scenario"] = scenario.into();
                 object["result"] = result.into();
+                object["reason"] = locale::translate(&reason, locale).into();
+            }
+            BroadcastMessageContents::Status(unit, state, reason) => {
+                object["type"] = "status".into();
+                object["unit"] = unit.into();
+                object["state"] = state.into();
+                object["reason"] = reason.into();
+            }
+            BroadcastMessageContents::Update(updater, status, message) => {
+                object["type"] = "update".into();
+                object["updater"] = updater.into();
+                object["status"] = status.into();
+                object["message"] = message.into();
+            }
+            BroadcastMessageContents::Measure(m) => {
+                object["type"] = "measure".into();
+                object["result"] = m.result().into();
+                match m.limit {
+                    None => {
+                        object["low"] = json::JsonValue::Null;
+                        object["high"] = json::JsonValue::Null;
+                    }
+                    Some(l) => {
+                        object["low"] = l.low.into();
+                        object["high"] = l.high.into();
+                    }
+                }
+                object["test"] = m.test.into();
+                object["name"] = m.name.into();
+                object["value"] = m.value.into();
+                object["unit"] = m.unit.into();
+            }
+            BroadcastMessageContents::Progress(test, percent, message) => {
+                object["type"] = "progress".into();
+                object["test"] = test.into();
+                object["percent"] = percent.into();
+                object["message"] = message.into();
+            }
+            BroadcastMessageContents::Attach(test, path, description) => {
+                object["type"] = "attach".into();
+                object["test"] = test.into();
+                object["path"] = path.into();
+                object["description"] = description.into();
+            }
+            BroadcastMessageContents::Prompt(id, timeout, choices, text) => {
+                object["type"] = "prompt".into();
+                object["id"] = id.into();
+                object["timeout"] = timeout.into();
+                object["choices"] = choices.into();
+                object["text"] = text.into();
+            }
+            BroadcastMessageContents::Waiting(test, resource, holder) => {
+                object["type"] = "waiting".into();
+                object["test"] = test.into();
+                object["resource"] = resource.into();
+                object["holder"] = holder.into();
+            }
+            BroadcastMessageContents::Retry(test, attempt, attempts, reason) => {
+                object["type"] = "retry".into();
+                object["test"] = test.into();
+                object["attempt"] = attempt.into();
+                object["attempts"] = attempts.into();
                 object["reason"] = reason.into();
             }
+            BroadcastMessageContents::Iteration(scenario, iteration, iterations) => {
+                object["type"] = "iteration".into();
+                object["scenario"] = scenario.into();
+                object["iteration"] = iteration.into();
+                object["iterations"] = iterations.into();
+            }
+            BroadcastMessageContents::Summary(scenario, passed, failed, tallies) => {
+                object["type"] = "summary".into();
+                object["scenario"] = scenario.into();
+                object["passed"] = passed.into();
+                object["failed"] = failed.into();
+                let mut tests = json::JsonValue::new_array();
+                for t in tallies {
+                    let mut tally = json::JsonValue::new_object();
+                    tally["test"] = t.test.into();
+                    tally["pass"] = t.pass.into();
+                    tally["fail"] = t.fail.into();
+                    tally["skip"] = t.skip.into();
+                    tests.push(tally).ok();
+                }
+                object["tests"] = tests;
+            }
         };
         match writeln!(stdin, "{}", json::stringify(object)) {
             Ok(_) => Ok(()),
@@ -314,7 +673,10 @@ impl Interface {
         msg.replace("\\t", "\t").replace("\\n", "\n").replace("\\r", "\r").replace("\\\\", "\\")
     }
 
-    fn text_read<T: Unit + ?Sized>(line: String, unit: &T) -> Result<(), ()> {
+    fn text_read<T: Unit + ?Sized>(line: String,
+                                   unit: &T,
+                                   pong: &Arc<Mutex<Option<String>>>)
+                                   -> Result<(), ()> {
         unit.debug(format!("CFTI interface input: {}", line));
         let mut words: Vec<String> =
             line.split_whitespace().map(|x| Self::cfti_unescape(x.to_string())).collect();
@@ -338,16 +700,29 @@ impl Interface {
                 }
             }
             "start" => {
-                if words.is_empty() {
-                    ControlMessageContents::StartScenario(None)
-                } else {
-                    ControlMessageContents::StartScenario(Some(words[0].to_lowercase()))
+                // A scenario of "-" means the current one, so that a count can follow it.
+                let scenario = match words.get(0) {
+                    None => None,
+                    Some(s) if s == "-" => None,
+                    Some(s) => Some(s.to_lowercase()),
+                };
+                match words.get(1).map(|c| c.parse::<u32>()) {
+                    None => ControlMessageContents::StartScenario(scenario, None),
+                    Some(Ok(count)) => ControlMessageContents::StartScenario(scenario, Some(count)),
+                    Some(Err(_)) => {
+                        ControlMessageContents::Log(format!("Invalid START count: {}", words[1]))
+                    }
                 }
             }
             "abort" => ControlMessageContents::AbortTests,
-            "pong" => ControlMessageContents::Pong(words[0].to_lowercase()),
+            "pong" => {
+                let token = words.join(" ");
+                *(pong.lock().unwrap()) = Some(token.clone());
+                ControlMessageContents::Pong(token)
+            }
             "jig" => ControlMessageContents::GetJig,
             "hello" => ControlMessageContents::Hello(words.join(" ")),
+            "locale" => ControlMessageContents::Locale(words.join(" ")),
             "shutdown" => {
                 if words.is_empty() {
                     ControlMessageContents::Shutdown(None)
@@ -356,6 +731,70 @@ impl Interface {
                 }
             }
             "log" => ControlMessageContents::Log(words.join(" ")),
+            "answer" => {
+                if words.is_empty() {
+                    ControlMessageContents::Log(format!("ANSWER is missing a prompt id"))
+                } else {
+                    let id = words.remove(0);
+                    ControlMessageContents::Answer(id, words.join(" "))
+                }
+            }
+            _ => ControlMessageContents::Log(format!("Unimplemented verb: {}", verb)),
+        };
+
+        unit.control(response);
+        Ok(())
+    }
+
+    /// Parse one line of JSON from the interface.
+    ///
+    /// Each line is an object with a "type" field, matching the verbs
+    /// that text_read() understands.  See doc/IPC.md for the full schema.
+    fn json_read<T: Unit + ?Sized>(line: String,
+                                   unit: &T,
+                                   pong: &Arc<Mutex<Option<String>>>)
+                                   -> Result<(), ()> {
+        unit.debug(format!("CFTI interface input: {}", line));
+
+        // Don't crash if we get a blank line.
+        if line.trim().is_empty() {
+            return Ok(());
+        }
+
+        let object = match json::parse(line.as_str()) {
+            Err(e) => {
+                unit.control(ControlMessageContents::Log(format!("Invalid JSON: {:?}", e)));
+                return Ok(());
+            }
+            Ok(o) => o,
+        };
+
+        let optional = |key: &str| object[key].as_str().map(|x| x.to_lowercase());
+        let text = |key: &str| object[key].as_str().unwrap_or("").to_string();
+
+        let verb = object["type"].as_str().unwrap_or("").to_lowercase();
+        let response = match verb.as_str() {
+            "scenario" => ControlMessageContents::Scenario(text("id").to_lowercase()),
+            "scenarios" => ControlMessageContents::GetScenarios,
+            "tests" => ControlMessageContents::GetTests(optional("scenario")),
+            "start" => {
+                let count = object["count"].as_u32();
+                ControlMessageContents::StartScenario(optional("scenario"), count)
+            }
+            "abort" => ControlMessageContents::AbortTests,
+            "pong" => {
+                let token = text("val");
+                *(pong.lock().unwrap()) = Some(token.clone());
+                ControlMessageContents::Pong(token)
+            }
+            "jig" => ControlMessageContents::GetJig,
+            "hello" => ControlMessageContents::Hello(text("id")),
+            "locale" => ControlMessageContents::Locale(text("locale")),
+            "shutdown" => {
+                ControlMessageContents::Shutdown(object["reason"].as_str().map(|x| x.to_string()))
+            }
+            "log" => ControlMessageContents::Log(text("message")),
+            "answer" => ControlMessageContents::Answer(text("id"), text("answer")),
             _ => ControlMessageContents::Log(format!("Unimplemented verb: {}", verb)),
         };
 
@@ -365,6 +804,38 @@ impl Interface {
 
     pub fn start(&self, working_directory: &Option<String>) -> Result<(), InterfaceError> {
 
+        // Anything left over from a previous process is now stale.
+        let generation = {
+            let mut current = self.generation.lock().unwrap();
+            *current = *current + 1;
+            *current
+        };
+        *(self.pong.lock().unwrap()) = None;
+
+        let connection = Connection {
+            unit: self.to_simple_unit(),
+            format: self.format.clone(),
+            locale: self.locale.clone(),
+            log_ordering: self.log_ordering,
+            pong: self.pong.clone(),
+            generation: self.generation.clone(),
+            current_generation: generation,
+            filter: self.filter.clone(),
+        };
+
+        let exec_start = match self.exec_start {
+            Some(ref s) => s,
+            None => {
+                return match self.listen_stream {
+                    Some(ListenStream::Tcp(ref addr)) if self.is_http() => {
+                        self.serve_http(addr, connection)
+                    }
+                    Some(ref l) => self.listen_on(l, connection),
+                    None => Err(InterfaceError::MissingExecSection),
+                }
+            }
+        };
+
         let working_directory = match self.working_directory {
             Some(ref s) => Some(s.clone()),
             None => {
@@ -375,40 +846,442 @@ impl Interface {
             }
         };
 
+        let started = time::Instant::now();
         let mut running =
-            match Runny::new(self.exec_start.as_str()).directory(&working_directory).start() {
+            match Runny::new(exec_start.as_str()).directory(&working_directory).start() {
                 Ok(p) => p,
                 Err(e) => {
                     self.debug(format!("Unable to run interface command {}: {:?}",
-                                       self.exec_start,
+                                       exec_start,
                                        e));
                     return Err(InterfaceError::ExecCommandFailed);
                 }
             };
 
         self.debug(format!("Launched interface"));
-        let mut stdin = running.take_input();
+        let stdin = running.take_input();
         let stdout = running.take_output();
         let stderr = running.take_error();
 
+        process::log_output(stderr, self, "stderr").unwrap();
+
+        // A program that stops reading its broadcasts is as good as hung.
+        let unit = self.to_simple_unit();
+        let disconnect = move || {
+            let reason = "stopped reading broadcasts".to_string();
+            unit.control(ControlMessageContents::RestartInterface(reason));
+        };
+        if let Err(e) = connection.attach(stdout, stdin, disconnect) {
+            self.debug(format!("Unable to talk to interface: {:?}", e));
+            return Err(InterfaceError::ExecCommandFailed);
+        }
+
+        if let Some(ping_interval) = self.ping_interval {
+            self.watchdog(generation, ping_interval, started);
+        }
+
+        // If the process goes away on its own, relaunch it.
+        let waiter = running.waiter();
+        let thr_generation = self.generation.clone();
+        let unit = self.to_simple_unit();
+        let restarts = self.restarts.clone();
+        let max_restarts = self.max_restarts;
+        thread::spawn(move || {
+            let result = waiter.result();
+            if *(thr_generation.lock().unwrap()) == generation {
+                let reason = format!("exited with code {}", result);
+                Self::request_restart(&unit,
+                                      &restarts,
+                                      max_restarts,
+                                      started,
+                                      reason,
+                                      &thr_generation,
+                                      generation);
+            }
+        });
+
+        *(self.process.lock().unwrap()) = Some(running);
+        Ok(())
+    }
+
+    fn is_http(&self) -> bool {
         match self.format {
-            InterfaceFormat::Text => {
+            InterfaceFormat::Http => true,
+            _ => false,
+        }
+    }
 
-                // Send some initial information to the client.
-                writeln!(stdin, "HELLO Jig/20 1.0").unwrap();
+    /// Answer HTTP requests and WebSocket clients using the built-in server.
+    /// There is no process to PING, so there is no watchdog.
+    fn serve_http(&self, addr: &str, connection: Connection) -> Result<(), InterfaceError> {
+        let result = http::start(addr,
+                                 self.to_simple_unit(),
+                                 self.locale.clone(),
+                                 self.filter.clone(),
+                                 move || connection.is_current());
+        match result {
+            Err(e) => Err(InterfaceError::ListenFailed(format!("{:?}", e))),
+            Ok(_) => Ok(()),
+        }
+    }
 
-                // Send all broadcasts to the stdin of the child process.
-                self.controller.listen(move |msg| Interface::text_write(&mut stdin, msg));
-                process::log_output(stderr, self, "stderr").unwrap();
-                process::watch_output(stdout, self, move |line, u| Interface::text_read(line, u))
-                    .unwrap();
+    /// Accept clients on a socket, each of which gets its own copy of
+    /// the protocol.
+    fn listen_on(&self,
+                 listen_stream: &ListenStream,
+                 connection: Connection)
+                 -> Result<(), InterfaceError> {
+        let builder = thread::Builder::new().name(format!("L-{}", self.id()).into());
+        let result = match *listen_stream {
+            ListenStream::Tcp(ref addr) => {
+                let listener = match TcpListener::bind(addr.as_str()) {
+                    Err(e) => return Err(InterfaceError::ListenFailed(format!("{}: {}", addr, e))),
+                    Ok(l) => l,
+                };
+                builder.spawn(move || {
+                    Self::accept_clients(listener.incoming(),
+                                         TcpStream::try_clone,
+                                         Self::shutdown_tcp,
+                                         &connection)
+                })
             }
-            InterfaceFormat::JSON => {
-                self.controller.listen(move |msg| Interface::json_write(&mut stdin, msg));
+            ListenStream::Unix(ref path) => {
+                // Clean up a socket left behind by a previous run, but don't
+                // clobber anything that isn't a socket.
+                if let Ok(metadata) = fs::metadata(path) {
+                    if metadata.file_type().is_socket() {
+                        fs::remove_file(path).ok();
+                    }
+                }
+                let listener = match UnixListener::bind(path) {
+                    Err(e) => return Err(InterfaceError::ListenFailed(format!("{}: {}", path, e))),
+                    Ok(l) => l,
+                };
+                builder.spawn(move || {
+                    Self::accept_clients(listener.incoming(),
+                                         UnixStream::try_clone,
+                                         Self::shutdown_unix,
+                                         &connection)
+                })
             }
         };
 
-        *(self.process.lock().unwrap()) = Some(running);
+        match result {
+            Err(e) => Err(InterfaceError::ListenFailed(format!("{:?}", e))),
+            Ok(handle) => {
+                *(self.listener.lock().unwrap()) = Some(handle);
+                self.debug(format!("Listening on {:?}", listen_stream));
+                Ok(())
+            }
+        }
+    }
+
+    /// Wake up the thread accepting clients, so that it sees it's no longer
+    /// current, and wait for it to close its socket so the address is free again.
+    fn close_listener(&self) {
+        let handle = match self.listener.lock().unwrap().take() {
+            None => return,
+            Some(h) => h,
+        };
+        let woken = match self.listen_stream {
+            Some(ListenStream::Tcp(ref addr)) => TcpStream::connect(addr.as_str()).is_ok(),
+            Some(ListenStream::Unix(ref path)) => UnixStream::connect(path).is_ok(),
+            None => false,
+        };
+        if woken {
+            handle.join().ok();
+        }
+    }
+
+    fn shutdown_tcp(stream: &TcpStream) {
+        stream.shutdown(net::Shutdown::Both).ok();
+    }
+
+    fn shutdown_unix(stream: &UnixStream) {
+        stream.shutdown(net::Shutdown::Both).ok();
+    }
+
+    fn accept_clients<S, I>(incoming: I,
+                            try_clone: fn(&S) -> io::Result<S>,
+                            shutdown: fn(&S),
+                            connection: &Connection)
+        where S: Read + Write + Send + 'static,
+              I: Iterator<Item = io::Result<S>>
+    {
+        for stream in incoming {
+            if !connection.is_current() {
+                return;
+            }
+
+            let stream = match stream {
+                Err(e) => {
+                    connection.unit.debug(format!("Unable to accept client: {:?}", e));
+                    continue;
+                }
+                Ok(s) => s,
+            };
+
+            let (reader, handle) = match (try_clone(&stream), try_clone(&stream)) {
+                (Ok(r), Ok(h)) => (r, h),
+                (Err(e), _) |
+                (_, Err(e)) => {
+                    connection.unit.debug(format!("Unable to set up client: {:?}", e));
+                    continue;
+                }
+            };
+
+            connection.unit.debug(format!("Accepted new client"));
+            if let Err(e) = connection.attach(reader, stream, move || shutdown(&handle)) {
+                connection.unit.debug(format!("Unable to talk to client: {:?}", e));
+            }
+        }
+    }
+
+    /// Ask for the interface to be relaunched, waiting twice as long each time it goes
+    /// away in a row, up to a minute.  After `max_restarts` in a row, give up instead.
+    /// A process that was up for a while gets a fresh count.
+    fn request_restart(unit: &SimpleUnit,
+                       restarts: &Arc<Mutex<u32>>,
+                       max_restarts: u32,
+                       started: time::Instant,
+                       reason: String,
+                       generation: &Arc<Mutex<u32>>,
+                       current_generation: u32) {
+        let count = {
+            let mut restarts = restarts.lock().unwrap();
+            if started.elapsed() >= time::Duration::from_secs(RESTART_RESET_SECS) {
+                *restarts = 0;
+            }
+            *restarts = *restarts + 1;
+            *restarts
+        };
+
+        if count > max_restarts {
+            unit.broadcast(BroadcastMessageContents::Status(unit.id().to_string(),
+                                                            "failed".to_string(),
+                                                            format!("{}, and was already \
+                                                                     relaunched {} times",
+                                                                    reason,
+                                                                    max_restarts)));
+            return;
+        }
+
+        let delay = cmp::min(1 << cmp::min(count - 1, 6), MAX_RESTART_DELAY_SECS);
+        thread::sleep(time::Duration::from_secs(delay));
+        if *(generation.lock().unwrap()) != current_generation {
+            return;
+        }
+        unit.control(ControlMessageContents::RestartInterface(reason));
+    }
+
+    /// Periodically PING the interface, and ask for it to be restarted if it
+    /// doesn't answer in time.
+    fn watchdog(&self, generation: u32, ping_interval: time::Duration, started: time::Instant) {
+        let unit = self.to_simple_unit();
+        let thr_generation = self.generation.clone();
+        let pong = self.pong.clone();
+        let restarts = self.restarts.clone();
+        let max_restarts = self.max_restarts;
+
+        let builder = thread::Builder::new().name(format!("P-{}", self.id()).into());
+        builder.spawn(move || {
+                let mut counter = 0;
+                loop {
+                    thread::sleep(ping_interval);
+                    if *(thr_generation.lock().unwrap()) != generation {
+                        return;
+                    }
+
+                    counter = counter + 1;
+                    let token = format!("{}-{}", generation, counter);
+                    unit.broadcast(BroadcastMessageContents::Ping(token.clone()));
+
+                    let deadline = time::Instant::now() +
+                                   time::Duration::from_secs(PONG_TIMEOUT_SECS);
+                    loop {
+                        if *(thr_generation.lock().unwrap()) != generation {
+                            return;
+                        }
+                        if *(pong.lock().unwrap()) == Some(token.clone()) {
+                            break;
+                        }
+                        if time::Instant::now() >= deadline {
+                            Self::request_restart(&unit,
+                                                  &restarts,
+                                                  max_restarts,
+                                                  started,
+                                                  format!("no PONG within {} seconds",
+                                                          PONG_TIMEOUT_SECS),
+                                                  &thr_generation,
+                                                  generation);
+                            return;
+                        }
+                        thread::sleep(time::Duration::from_millis(100));
+                    }
+                }
+            })
+            .unwrap();
+    }
+
+    /// Stop watching the interface, so that it won't be restarted, and stop
+    /// accepting clients.  The process itself is left alone.
+    pub fn retire(&self) {
+        {
+            let mut current = self.generation.lock().unwrap();
+            *current = *current + 1;
+        }
+        self.close_listener();
+    }
+
+    /// Kill the interface process.
+    pub fn stop(&self) {
+        self.retire();
+        if let Some(mut p) = self.process.lock().unwrap().take() {
+            if let Err(e) = p.terminate(Some(self.termination_timeout)) {
+                self.debug(format!("Unable to terminate interface: {:?}", e));
+            }
+        }
+    }
+
+    /// Kill the interface process, and launch a new one.
+    pub fn restart(&self, reason: String, working_directory: &Option<String>) {
+        self.stop();
+        self.broadcast(BroadcastMessageContents::Status(self.id().to_string(),
+                                                        "restarting".to_string(),
+                                                        reason));
+        match self.start(working_directory) {
+            Err(e) => {
+                self.broadcast(BroadcastMessageContents::Status(self.id().to_string(),
+                                                                "failed".to_string(),
+                                                                format!("{}", e)))
+            }
+            Ok(_) => {
+                self.broadcast(BroadcastMessageContents::Status(self.id().to_string(),
+                                                                "running".to_string(),
+                                                                "".to_string()))
+            }
+        }
+    }
+}
+
+/// Everything needed to hook a stream up to the controller, so that it
+/// can be done from a listener thread as well as from Interface::start().
+#[derive(Clone)]
+struct Connection {
+    unit: SimpleUnit,
+    format: InterfaceFormat,
+    locale: Arc<Mutex<Option<String>>>,
+    log_ordering: bool,
+    pong: Arc<Mutex<Option<String>>>,
+    generation: Arc<Mutex<u32>>,
+    current_generation: u32,
+    filter: MessageFilter,
+}
+
+impl Connection {
+    fn is_current(&self) -> bool {
+        *(self.generation.lock().unwrap()) == self.current_generation
+    }
+
+    /// Send the HELLO banner and every broadcast to `writer`, and pass
+    /// commands read from `reader` on to the controller.
+    ///
+    /// Broadcasts are queued up for a thread of their own to write, so that a
+    /// client that stops reading can't hold up the bus.  If it falls too far
+    /// behind, `disconnect` is called, and it gets no more broadcasts.
+    fn attach<R, W, D>(&self, reader: R, mut writer: W, disconnect: D) -> io::Result<()>
+        where R: Read + Send + 'static,
+              W: Write + Send + 'static,
+              D: Fn() + Send + 'static
+    {
+        // Send some initial information to the client.
+        match self.format {
+            InterfaceFormat::Text => try!(writeln!(writer, "HELLO Jig/20 1.0")),
+            InterfaceFormat::JSON |
+            InterfaceFormat::Http => {
+                let mut hello = json::JsonValue::new_object();
+                hello["type"] = "hello".into();
+                hello["version"] = "Jig/20 1.0".into();
+                try!(writeln!(writer, "{}", json::stringify(hello)));
+            }
+        };
+
+        // Once the client goes away, stop sending it broadcasts.
+        let closed = Arc::new(Mutex::new(false));
+
+        let (queue, queued) = mpsc::sync_channel::<Vec<u8>>(CLIENT_QUEUE_SIZE);
+        let thr_closed = closed.clone();
+        let builder = thread::Builder::new().name(format!("W-{}", self.unit.id()).into());
+        try!(builder.spawn(move || {
+            for record in queued {
+                if writer.write_all(&record).and_then(|_| writer.flush()).is_err() {
+                    break;
+                }
+            }
+            *(thr_closed.lock().unwrap()) = true;
+        }));
+
+        // Send all broadcasts to the client.
+        let id = self.unit.id().to_string();
+        let format = self.format.clone();
+        let locale = self.locale.clone();
+        let connection = self.clone();
+        let thr_closed = closed.clone();
+        self.unit.controller().listen(move |msg| {
+            if *(thr_closed.lock().unwrap()) || !connection.is_current() {
+                return Err("interface went away".to_string());
+            }
+            match msg.message {
+                // Each interface only gets its own PINGs.
+                BroadcastMessageContents::Ping(_) => {
+                    if msg.unit_id != id {
+                        return Ok(());
+                    }
+                }
+                // The rest of the protocol is always sent, but logs can be filtered.
+                BroadcastMessageContents::Log(_) => {
+                    if !connection.filter.matches(&msg) {
+                        return Ok(());
+                    }
+                }
+                _ => (),
+            }
+            let mut record = vec![];
+            try!(match format {
+                InterfaceFormat::Text => {
+                    Interface::text_write(&mut record,
+                                          msg,
+                                          &*locale.lock().unwrap(),
+                                          connection.log_ordering)
+                }
+                InterfaceFormat::JSON |
+                InterfaceFormat::Http => {
+                    Interface::json_write(&mut record, msg, &*locale.lock().unwrap())
+                }
+            });
+            match queue.try_send(record) {
+                Ok(_) => Ok(()),
+                Err(TrySendError::Full(_)) => {
+                    disconnect();
+                    Err("client stopped reading broadcasts, so it was disconnected".to_string())
+                }
+                Err(TrySendError::Disconnected(_)) => Err("interface went away".to_string()),
+            }
+        });
+
+        let format = self.format.clone();
+        let pong = self.pong.clone();
+        let reader_thread = try!(process::watch_output(reader, &self.unit, move |line, u| {
+            match format {
+                InterfaceFormat::Text => Interface::text_read(line, u, &pong),
+                InterfaceFormat::JSON |
+                InterfaceFormat::Http => Interface::json_read(line, u, &pong),
+            }
+        }));
+        thread::spawn(move || {
+            reader_thread.join().ok();
+            *(closed.lock().unwrap()) = true;
+        });
         Ok(())
     }
 }
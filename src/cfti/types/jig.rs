@@ -4,6 +4,7 @@ use cfti::process;
 use cfti::config;
 use cfti::controller::{Controller, BroadcastMessageContents};
 use cfti::unitfile::UnitFile;
+use cfti::locale::LocalizedString;
 use cfti::types::unit::Unit;
 use cfti::testset;
 
@@ -19,10 +20,10 @@ pub struct Jig {
     id: String,
 
     /// Name: Defines the short name for this jig.
-    name: String,
+    name: LocalizedString,
 
     /// Description: Defines a detailed description of this jig.  May be up to one paragraph.
-    description: String,
+    description: LocalizedString,
 
     /// DefaultScenario: Name of the scenario to run by default.
     default_scenario: Option<String>,
@@ -66,20 +67,20 @@ impl Jig {
         };
 
         if let Some(s) = unitfile.get("Jig", "TestProgram") {
-            if !process::try_command(test_set, s, &working_directory, config.timeout()) {
+            if !process::try_command(test_set, s, &working_directory, &[], config.timeout()) {
                 test_set.debug(format!("{}: Test program FAILED", id));
                 return None;
             }
         };
 
-        let description = match unitfile.get("Jig", "Description") {
-            None => "".to_string(),
-            Some(s) => s.to_string(),
+        let description = match unitfile.get_localized_string("Jig", "Description") {
+            None => LocalizedString::new(""),
+            Some(s) => s,
         };
 
-        let name = match unitfile.get("Jig", "Name") {
-            None => id.to_string(),
-            Some(s) => s.to_string(),
+        let name = match unitfile.get_localized_string("Jig", "Name") {
+            None => LocalizedString::new(id),
+            Some(s) => s,
         };
 
         let default_scenario = match unitfile.get("Jig", "DefaultScenario") {
@@ -109,14 +110,14 @@ impl Jig {
                                   &BroadcastMessageContents::Describe(self.kind().to_string(),
                                                                       "name".to_string(),
                                                                       self.id().to_string(),
-                                                                      self.name().to_string()));
+                                                                      self.name.clone()));
         self.controller.broadcast(self.id(),
                                   self.kind(),
                                   &BroadcastMessageContents::Describe(self.kind().to_string(),
                                                                       "description".to_string(),
                                                                       self.id().to_string(),
-                                                                      self.description()
-                                                                          .to_string()));
+                                                                      self.description
+                                                                          .clone()));
     }
 
     pub fn default_scenario(&self) -> &Option<String> {
@@ -134,11 +135,11 @@ impl Unit for Jig {
     }
 
     fn name(&self) -> &str {
-        self.name.as_str()
+        self.name.default()
     }
 
     fn description(&self) -> &str {
-        self.description.as_str()
+        self.description.default()
     }
 
     fn id(&self) -> &str {
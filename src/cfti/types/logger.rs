@@ -1,20 +1,33 @@
 extern crate json;
 extern crate runny;
 
-use cfti::types::Jig;
-use cfti::types::unit::Unit;
+use cfti::types::{Jig, RestartPolicy, restart_delay};
+use cfti::types::unit::{Unit, SimpleUnit};
 use cfti::controller::{Controller, ControlMessageContents, BroadcastMessage,
                        BroadcastMessageContents};
 use cfti::process;
 use cfti::config;
+use cfti::filter::MessageFilter;
+use cfti::logfile::{LogFile, LogFileSettings};
+use cfti::logqueue::LogQueue;
 use cfti::unitfile::UnitFile;
 
 use self::runny::running::Running;
 
 use std::collections::HashMap;
 use std::io::Write;
+use std::path::Path;
 use std::sync::{Arc, Mutex};
 use std::fmt::{Formatter, Display, Error};
+use std::thread;
+use std::time;
+
+/// Every kind of message a logger may ask for with Messages=.
+const MESSAGE_TYPES: [&'static str; 24] = ["log", "describe", "scenario", "scenarios", "jig",
+                                           "shutdown", "ping", "tests", "start", "finish",
+                                           "skip", "running", "pass", "fail", "status", "update",
+                                           "measure", "progress", "attach", "prompt", "waiting",
+                                           "retry", "iteration", "summary"];
 
 #[derive(Debug, Clone)]
 enum LoggerFormat {
@@ -22,6 +35,105 @@ enum LoggerFormat {
     JSON,
 }
 
+#[derive(Debug)]
+enum LoggerType {
+    /// Send records to the stdin of a program.
+    Program(String),
+
+    /// Write records to a file.
+    File(LogFileSettings),
+}
+
+/// The stdin of a program logger, along with the records it missed while it was down.
+struct LoggerPipe {
+    stdin: Option<Box<Write + Send>>,
+    queue: LogQueue,
+
+    /// Set after an error has been reported, so that the report itself
+    /// doesn't cause another one.
+    failing: bool,
+
+    /// Set once the program has exited and won't be restarted.
+    finished: bool,
+}
+
+impl LoggerPipe {
+    /// Send `record` to the logger, or queue it if the logger is down.
+    fn send(&mut self, record: String) -> Result<(), String> {
+        // Only write directly if nothing is waiting, so records stay in order.
+        if self.queue.is_empty() {
+            let result = match self.stdin {
+                None => None,
+                Some(ref mut stdin) => Some(writeln!(stdin, "{}", record)),
+            };
+            match result {
+                None => (),
+                Some(Ok(_)) => return Ok(()),
+                Some(Err(e)) => {
+                    self.stdin = None;
+                    if let Err(e) = self.queue.push(record) {
+                        return Err(format!("Unable to queue record: {:?}", e));
+                    }
+                    return Err(format!("Unable to write to logger: {:?}", e));
+                }
+            }
+        }
+
+        if let Err(e) = self.queue.push(record) {
+            return Err(format!("Unable to queue record: {:?}", e));
+        }
+        self.replay().map(|_| ())
+    }
+
+    /// Send every queued record to the logger, if it's running, and return
+    /// how many were sent.
+    fn replay(&mut self) -> Result<usize, String> {
+        let result = match self.stdin {
+            None => return Ok(0),
+            Some(ref mut stdin) => self.queue.drain_into(stdin),
+        };
+        match result {
+            Ok(count) => Ok(count),
+            Err(e) => {
+                self.stdin = None;
+                Err(format!("Unable to write to logger: {:?}", e))
+            }
+        }
+    }
+}
+
+/// Decides which messages a logger gets, and how each one is written.
+#[derive(Debug, Clone)]
+struct RecordFormat {
+    format: LoggerFormat,
+    messages: Option<Vec<String>>,
+    filter: MessageFilter,
+}
+
+impl RecordFormat {
+    /// Format `msg` as a single line, or return None if the logger doesn't want it.
+    fn record(&self, msg: &BroadcastMessage) -> Option<String> {
+        // Loggers that only want logs get the original layout, so that
+        // existing loggers keep working.
+        let logs_only = match self.messages {
+            Some(ref m) => {
+                if !m.iter().any(|verb| verb == msg.message.verb()) {
+                    return None;
+                }
+                m.iter().all(|verb| verb == "log")
+            }
+            None => false,
+        };
+        if !self.filter.matches(msg) {
+            return None;
+        }
+        Some(match self.format {
+            LoggerFormat::TabSeparatedValue => Logger::tsv_record(msg, logs_only),
+            LoggerFormat::JSON => json::stringify(Logger::json_record(msg)),
+        })
+    }
+}
+
 #[derive(Debug)]
 pub enum LoggerError {
     FileLoadError(String),
@@ -29,6 +141,18 @@ pub enum LoggerError {
     MissingExecSection,
     ExecCommandFailed,
     InvalidType(String),
+    InvalidMessageType(String),
+    InvalidLoggerType(String),
+    MissingPath,
+    ParseMaxSizeError(String),
+    ParseMaxAgeError(String),
+    ParseMaxFilesError(String),
+    ParsePerRunError(String),
+    LogFileError(String),
+    InvalidRestart(String),
+    ParseRestartSecError(String),
+    ParseBufferSizeError(String),
+    BufferFileError(String),
 }
 
 impl Display for LoggerError {
@@ -39,6 +163,18 @@ impl Display for LoggerError {
             &LoggerError::MissingExecSection => write!(f, "Unit file is missing exec section"),
             &LoggerError::ExecCommandFailed => write!(f, "Unable to exec command"),
             &LoggerError::InvalidType(ref s) => write!(f, "Invalid logger type: {}", s),
+            &LoggerError::InvalidMessageType(ref s) => write!(f, "Invalid message type: {}", s),
+            &LoggerError::InvalidLoggerType(ref s) => write!(f, "Invalid Type: {}", s),
+            &LoggerError::MissingPath => write!(f, "File loggers need a Path"),
+            &LoggerError::ParseMaxSizeError(ref s) => write!(f, "Invalid MaxSize: {}", s),
+            &LoggerError::ParseMaxAgeError(ref s) => write!(f, "Invalid MaxAge: {}", s),
+            &LoggerError::ParseMaxFilesError(ref s) => write!(f, "Invalid MaxFiles: {}", s),
+            &LoggerError::ParsePerRunError(ref s) => write!(f, "Invalid PerRun: {}", s),
+            &LoggerError::LogFileError(ref s) => write!(f, "Unable to open log file: {}", s),
+            &LoggerError::InvalidRestart(ref s) => write!(f, "Invalid Restart: {}", s),
+            &LoggerError::ParseRestartSecError(ref s) => write!(f, "Invalid RestartSec: {}", s),
+            &LoggerError::ParseBufferSizeError(ref s) => write!(f, "Invalid BufferSize: {}", s),
+            &LoggerError::BufferFileError(ref s) => write!(f, "Unable to open buffer: {}", s),
         }
     }
 }
@@ -57,15 +193,34 @@ pub struct Logger {
     /// format: The format requested by this logger.
     format: LoggerFormat,
 
-    /// exec_start: A command to run when starting tests.
-    exec_start: String,
+    /// messages: The kinds of messages to send to the logger, or None for all of them.
+    messages: Option<Vec<String>>,
 
-    /// working_directory: The path where exec_start will be run from.
+    /// filter: Which classes and units to send to the logger.
+    filter: MessageFilter,
+
+    /// kind: Where records are sent to, either a program or a file.
+    kind: LoggerType,
+
+    /// working_directory: The path where the program will be run from, and
+    /// where relative file paths start from.
     working_directory: Option<String>,
 
     /// The master controller, where bus messages come and go.
     controller: Controller,
 
+    /// restart: What to do when the logger program exits.
+    restart: RestartPolicy,
+
+    /// restart_sec: How long to wait before restarting the logger program.
+    restart_sec: time::Duration,
+
+    /// buffer_size: How many records to hold onto while the logger program is down.
+    buffer_size: usize,
+
+    /// buffer_path: A file to hold those records in, rather than memory.
+    buffer_path: Option<String>,
+
     /// The actual, running process
     process: Arc<Mutex<Option<Running>>>,
 }
@@ -116,12 +271,12 @@ impl Logger {
             }
         }
 
-        let description = match unitfile.get("Logger", "Description") {
+        let description = match unitfile.get_localized("Logger", "Description", config.locale()) {
             None => None,
             Some(s) => Some(s.to_string()),
         };
 
-        let name = match unitfile.get("Logger", "Name") {
+        let name = match unitfile.get_localized("Logger", "Name", config.locale()) {
             None => id.to_string(),
             Some(s) => s.to_string(),
         };
@@ -131,11 +286,6 @@ impl Logger {
             Some(s) => Some(s.to_string()),
         };
 
-        let exec_start = match unitfile.get("Logger", "ExecStart") {
-            None => return Some(Err(LoggerError::MissingExecSection)),
-            Some(s) => s.to_string(),
-        };
-
         let format = match unitfile.get("Logger", "Format") {
             None => LoggerFormat::TabSeparatedValue,
             Some(s) => {
@@ -147,14 +297,96 @@ impl Logger {
             }
         };
 
+        let kind = match unitfile.get("Logger", "Type").map(|s| s.to_lowercase()) {
+            None => {
+                match unitfile.get("Logger", "ExecStart") {
+                    None => return Some(Err(LoggerError::MissingExecSection)),
+                    Some(s) => LoggerType::Program(s.to_string()),
+                }
+            }
+            Some(ref s) if s == "program" => {
+                match unitfile.get("Logger", "ExecStart") {
+                    None => return Some(Err(LoggerError::MissingExecSection)),
+                    Some(s) => LoggerType::Program(s.to_string()),
+                }
+            }
+            Some(ref s) if s == "file" => {
+                match Self::load_file_settings(&unitfile, &format) {
+                    Err(e) => return Some(Err(e)),
+                    Ok(settings) => LoggerType::File(settings),
+                }
+            }
+            Some(s) => return Some(Err(LoggerError::InvalidLoggerType(s))),
+        };
+
+        let messages = match unitfile.get("Logger", "Messages") {
+            None => Some(vec!["log".to_string()]),
+            Some(s) => {
+                let mut messages = vec![];
+                let mut all = false;
+                for verb in s.split(|c| c == ',' || c == ' ') {
+                    let verb = verb.trim().to_lowercase();
+                    if verb.is_empty() {
+                        continue;
+                    }
+                    if verb == "all" {
+                        all = true;
+                    } else if MESSAGE_TYPES.contains(&verb.as_str()) {
+                        messages.push(verb);
+                    } else {
+                        return Some(Err(LoggerError::InvalidMessageType(verb)));
+                    }
+                }
+                if all { None } else { Some(messages) }
+            }
+        };
+
+        let restart = match unitfile.get("Logger", "Restart") {
+            None => RestartPolicy::No,
+            Some(s) => {
+                match RestartPolicy::from_str(s) {
+                    Some(r) => r,
+                    None => return Some(Err(LoggerError::InvalidRestart(s.to_string()))),
+                }
+            }
+        };
+
+        let restart_sec = match unitfile.get("Logger", "RestartSec") {
+            None => time::Duration::from_secs(1),
+            Some(s) => {
+                match s.parse() {
+                    Err(_) => return Some(Err(LoggerError::ParseRestartSecError(s.to_string()))),
+                    Ok(n) => time::Duration::from_secs(n),
+                }
+            }
+        };
+
+        let buffer_size = match unitfile.get("Logger", "BufferSize") {
+            None => 1000,
+            Some(s) => {
+                match s.parse() {
+                    Err(_) => return Some(Err(LoggerError::ParseBufferSizeError(s.to_string()))),
+                    Ok(n) => n,
+                }
+            }
+        };
+
+        let buffer_path = unitfile.get("Logger", "BufferPath").map(|s| s.to_string());
+
         Some(Ok(Logger {
             id: id.to_string(),
             name: name,
             description: description,
-            exec_start: exec_start,
+            kind: kind,
             working_directory: working_directory,
             format: format,
+            messages: messages,
+            filter: MessageFilter::from_unit_file(&unitfile, "Logger"),
             controller: controller.clone(),
+            restart: restart,
+            restart_sec: restart_sec,
+            buffer_size: buffer_size,
+            buffer_path: buffer_path,
             process: Arc::new(Mutex::new(None)),
         }))
     }
@@ -171,68 +403,538 @@ impl Logger {
             }
         };
 
+        let record_format = RecordFormat {
+            format: self.format.clone(),
+            messages: self.messages.clone(),
+            filter: self.filter.clone(),
+        };
+
+        match self.kind {
+            LoggerType::Program(ref exec_start) => {
+                self.start_program(exec_start, &working_directory, record_format)
+            }
+            LoggerType::File(ref settings) => {
+                self.start_file(settings, &working_directory, record_format)
+            }
+        }
+    }
+
+    fn start_program(&self,
+                     exec_start: &str,
+                     working_directory: &Option<String>,
+                     record_format: RecordFormat)
+                     -> Result<(), LoggerError> {
+        let buffer_path = match self.buffer_path {
+            None => None,
+            Some(ref p) => {
+                match *working_directory {
+                    None => Some(Path::new(p).to_path_buf()),
+                    Some(ref wd) => Some(Path::new(wd).join(p)),
+                }
+            }
+        };
+        let queue = match LogQueue::new(self.buffer_size, buffer_path) {
+            Err(e) => return Err(LoggerError::BufferFileError(format!("{:?}", e))),
+            Ok(q) => q,
+        };
+
         self.debug(format!("Starting logger..."));
-        let mut process =
-            match process::spawn_cmd(self.exec_start.as_str(), self, &working_directory) {
+        let running = match process::spawn_cmd(exec_start, self, working_directory, &[]) {
+            Err(e) => {
+                self.debug(format!("Unable to spawn {}: {:?}", exec_start, e));
+                return Err(LoggerError::ExecCommandFailed);
+            }
+            Ok(s) => s,
+        };
+
+        // Records go through the pipe, so nothing is lost if the logger goes away.
+        let pipe = Arc::new(Mutex::new(LoggerPipe {
+            stdin: None,
+            queue: queue,
+            failing: false,
+            finished: false,
+        }));
+        let thr_pipe = pipe.clone();
+        let unit = self.to_simple_unit();
+        self.controller.listen(move |msg| {
+            let record = match record_format.record(&msg) {
+                None => return Ok(()),
+                Some(r) => r,
+            };
+            let mut pipe = thr_pipe.lock().unwrap();
+            if pipe.finished {
+                return Err("logger has exited".to_string());
+            }
+            match pipe.send(record) {
+                Ok(_) => pipe.failing = false,
                 Err(e) => {
-                    self.debug(format!("Unable to spawn {}: {:?}", self.exec_start, e));
-                    return Err(LoggerError::ExecCommandFailed);
+                    if !pipe.failing {
+                        pipe.failing = true;
+                        unit.debug(e);
+                    }
                 }
-                Ok(s) => s,
-            };
+            }
+            Ok(())
+        });
 
-        let mut stdin = process.take_input();
         let unit = self.to_simple_unit();
+        let cmd = exec_start.to_string();
+        let working_directory = working_directory.clone();
+        let restart = self.restart.clone();
+        let restart_sec = self.restart_sec;
+        let thr_process = self.process.clone();
+        let builder = thread::Builder::new().name(format!("G-{}", self.id()).into());
+        builder.spawn(move || {
+                let mut running = running;
+                loop {
+                    let result = Self::supervise(running, &unit, &pipe, &thr_process);
+                    let reason = format!("exited with code {}", result);
+                    if !restart.should_restart(Some(result)) {
+                        {
+                            let mut pipe = pipe.lock().unwrap();
+                            pipe.finished = true;
+                            let lost = pipe.queue.abandon();
+                            if lost > 0 {
+                                unit.debug(format!("Logger exited, so {} records were lost",
+                                                   lost));
+                            }
+                        }
+                        let state = if result == 0 { "exited" } else { "failed" };
+                        unit.broadcast(BroadcastMessageContents::Status(unit.id().to_string(),
+                                                                        state.to_string(),
+                                                                        reason));
+                        return;
+                    }
 
-        match self.format {
-            LoggerFormat::TabSeparatedValue => {
-                self.controller.listen_logs(move |msg| {
-                    match msg {
-                        BroadcastMessage { message: BroadcastMessageContents::Log(log), .. } => {
-                            if let Err(e) = writeln!(&mut stdin,
-                                                     "{}\t{}\t{}\t{}\t{}\t{}\t",
-                                                     msg.message_class,
-                                                     msg.unit_id,
-                                                     msg.unit_type,
-                                                     msg.unix_time,
-                                                     msg.unix_time_nsecs,
-                                                     log.replace("\\", "\\\\")
-                                                         .replace("\n", "\\n")
-                                                         .replace("\t", "\\t")) {
-                                unit.debug(format!("Unable to write to logfile: {:?}", e));
-                                return Err(format!("{:?}", e));
+                    unit.broadcast(BroadcastMessageContents::Status(unit.id().to_string(),
+                                                                    "restarting".to_string(),
+                                                                    reason));
+                    let mut spawn_failures = 0;
+                    running = loop {
+                        thread::sleep(restart_delay(restart_sec, spawn_failures));
+                        match process::spawn_cmd(cmd.as_str(), &unit, &working_directory, &[]) {
+                            Ok(r) => break r,
+                            Err(e) => {
+                                spawn_failures = spawn_failures + 1;
+                                unit.broadcast(BroadcastMessageContents::Status(
+                                    unit.id().to_string(),
+                                    "restarting".to_string(),
+                                    format!("unable to start: {:?}", e)));
                             }
                         }
-                        _ => (),
                     };
-                    Ok(())
-                })
-            }
-            LoggerFormat::JSON => {
-                self.controller.listen_logs(move |msg| {
-                    match msg {
-                        BroadcastMessage { message: BroadcastMessageContents::Log(log), .. } => {
-                            let mut object = json::JsonValue::new_object();
-                            object["message_class"] = msg.message_class.into();
-                            object["unit_id"] = msg.unit_id.into();
-                            object["unit_type"] = msg.unit_type.into();
-                            object["unix_time"] = msg.unix_time.into();
-                            object["unix_time_nsecs"] = msg.unix_time_nsecs.into();
-                            object["message"] = log.into();
-                            if let Err(e) = writeln!(&mut stdin, "{}", json::stringify(object)) {
-                                unit.debug(format!("Unable to write to logfile: {:?}", e));
-                                return Err(format!("{:?}", e));
-                            };
+                }
+            })
+            .unwrap();
+        Ok(())
+    }
+
+    /// Hook a freshly-started logger program up to the pipe, replay anything
+    /// it missed, and wait for it to exit.
+    fn supervise(mut running: Running,
+                 unit: &SimpleUnit,
+                 pipe: &Arc<Mutex<LoggerPipe>>,
+                 process: &Arc<Mutex<Option<Running>>>)
+                 -> i32 {
+        let waiter = running.waiter();
+        {
+            let mut pipe = pipe.lock().unwrap();
+            pipe.stdin = Some(Box::new(running.take_input()));
+            match pipe.replay() {
+                Err(e) => unit.debug(e),
+                Ok(0) => (),
+                Ok(count) => unit.debug(format!("Replayed {} records", count)),
+            }
+            let dropped = pipe.queue.take_dropped();
+            if dropped > 0 {
+                unit.debug(format!("Buffer was full, so {} records were lost", dropped));
+            }
+        }
+        *(process.lock().unwrap()) = Some(running);
+        unit.broadcast(BroadcastMessageContents::Status(unit.id().to_string(),
+                                                        "running".to_string(),
+                                                        "".to_string()));
+
+        let result = waiter.result();
+        pipe.lock().unwrap().stdin = None;
+        process.lock().unwrap().take();
+        result
+    }
+
+    fn start_file(&self,
+                  settings: &LogFileSettings,
+                  working_directory: &Option<String>,
+                  record_format: RecordFormat)
+                  -> Result<(), LoggerError> {
+        let mut settings = settings.clone();
+        if let Some(ref wd) = *working_directory {
+            settings.path = Path::new(wd).join(&settings.path);
+        }
+
+        let mut log_file = match LogFile::open(settings) {
+            Err(e) => return Err(LoggerError::LogFileError(format!("{:?}", e))),
+            Ok(f) => f,
+        };
+        self.debug(format!("Logging to {}", log_file.path().display()));
+
+        let unit = self.to_simple_unit();
+
+        // Only complain once each time writes start failing, e.g. when the disk fills up.
+        let mut failing = false;
+        self.controller.listen(move |msg| {
+            // Switch files as runs start and finish, whether or not the
+            // START and FINISH messages themselves are logged.
+            if let BroadcastMessageContents::Start(ref scenario) = msg.message {
+                if let Err(e) = log_file.start_run(scenario, msg.unix_time) {
+                    unit.debug(format!("Unable to open log file for run: {:?}", e));
+                }
+            }
+            if let Some(record) = record_format.record(&msg) {
+                match log_file.write_line(&record) {
+                    Ok(_) => failing = false,
+                    Err(e) => {
+                        if !failing {
+                            failing = true;
+                            unit.debug(format!("Unable to write to logfile: {:?}", e));
                         }
-                        _ => (),
                     }
-                    Ok(())
-                })
+                }
+            }
+            if let BroadcastMessageContents::Finish(_, _, _) = msg.message {
+                if let Err(e) = log_file.finish_run() {
+                    unit.debug(format!("Unable to reopen log file: {:?}", e));
+                }
             }
+            Ok(())
+        });
+        Ok(())
+    }
+
+    fn load_file_settings(unitfile: &UnitFile,
+                          format: &LoggerFormat)
+                          -> Result<LogFileSettings, LoggerError> {
+        let path = match unitfile.get("Logger", "Path") {
+            None => return Err(LoggerError::MissingPath),
+            Some(s) => Path::new(s).to_path_buf(),
         };
 
-        *self.process.lock().unwrap() = Some(process);
-        Ok(())
+        let max_size = match unitfile.get("Logger", "MaxSize") {
+            None => None,
+            Some(s) => {
+                match Self::parse_size(s) {
+                    None => return Err(LoggerError::ParseMaxSizeError(s.to_string())),
+                    Some(n) => Some(n),
+                }
+            }
+        };
+
+        let max_age = match unitfile.get("Logger", "MaxAge") {
+            None => None,
+            Some(s) => {
+                match s.parse() {
+                    Err(_) => return Err(LoggerError::ParseMaxAgeError(s.to_string())),
+                    Ok(n) => Some(time::Duration::from_secs(n)),
+                }
+            }
+        };
+
+        let max_files = match unitfile.get("Logger", "MaxFiles") {
+            None => 5,
+            Some(s) => {
+                match s.parse() {
+                    Err(_) => return Err(LoggerError::ParseMaxFilesError(s.to_string())),
+                    Ok(n) => n,
+                }
+            }
+        };
+
+        let per_run = match unitfile.get("Logger", "PerRun") {
+            None => false,
+            Some(s) => {
+                match s.to_lowercase().as_ref() {
+                    "yes" | "true" | "1" => true,
+                    "no" | "false" | "0" => false,
+                    _ => return Err(LoggerError::ParsePerRunError(s.to_string())),
+                }
+            }
+        };
+
+        let extension = match *format {
+            LoggerFormat::TabSeparatedValue => "tsv",
+            LoggerFormat::JSON => "json",
+        };
+
+        Ok(LogFileSettings {
+            path: path,
+            extension: extension.to_string(),
+            max_size: max_size,
+            max_age: max_age,
+            max_files: max_files,
+            per_run: per_run,
+        })
+    }
+
+    /// Parse a size such as "512", "64K", "10M", or "1G".
+    fn parse_size(s: &str) -> Option<u64> {
+        let s = s.trim();
+        let (number, multiplier) = match s.chars().last() {
+            Some('k') | Some('K') => (&s[..s.len() - 1], 1024),
+            Some('m') | Some('M') => (&s[..s.len() - 1], 1024 * 1024),
+            Some('g') | Some('G') => (&s[..s.len() - 1], 1024 * 1024 * 1024),
+            _ => (s, 1),
+        };
+        match number.trim().parse::<u64>() {
+            Err(_) => None,
+            Ok(n) => n.checked_mul(multiplier),
+        }
+    }
+
+    fn tsv_escape(s: &str) -> String {
+        s.replace("\\", "\\\\").replace("\n", "\\n").replace("\t", "\\t")
+    }
+
+    /// The sequence number, run id, and monotonic time columns, which every record has.
+    fn tsv_ordering(msg: &BroadcastMessage) -> String {
+        format!("{}\t{}\t{}\t{}",
+                msg.sequence,
+                match msg.run_id {
+                    Some(ref r) => r.as_str(),
+                    None => "",
+                },
+                msg.monotonic_time,
+                msg.monotonic_time_nsecs)
+    }
+
+    /// Format a message as a line of tab-separated values.
+    ///
+    /// In the original layout, used when only logs are wanted, every record is a log
+    /// line, with the ordering columns after the message.  Otherwise, the record starts
+    /// with the message type, followed by the same columns as the original layout up to
+    /// the message, then one column per argument, then the ordering columns.  Either
+    /// way, the ordering columns come last, so readers that split on position keep working.
+    fn tsv_record(msg: &BroadcastMessage, logs_only: bool) -> String {
+        if logs_only {
+            if let BroadcastMessageContents::Log(ref log) = msg.message {
+                return format!("{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                               msg.message_class,
+                               msg.unit_id,
+                               msg.unit_type,
+                               msg.unix_time,
+                               msg.unix_time_nsecs,
+                               Self::tsv_escape(log),
+                               Self::tsv_ordering(msg));
+            }
+        }
+
+        let fields = match msg.message {
+            BroadcastMessageContents::Log(ref log) => vec![log.clone()],
+            BroadcastMessageContents::Describe(ref class, ref field, ref name, ref value) => {
+                vec![class.clone(), field.clone(), name.clone(), value.default().to_string()]
+            }
+            BroadcastMessageContents::Scenario(ref id) => vec![id.clone()],
+            BroadcastMessageContents::Scenarios(ref list) => vec![list.join(" ")],
+            BroadcastMessageContents::Jig(ref id) => vec![id.clone()],
+            BroadcastMessageContents::Shutdown(ref reason) => vec![reason.clone()],
+            BroadcastMessageContents::Ping(ref val) => vec![val.clone()],
+            BroadcastMessageContents::Tests(ref scenario, ref list) => {
+                vec![scenario.clone(), list.join(" ")]
+            }
+            BroadcastMessageContents::Start(ref scenario) => vec![scenario.clone()],
+            BroadcastMessageContents::Finish(ref scenario, result, ref reason) => {
+                vec![scenario.clone(), result.to_string(), reason.clone()]
+            }
+            BroadcastMessageContents::Running(ref test) => vec![test.clone()],
+            BroadcastMessageContents::Skip(ref test, ref reason) |
+            BroadcastMessageContents::Pass(ref test, ref reason) |
+            BroadcastMessageContents::Fail(ref test, ref reason) => {
+                vec![test.clone(), reason.clone()]
+            }
+            BroadcastMessageContents::Status(ref unit, ref state, ref reason) => {
+                vec![unit.clone(), state.clone(), reason.clone()]
+            }
+            BroadcastMessageContents::Update(ref updater, ref status, ref message) => {
+                vec![updater.clone(), status.clone(), message.clone()]
+            }
+            BroadcastMessageContents::Measure(ref m) => {
+                vec![m.test.clone(),
+                     m.name.clone(),
+                     m.value.to_string(),
+                     m.unit.clone(),
+                     match m.limit {
+                         None => "".to_string(),
+                         Some(ref l) => l.to_string(),
+                     },
+                     m.result().to_string()]
+            }
+            BroadcastMessageContents::Progress(ref test, percent, ref message) => {
+                vec![test.clone(), percent.to_string(), message.clone()]
+            }
+            BroadcastMessageContents::Attach(ref test, ref path, ref description) => {
+                vec![test.clone(), path.clone(), description.clone()]
+            }
+            BroadcastMessageContents::Prompt(ref id, timeout, ref choices, ref text) => {
+                vec![id.clone(), timeout.to_string(), choices.join(","), text.clone()]
+            }
+            BroadcastMessageContents::Waiting(ref test, ref resource, ref holder) => {
+                vec![test.clone(), resource.clone(), holder.clone()]
+            }
+            BroadcastMessageContents::Retry(ref test, attempt, attempts, ref reason) => {
+                vec![test.clone(), attempt.to_string(), attempts.to_string(), reason.clone()]
+            }
+            BroadcastMessageContents::Iteration(ref scenario, iteration, iterations) => {
+                vec![scenario.clone(), iteration.to_string(), iterations.to_string()]
+            }
+            BroadcastMessageContents::Summary(ref scenario, passed, failed, ref tallies) => {
+                let tallies: Vec<String> = tallies.iter().map(|t| t.to_string()).collect();
+                vec![scenario.clone(), passed.to_string(), failed.to_string(), tallies.join(" ")]
+            }
+        };
+
+        let mut record = format!("{}\t{}\t{}\t{}\t{}\t{}",
+                                 msg.message.verb(),
+                                 msg.message_class,
+                                 msg.unit_id,
+                                 msg.unit_type,
+                                 msg.unix_time,
+                                 msg.unix_time_nsecs);
+        for field in fields {
+            record.push('\t');
+            record.push_str(&Self::tsv_escape(&field));
+        }
+        record.push('\t');
+        record.push_str(&Self::tsv_ordering(msg));
+        record
+    }
+
+    /// Format a message as a JSON object.  Every record has the same fields as
+    /// a log record, plus a "type" field and one field per argument.
+    fn json_record(msg: &BroadcastMessage) -> json::JsonValue {
+        let mut object = json::JsonValue::new_object();
+        object["type"] = msg.message.verb().into();
+        object["message_class"] = msg.message_class.as_str().into();
+        object["unit_id"] = msg.unit_id.as_str().into();
+        object["unit_type"] = msg.unit_type.as_str().into();
+        object["unix_time"] = msg.unix_time.into();
+        object["unix_time_nsecs"] = msg.unix_time_nsecs.into();
+        object["monotonic_time"] = msg.monotonic_time.into();
+        object["monotonic_time_nsecs"] = msg.monotonic_time_nsecs.into();
+        object["sequence"] = msg.sequence.into();
+        object["run_id"] = match msg.run_id {
+            Some(ref r) => r.as_str().into(),
+            None => json::JsonValue::Null,
+        };
+        match msg.message {
+            BroadcastMessageContents::Log(ref log) => object["message"] = log.as_str().into(),
+            BroadcastMessageContents::Describe(ref class, ref field, ref name, ref value) => {
+                object["class"] = class.as_str().into();
+                object["field"] = field.as_str().into();
+                object["name"] = name.as_str().into();
+                object["value"] = value.default().into();
+            }
+            BroadcastMessageContents::Scenario(ref id) => object["id"] = id.as_str().into(),
+            BroadcastMessageContents::Scenarios(ref list) => {
+                object["scenarios"] = list.clone().into()
+            }
+            BroadcastMessageContents::Jig(ref id) => object["id"] = id.as_str().into(),
+            BroadcastMessageContents::Shutdown(ref reason) => {
+                object["reason"] = reason.as_str().into()
+            }
+            BroadcastMessageContents::Ping(ref val) => object["val"] = val.as_str().into(),
+            BroadcastMessageContents::Tests(ref scenario, ref list) => {
+                object["scenario"] = scenario.as_str().into();
+                object["tests"] = list.clone().into();
+            }
+            BroadcastMessageContents::Start(ref scenario) => {
+                object["scenario"] = scenario.as_str().into()
+            }
+            BroadcastMessageContents::Finish(ref scenario, result, ref reason) => {
+                object["scenario"] = scenario.as_str().into();
+                object["result"] = result.into();
+                object["reason"] = reason.as_str().into();
+            }
+            BroadcastMessageContents::Running(ref test) => object["test"] = test.as_str().into(),
+            BroadcastMessageContents::Skip(ref test, ref reason) |
+            BroadcastMessageContents::Pass(ref test, ref reason) |
+            BroadcastMessageContents::Fail(ref test, ref reason) => {
+                object["test"] = test.as_str().into();
+                object["reason"] = reason.as_str().into();
+            }
+            BroadcastMessageContents::Status(ref unit, ref state, ref reason) => {
+                object["unit"] = unit.as_str().into();
+                object["state"] = state.as_str().into();
+                object["reason"] = reason.as_str().into();
+            }
+            BroadcastMessageContents::Update(ref updater, ref status, ref message) => {
+                object["updater"] = updater.as_str().into();
+                object["status"] = status.as_str().into();
+                object["message"] = message.as_str().into();
+            }
+            BroadcastMessageContents::Measure(ref m) => {
+                object["test"] = m.test.as_str().into();
+                object["name"] = m.name.as_str().into();
+                object["value"] = m.value.into();
+                object["unit"] = m.unit.as_str().into();
+                match m.limit {
+                    None => {
+                        object["low"] = json::JsonValue::Null;
+                        object["high"] = json::JsonValue::Null;
+                    }
+                    Some(ref l) => {
+                        object["low"] = l.low.into();
+                        object["high"] = l.high.into();
+                    }
+                }
+                object["result"] = m.result().into();
+            }
+            BroadcastMessageContents::Progress(ref test, percent, ref message) => {
+                object["test"] = test.as_str().into();
+                object["percent"] = percent.into();
+                object["message"] = message.as_str().into();
+            }
+            BroadcastMessageContents::Attach(ref test, ref path, ref description) => {
+                object["test"] = test.as_str().into();
+                object["path"] = path.as_str().into();
+                object["description"] = description.as_str().into();
+            }
+            BroadcastMessageContents::Prompt(ref id, timeout, ref choices, ref text) => {
+                object["id"] = id.as_str().into();
+                object["timeout"] = timeout.into();
+                object["choices"] = choices.clone().into();
+                object["text"] = text.as_str().into();
+            }
+            BroadcastMessageContents::Waiting(ref test, ref resource, ref holder) => {
+                object["test"] = test.as_str().into();
+                object["resource"] = resource.as_str().into();
+                object["holder"] = holder.as_str().into();
+            }
+            BroadcastMessageContents::Retry(ref test, attempt, attempts, ref reason) => {
+                object["test"] = test.as_str().into();
+                object["attempt"] = attempt.into();
+                object["attempts"] = attempts.into();
+                object["reason"] = reason.as_str().into();
+            }
+            BroadcastMessageContents::Iteration(ref scenario, iteration, iterations) => {
+                object["scenario"] = scenario.as_str().into();
+                object["iteration"] = iteration.into();
+                object["iterations"] = iterations.into();
+            }
+            BroadcastMessageContents::Summary(ref scenario, passed, failed, ref tallies) => {
+                object["scenario"] = scenario.as_str().into();
+                object["passed"] = passed.into();
+                object["failed"] = failed.into();
+                let mut tests = json::JsonValue::new_array();
+                for t in tallies {
+                    let mut tally = json::JsonValue::new_object();
+                    tally["test"] = t.test.as_str().into();
+                    tally["pass"] = t.pass.into();
+                    tally["fail"] = t.fail.into();
+                    tally["skip"] = t.skip.into();
+                    tests.push(tally).ok();
+                }
+                object["tests"] = tests;
+            }
+        }
+        object
     }
 }
 
@@ -5,6 +5,9 @@ mod logger;
 mod trigger;
 mod jig;
 mod interface;
+mod coupon;
+mod service;
+mod updater;
 pub mod unit;
 
 pub use self::test::*;
@@ -13,4 +16,7 @@ pub use self::logger::*;
 pub use self::trigger::*;
 pub use self::jig::*;
 pub use self::interface::*;
+pub use self::coupon::*;
+pub use self::service::*;
+pub use self::updater::*;
 pub use self::unit::*;
\ No newline at end of file
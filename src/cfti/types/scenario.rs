@@ -10,12 +10,17 @@ use std::time;
 use self::runny::running::Running;
 
 use cfti::types::test::{Test, TestState};
+use cfti::types::coupon::{Coupon, CouponError};
 use cfti::types::Unit;
 use cfti::process;
 use cfti::config;
 use cfti::testset;
 use cfti::controller::{Controller, BroadcastMessageContents, ControlMessageContents};
 use cfti::unitfile::UnitFile;
+use cfti::locale::LocalizedString;
+use cfti::report::{ReportFormat, RunReport};
+use cfti::resources::ResourceManager;
+use cfti::repeat::Iterations;
 
 #[derive(Clone, Debug)]
 pub enum ScenarioError {
@@ -23,6 +28,10 @@ pub enum ScenarioError {
     MissingScenarioSection,
     TestListNotFound,
     DependencyError(String),
+    InvalidReportFormat(String),
+    InvalidParallel(String),
+    InvalidRepeat(String),
+    InvalidRepeatUntilFailure(String),
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -30,11 +39,16 @@ enum ScenarioState {
     /// The scenario has been loaded, and is ready to run.
     Idle,
 
+    /// Coupon ExecPreflight commands are running, one after another.  This is
+    /// the index of the coupon whose command is underway.
+    Preflight(usize),
+
     /// The scenario has started, but is waiting for ExecStart to finish
     PreStart,
 
-    /// The scenario is running, and is on step (u32)
-    Running(usize),
+    /// The scenario is running tests.  These are the indexes of the ones
+    /// still underway, of which there are at most `parallel`.
+    Running(Vec<usize>),
 
     /// The scenario has succeeded, and is running the ExecStopSuccess step
     PostSuccess,
@@ -52,10 +66,10 @@ pub struct Scenario {
     id: String,
 
     /// name: Display name of this scenario.
-    name: String,
+    name: LocalizedString,
 
     /// description: Paragraph describing this scenario.
-    description: String,
+    description: LocalizedString,
 
     /// timeout: Maximum number of seconds this scenario should take.
     timeout: Duration,
@@ -90,9 +104,6 @@ pub struct Scenario {
     /// What the current state of the scenario is.
     state: Arc<Mutex<ScenarioState>>,
 
-    /// How many tests have failed.
-    failures: Arc<Mutex<u32>>,
-
     /// Dependency graph for all tests to be run.
     graph: dependy::Dependy,
 
@@ -110,6 +121,39 @@ pub struct Scenario {
 
     /// How long we let a process termination go for.
     termination_timeout: Duration,
+
+    /// Coupons to issue when this scenario finishes.
+    coupons: Vec<Arc<Mutex<Coupon>>>,
+
+    /// Variables exported by coupon preflight commands for the current run.
+    environment: Arc<Mutex<process::Environment>>,
+
+    /// What the coupon preflight command that just finished exported, or why it failed.
+    preflight_result: Arc<Mutex<Option<Result<process::Environment, CouponError>>>>,
+
+    /// report_directory: Where to write a report of each run, if anywhere.
+    report_directory: Option<String>,
+
+    /// report_formats: Which kinds of report to write into report_directory.
+    report_formats: Vec<ReportFormat>,
+
+    /// The wall-clock time the current run started, for reports.
+    started: Arc<Mutex<time::SystemTime>>,
+
+    /// parallel: How many tests may run at once.
+    parallel: usize,
+
+    /// Which tests hold which resources during the current run.
+    resources: Arc<Mutex<ResourceManager>>,
+
+    /// repeat: How many times to run the scenario each time it's started, or 0 for no limit.
+    repeat: u32,
+
+    /// repeat_until_failure: Stop repeating after the first run that fails.
+    repeat_until_failure: bool,
+
+    /// Which iteration of a repeated scenario is running, and how the others went.
+    iterations: Arc<Mutex<Iterations>>,
 }
 
 impl dependy::Dependency for Test {
@@ -199,14 +243,14 @@ impl Scenario {
             }
         }
 
-        let description = match unitfile.get("Scenario", "Description") {
-            None => "".to_string(),
-            Some(s) => s.to_string(),
+        let description = match unitfile.get_localized_string("Scenario", "Description") {
+            None => LocalizedString::new(""),
+            Some(s) => s,
         };
 
-        let name = match unitfile.get("Scenario", "Name") {
-            None => id.to_string(),
-            Some(s) => s.to_string(),
+        let name = match unitfile.get_localized_string("Scenario", "Name") {
+            None => LocalizedString::new(id),
+            Some(s) => s,
         };
 
         let timeout = match unitfile.get("Scenario", "Timeout") {
@@ -268,6 +312,59 @@ impl Scenario {
             }
         };
 
+        let report_directory = match unitfile.get("Scenario", "ReportDirectory") {
+            None => config.report_directory().clone(),
+            Some(s) => Some(s.to_string()),
+        };
+
+        let report_formats = match unitfile.get("Scenario", "ReportFormat") {
+            None => vec![ReportFormat::JUnit],
+            Some(s) => {
+                let mut formats = vec![];
+                for name in s.split(|c| c == ',' || c == ' ').filter(|x| !x.is_empty()) {
+                    match ReportFormat::from_str(name) {
+                        None => {
+                            return Some(Err(ScenarioError::InvalidReportFormat(name.to_string())))
+                        }
+                        Some(f) => formats.push(f),
+                    }
+                }
+                formats
+            }
+        };
+
+        let parallel = match unitfile.get("Scenario", "Parallel") {
+            None => 1,
+            Some(s) => {
+                match s.trim().parse() {
+                    Ok(n) if n > 0 => n,
+                    _ => return Some(Err(ScenarioError::InvalidParallel(s.to_string()))),
+                }
+            }
+        };
+
+        let repeat_until_failure = match unitfile.get("Scenario", "RepeatUntilFailure") {
+            None => false,
+            Some(s) => {
+                match s.to_lowercase().as_ref() {
+                    "yes" | "true" | "1" => true,
+                    "no" | "false" | "0" => false,
+                    _ => return Some(Err(ScenarioError::InvalidRepeatUntilFailure(s.to_string()))),
+                }
+            }
+        };
+
+        // Repeating until failure has no limit, unless Repeat= gives one.
+        let repeat = match unitfile.get("Scenario", "Repeat") {
+            None => if repeat_until_failure { 0 } else { 1 },
+            Some(s) => {
+                match s.trim().parse() {
+                    Ok(n) => n,
+                    Err(_) => return Some(Err(ScenarioError::InvalidRepeat(s.to_string()))),
+                }
+            }
+        };
+
         // Create a new dependency graph
         let mut graph = dependy::Dependy::new();
 
@@ -307,36 +404,7 @@ impl Scenario {
             tests.push(loaded_tests[test].clone());
         }
 
-        let failures = Arc::new(Mutex::new(0));
-
-        let thr_failures = failures.clone();
-
         let state = Arc::new(Mutex::new(ScenarioState::Idle));
-        //        let thr_state = state.clone();
-        let thr_id = id.to_string();
-
-        // Monitor broadcast states to determine when tests finish.
-        test_set.controller().listen(move |msg| {
-            match msg.message {
-                BroadcastMessageContents::Fail(msg_id, _) => {
-                    // Only indicate a failure if we're running a test.
-                    // Don't fail during e.g. ExecStartPre
-                    let start_id = format!("{}-{}", thr_id, "execstart");
-                    let stop_success_id = format!("{}-{}", thr_id, "execstopfailure");
-                    let stop_failure_id = format!("{}-{}", thr_id, "execstopsuccess");
-
-                    if (msg_id == start_id) || (msg_id == stop_success_id) ||
-                       (msg_id == stop_failure_id) {
-                        ;
-                    } else {
-                        let mut failures = thr_failures.lock().unwrap();
-                        *failures = *failures + 1;
-                    }
-                }
-                _ => (),
-            };
-            Ok(())
-        });
 
         Some(Ok(Scenario {
             id: id.to_string(),
@@ -353,16 +421,31 @@ impl Scenario {
             exec_stop_failure_timeout: config.scenario_success_timeout(),
             controller: test_set.controller().clone(),
             state: state,
-            failures: failures,
             graph: graph,
             termination_timeout: config.default_termination_timeout().clone(),
             working_directory: Arc::new(Mutex::new(None)),
             start_time: Arc::new(Mutex::new(time::Instant::now())),
             support_cmd: Arc::new(Mutex::new(None)),
             assumptions: Arc::new(Mutex::new(assumptions)),
+            coupons: vec![],
+            environment: Arc::new(Mutex::new(vec![])),
+            preflight_result: Arc::new(Mutex::new(None)),
+            report_directory: report_directory,
+            report_formats: report_formats,
+            started: Arc::new(Mutex::new(time::SystemTime::now())),
+            parallel: parallel,
+            resources: Arc::new(Mutex::new(ResourceManager::new())),
+            repeat: repeat,
+            repeat_until_failure: repeat_until_failure,
+            iterations: Arc::new(Mutex::new(Iterations::new(repeat, repeat_until_failure))),
         }))
     }
 
+    /// Issue `coupon` whenever this scenario is run.
+    pub fn add_coupon(&mut self, coupon: Arc<Mutex<Coupon>>) {
+        self.coupons.push(coupon);
+    }
+
     fn all_dependencies_succeeded(&self, test_name: &String) -> bool {
         for parent_name in self.graph.required_parents_of_named(test_name) {
             //            self.debug(format!("Checking if {} is an assumption ({})", test_name))
@@ -396,31 +479,14 @@ impl Scenario {
             // We can always enter the idle state.
             ScenarioState::Idle => true,
 
+            // Only run() starts preflight commands, and only if there are coupons.
+            ScenarioState::Preflight(_) => !self.coupons.is_empty(),
+
             // Run an exec_start command before we run the first test.
             ScenarioState::PreStart => self.exec_start.is_some(),
 
-            // Run a given test.
-            ScenarioState::Running(i) => {
-                let test_name = self.tests[i].lock().unwrap().id().to_string();
-                if self.scenario_timed_out() {
-                    false
-                } else if i >= self.tests.len() {
-                    false
-                }
-                // If the test isn't Pending (i.e. if it's skipped or failed), don't run it.
-                else if self.tests[i].lock().unwrap().state() != TestState::Pending {
-                    false
-                }
-                // Make sure all required dependencies succeeded.
-                else if !self.all_dependencies_succeeded(&test_name) {
-                    self.tests[i].lock().unwrap().skip();
-                    self.broadcast(BroadcastMessageContents::Skip(test_name.clone(),
-                                                                  "dependency failed".to_string()));
-                    false
-                } else {
-                    true
-                }
-            }
+            // Run tests, unless there's no time left to run any.
+            ScenarioState::Running(_) => !self.scenario_timed_out(),
 
             // Run a script on scenario success.
             ScenarioState::PostSuccess => self.exec_stop_success.is_some(),
@@ -436,17 +502,15 @@ impl Scenario {
     // Find the next state.
     // If we're idle, start the test.
     // The state order goes:
-    // Idle -> [PreStart] -> Test(0) -> ... -> Test(n) -> [PostSuccess/Fail] -> Idle
+    // Idle -> [Preflight] -> [PreStart] -> Running -> [PostSuccess/Fail] -> Idle
     //
+    // The scenario stays in Running until every test has finished or been
+    // skipped.  See step() for that.
     fn find_next_state(&self, current_state: ScenarioState) -> ScenarioState {
 
-        let test_count = self.tests.len();
-        let failure_count = *(self.failures.lock().unwrap());
-
         let new_state = match current_state {
-            ScenarioState::Idle => {
-                // Reset the number of errors.
-                *(self.failures.lock().unwrap()) = 0;
+            ScenarioState::Idle |
+            ScenarioState::Preflight(_) => {
                 for test in &self.tests {
                     test.lock().unwrap().pending();
                 }
@@ -455,24 +519,13 @@ impl Scenario {
                 ScenarioState::PreStart
             }
 
-            // If we've just run the PreStart command, see if we need
-            // to run test 0, or skip straight to Success.
-            ScenarioState::PreStart => ScenarioState::Running(0),
+            // If we've just run the PreStart command, start running tests.
+            ScenarioState::PreStart => ScenarioState::Running(vec![]),
 
-            // If we just finished running a test, determine the next test to run.
-            ScenarioState::Running(i) if (i + 1) < test_count => ScenarioState::Running(i + 1),
-            ScenarioState::Running(i) if (i + 1) >= test_count && failure_count > 0 => {
-                ScenarioState::PostFailure
-            }
-            ScenarioState::Running(i) if (i + 1) >= test_count && failure_count == 0 => {
-                ScenarioState::PostSuccess
-            }
-            ScenarioState::Running(i) => {
-                panic!("Got into a weird state. Running({}), test_count: {}, failure_count: {}",
-                       i,
-                       test_count,
-                       failure_count)
-            }
+            // Every test has finished, and the daemons have been terminated,
+            // so run the appropriate ExecStop.
+            ScenarioState::Running(_) if self.failed_tests() > 0 => ScenarioState::PostFailure,
+            ScenarioState::Running(_) => ScenarioState::PostSuccess,
             ScenarioState::PostFailure => ScenarioState::TestFinished,
             ScenarioState::PostSuccess => ScenarioState::TestFinished,
             ScenarioState::TestFinished => ScenarioState::TestFinished,
@@ -488,6 +541,145 @@ impl Scenario {
         }
     }
 
+    /// Returns true if test `i` no longer needs one of the `parallel` slots,
+    /// either because it has finished, or because it's a daemon that's ready.
+    fn test_is_done(&self, i: usize) -> bool {
+        let test = self.tests[i].lock().unwrap();
+        match test.state() {
+            TestState::Pending | TestState::Starting => false,
+            TestState::Running => test.is_daemon(),
+            TestState::Pass | TestState::Fail(_) | TestState::Skip => true,
+        }
+    }
+
+    /// Returns false if any test `test_name` requires is still underway, or hasn't run yet.
+    fn requirements_finished(&self, test_name: &String, running: &Vec<usize>) -> bool {
+        for parent_name in self.graph.required_parents_of_named(test_name) {
+            if self.assumptions.lock().unwrap().contains(parent_name) {
+                continue;
+            }
+            if let Some(&idx) = self.tests_map.get(parent_name) {
+                if running.contains(&idx) ||
+                   self.tests[idx].lock().unwrap().state() == TestState::Pending {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /// Start every test whose requirements have finished, in dependency order,
+    /// until `parallel` tests are running.  Tests whose requirements failed are skipped.
+    fn start_ready_tests(&self, running: &mut Vec<usize>) {
+        for i in 0..self.tests.len() {
+            if running.len() >= self.parallel || self.scenario_timed_out() {
+                return;
+            }
+
+            // If the test isn't Pending (i.e. if it's skipped or failed), don't run it.
+            if running.contains(&i) || self.tests[i].lock().unwrap().state() != TestState::Pending {
+                continue;
+            }
+
+            let test_name = self.tests[i].lock().unwrap().id().to_string();
+            if !self.requirements_finished(&test_name, running) {
+                continue;
+            }
+
+            // Make sure all required dependencies succeeded.
+            if !self.all_dependencies_succeeded(&test_name) {
+                self.tests[i].lock().unwrap().skip();
+                self.resources.lock().unwrap().stop_waiting(&test_name);
+                self.broadcast(BroadcastMessageContents::Skip(test_name,
+                                                              "dependency failed".to_string()));
+                continue;
+            }
+
+            // Wait for any test using the same resources to give them up.
+            let ref test = self.tests[i].lock().unwrap();
+            let mut resources = self.resources.lock().unwrap();
+            let blocked = resources.blocked_by(&test_name, test.resources());
+            if let Some((claim, holder)) = blocked {
+                if resources.wait(&test_name, &claim, &holder) {
+                    self.broadcast(BroadcastMessageContents::Waiting(test_name,
+                                                                     claim.name,
+                                                                     holder));
+                }
+                continue;
+            }
+            resources.acquire(&test_name, test.resources());
+            drop(resources);
+
+            running.push(i);
+            let test_max_time = self.make_timeout(test.timeout());
+            test.start(&*self.working_directory.lock().unwrap(),
+                       &*self.environment.lock().unwrap(),
+                       test_max_time);
+        }
+    }
+
+    /// Give back the resources of every test that's done with them.  Daemons
+    /// hold on to theirs for as long as they keep running.
+    fn release_resources(&self, running: &Vec<usize>) {
+        let mut resources = self.resources.lock().unwrap();
+        for (i, test) in self.tests.iter().enumerate() {
+            if running.contains(&i) {
+                continue;
+            }
+            let test = test.lock().unwrap();
+            if test.is_daemon() && test.state() == TestState::Running {
+                continue;
+            }
+            resources.release(test.id());
+        }
+    }
+
+    /// Skip the tests that are waiting on resources.  Only called once nothing
+    /// is running, when the only holders left are daemons that won't let go.
+    /// Returns true if any tests were skipped.
+    fn skip_stalled_tests(&self) -> bool {
+        let waiting = self.resources.lock().unwrap().take_waiting();
+        let mut skipped = false;
+        for (test_name, resource, holder) in waiting {
+            let test = match self.tests_map.get(&test_name) {
+                None => continue,
+                Some(&idx) => self.tests[idx].clone(),
+            };
+            if test.lock().unwrap().state() != TestState::Pending {
+                continue;
+            }
+            test.lock().unwrap().skip();
+            self.broadcast(BroadcastMessageContents::Skip(test_name,
+                                                          format!("{} is held by {}",
+                                                                  resource,
+                                                                  holder)));
+            skipped = true;
+        }
+        skipped
+    }
+
+    /// Start the ExecPreflight command of coupon `idx`.  Once it finishes,
+    /// advance() picks up what it exported.
+    fn start_preflight(&self, idx: usize) {
+        let unit = self.to_simple_unit();
+        let thr_result = self.preflight_result.clone();
+        let thr_support_cmd = self.support_cmd.clone();
+        let running = self.coupons[idx]
+            .lock()
+            .unwrap()
+            .preflight(&*self.working_directory.lock().unwrap(), move |res| {
+                *(thr_support_cmd.lock().unwrap()) = None;
+                *(thr_result.lock().unwrap()) = Some(res);
+                unit.control_class("support", ControlMessageContents::AdvanceScenario);
+            });
+
+        // Don't hang on to the command if it has already finished.
+        let mut support_cmd = self.support_cmd.lock().unwrap();
+        if self.preflight_result.lock().unwrap().is_none() {
+            *support_cmd = running;
+        }
+    }
+
     fn run_support_cmd(&self, cmd: &str, timeout: &Duration, testname: &str) {
         // unwrap is safe because we know a PreStart command exists.
         let tn = testname.to_string();
@@ -495,6 +687,7 @@ impl Scenario {
         let thr_support_cmd = self.support_cmd.clone();
         let res = process::try_command_completion(cmd,
                                                   &*self.working_directory.lock().unwrap(),
+                                                  &*self.environment.lock().unwrap(),
                                                   *timeout,
                                                   move |res: Result<(), process::CommandError>| {
             let msg = match res {
@@ -522,8 +715,29 @@ impl Scenario {
         *(self.support_cmd.lock().unwrap()) = Some(running);
     }
 
+    /// Returns true if the scenario is in the middle of a run, including
+    /// between iterations, when the next one has been asked for but hasn't started.
+    pub fn is_running(&self) -> bool {
+        if self.iterations.lock().unwrap().pending() {
+            return true;
+        }
+        match *(self.state.lock().unwrap()) {
+            ScenarioState::Idle |
+            ScenarioState::TestFinished => false,
+            _ => true,
+        }
+    }
+
     /// Don't run any new tests.  Stop the current test if one is running.
     pub fn abort(&self) {
+        // Don't start any more iterations.  If one was about to start, the run is over now.
+        {
+            let mut iterations = self.iterations.lock().unwrap();
+            if iterations.stop() && iterations.is_repeating() {
+                self.summarize(&iterations);
+            }
+        }
+
         let mut current_state = self.state.lock().unwrap();
 
         match *current_state {
@@ -532,6 +746,7 @@ impl Scenario {
             ScenarioState::TestFinished => (),
 
             // Running one of our support commands. Stop that.
+            ScenarioState::Preflight(_) |
             ScenarioState::PreStart |
             ScenarioState::PostFailure |
             ScenarioState::PostSuccess => {
@@ -541,13 +756,19 @@ impl Scenario {
                 self.finish_scenario();
             }
 
-            // In the middle of running a test.
-            ScenarioState::Running(i) => {
-                self.tests[i].lock().unwrap().skip();
-                for test_num in i..self.tests.len() {
-                    self.tests[test_num].lock().unwrap().skip();
+            // In the middle of running tests.
+            ScenarioState::Running(ref running) => {
+                for test in &self.tests {
+                    let test = test.lock().unwrap();
+                    if test.state() == TestState::Pending {
+                        test.skip();
+                    }
                 }
-                self.tests[i].lock().unwrap().stop(&*self.working_directory.lock().unwrap());
+                for &i in running {
+                    self.tests[i].lock().unwrap().skip();
+                    self.tests[i].lock().unwrap().stop(&*self.working_directory.lock().unwrap());
+                }
+                self.terminate_daemons();
                 self.finish_scenario();
             }
         }
@@ -555,39 +776,197 @@ impl Scenario {
         *current_state = ScenarioState::TestFinished;
     }
 
-    // Post messages and terminate tests.
-    pub fn finish_scenario(&self) {
-        let failures = *(self.failures.lock().unwrap());
+    /// Stop every daemon test, which decides whether it passed.  Called once
+    /// per run, when the last test has finished or the run is aborted.
+    fn terminate_daemons(&self) {
         for test in &self.tests {
-            test.lock().unwrap().terminate();
+            let test = test.lock().unwrap();
+            if test.is_daemon() {
+                test.terminate();
+            }
         }
+    }
+
+    /// How many tests have failed so far.
+    fn failed_tests(&self) -> u32 {
+        self.tests
+            .iter()
+            .filter(|t| match t.lock().unwrap().state() {
+                TestState::Fail(_) => true,
+                _ => false,
+            })
+            .count() as u32
+    }
+
+    // Post messages.  Daemons have already been terminated, so each test's
+    // state is final.
+    pub fn finish_scenario(&self) {
+        let mut failures = self.failed_tests();
+
+        // A coupon is only issued if every single test passed.
+        let passed = failures == 0 &&
+                     self.tests.iter().all(|x| x.lock().unwrap().state() == TestState::Pass);
+        for coupon in &self.coupons {
+            let coupon = coupon.lock().unwrap();
+            if let Err(e) = coupon.finish(self.id(),
+                                          &self.tests,
+                                          passed,
+                                          &*self.working_directory.lock().unwrap(),
+                                          &*self.environment.lock().unwrap()) {
+                self.log(format!("Coupon {} failed: {:?}", coupon.id(), e));
+                failures = failures + 1;
+            }
+        }
+
         if failures > 0 {
             self.log(format!("{} tests failed", failures));
-            self.broadcast(BroadcastMessageContents::Finish(self.id().to_string(),
-                                                            failures + 500,
-                                                            "At least one test failed"
-                                                                .to_string()));
+            self.finish(failures + 500, "At least one test failed");
         } else {
             self.log(format!("All tests passed successfully"));
-            self.broadcast(BroadcastMessageContents::Finish(self.id().to_string(),
-                                                            200,
-                                                            "Finished tests".to_string()));
+            self.finish(200, "Finished tests");
         }
     }
 
-    // Given the current state, figure out the next test to run (if any)
+    /// Write out any reports, and then tell everyone the run is over.
+    fn finish(&self, result: u32, reason: &str) {
+        if let Some(ref directory) = self.report_directory {
+            let iteration = {
+                let iterations = self.iterations.lock().unwrap();
+                if iterations.is_repeating() {
+                    Some(iterations.current())
+                } else {
+                    None
+                }
+            };
+            let report = RunReport {
+                scenario: self.id(),
+                iteration: iteration,
+                started: self.started.lock().unwrap().clone(),
+                finished: time::SystemTime::now(),
+                result: result,
+                reason: reason,
+                tests: &self.tests,
+            };
+            for format in &self.report_formats {
+                match report.write(directory, format) {
+                    Ok(path) => self.debug(format!("Wrote report to {}", path.display())),
+                    Err(e) => self.log(format!("Unable to write report: {}", e)),
+                }
+            }
+        }
+
+        self.broadcast(BroadcastMessageContents::Finish(self.id().to_string(),
+                                                        result,
+                                                        reason.to_string()));
+        self.controller.set_run_id(None);
+
+        // If the scenario is being repeated, either start the next iteration,
+        // or sum up how every iteration went.  The next one is started through the
+        // controller, so that a long run doesn't pile up on the stack.
+        let results: Vec<(String, TestState)> = self.tests
+            .iter()
+            .map(|t| {
+                let t = t.lock().unwrap();
+                (t.id().to_string(), t.state())
+            })
+            .collect();
+        let mut iterations = self.iterations.lock().unwrap();
+        iterations.record(result == 200, &results);
+        if iterations.next() {
+            self.control(ControlMessageContents::RepeatScenario);
+        } else if iterations.is_repeating() {
+            self.summarize(&iterations);
+        }
+    }
+
+    /// Broadcast how each test fared across every iteration of a repeated scenario.
+    fn summarize(&self, iterations: &Iterations) {
+        self.log(format!("Ran {} iterations: {} passed, {} failed",
+                         iterations.passed() + iterations.failed(),
+                         iterations.passed(),
+                         iterations.failed()));
+        self.broadcast(BroadcastMessageContents::Summary(self.id().to_string(),
+                                                         iterations.passed(),
+                                                         iterations.failed(),
+                                                         iterations.tallies().clone()));
+    }
+
+    /// Move the scenario along, because a test or command has finished.
+    ///
+    /// AdvanceScenario messages that arrive while no run is underway, e.g. from a
+    /// test left over from an aborted run, are ignored.
     pub fn advance(&self) {
         let current_state = self.state.lock().unwrap().clone();
-
-        // Run the test's stop() command if we just ran a test.
         match current_state {
-            ScenarioState::Running(step) => {
-                self.tests[step]
+            ScenarioState::Idle |
+            ScenarioState::TestFinished => {
+                self.debug(format!("Ignoring request to advance, because ScenarioState is {:?}",
+                                   current_state));
+            }
+            _ => self.step(),
+        }
+    }
+
+    // Given the current state, figure out the next test to run (if any)
+    fn step(&self) {
+        let current_state = self.state.lock().unwrap().clone();
+
+        // Wait for each coupon's preflight command in turn, before starting any tests.
+        if let ScenarioState::Preflight(idx) = current_state {
+            let result = self.preflight_result.lock().unwrap().take();
+            match result {
+                None => return,
+                Some(Err(e)) => {
+                    let coupon = self.coupons[idx].lock().unwrap();
+                    self.log(format!("Coupon {} preflight failed: {:?}", coupon.id(), e));
+                    *(self.state.lock().unwrap()) = ScenarioState::TestFinished;
+
+                    // Nothing can be tested, so there's no point in repeating.
+                    self.iterations.lock().unwrap().stop();
+                    self.finish(412, "Coupon preflight failed");
+                    return;
+                }
+                Some(Ok(mut e)) => {
+                    self.environment.lock().unwrap().append(&mut e);
+                    if idx + 1 < self.coupons.len() {
+                        *(self.state.lock().unwrap()) = ScenarioState::Preflight(idx + 1);
+                        self.start_preflight(idx + 1);
+                        return;
+                    }
+                }
+            }
+        }
+
+        if let ScenarioState::Running(mut running) = current_state.clone() {
+            // An AdvanceScenario usually means one test has finished, or a daemon has come
+            // ready, but several may have by the time it's handled.  Run each one's stop()
+            // command, and free up their slots.
+            let done: Vec<usize> =
+                running.iter().cloned().filter(|&i| self.test_is_done(i)).collect();
+            running.retain(|i| !done.contains(i));
+            for i in done {
+                self.tests[i]
                     .lock()
                     .unwrap()
-                    .stop(&*self.working_directory.lock().unwrap())
+                    .stop(&*self.working_directory.lock().unwrap());
+            }
+
+            // Stay in the Running state until there's nothing left to start,
+            // and the last test has finished.
+            self.release_resources(&running);
+            self.start_ready_tests(&mut running);
+            while running.is_empty() && !self.scenario_timed_out() && self.skip_stalled_tests() {
+                self.start_ready_tests(&mut running);
+            }
+            let still_running = !running.is_empty();
+            *(self.state.lock().unwrap()) = ScenarioState::Running(running);
+            if still_running {
+                return;
             }
-            _ => (),
+
+            // Daemons only pass or fail once they're terminated, and that decides
+            // which ExecStop to run.
+            self.terminate_daemons();
         }
 
         let new_state = self.find_next_state(current_state);
@@ -596,6 +975,9 @@ impl Scenario {
             // We generally shouldn't transition to the Idle state.
             ScenarioState::Idle => (),
 
+            // Preflight commands are started by run(), and then each other.
+            ScenarioState::Preflight(_) => (),
+
             // If we want to run a preroll command and it fails, log it and start the tests.
             ScenarioState::PreStart => {
                 let ref cmd = self.exec_start;
@@ -604,12 +986,8 @@ impl Scenario {
                                      &self.exec_start_timeout,
                                      format!("{}-{}", self.id(), "execstart").as_str());
             }
-            ScenarioState::Running(next_step) => {
-                let ref test = self.tests[next_step].lock().unwrap();
-                let test_timeout = test.timeout();
-                let test_max_time = self.make_timeout(test_timeout);
-                test.start(&*self.working_directory.lock().unwrap(), test_max_time);
-            }
+            // Start the first tests.
+            ScenarioState::Running(_) => self.step(),
             ScenarioState::PostSuccess => {
                 let ref cmd = self.exec_stop_success;
                 let cmd = cmd.clone().unwrap();
@@ -631,6 +1009,29 @@ impl Scenario {
         }
     }
 
+    /// Run `test_id` again, after an attempt failed in a way that its RetryOn allows.
+    /// The test keeps its place among the running tests while it's retried.
+    pub fn retry(&self, test_id: &str) {
+        let idx = match self.tests_map.get(test_id) {
+            None => return,
+            Some(&i) => i,
+        };
+
+        // If the scenario was aborted in the meantime, the test was already skipped.
+        match *(self.state.lock().unwrap()) {
+            ScenarioState::Running(ref running) if running.contains(&idx) => (),
+            _ => return,
+        }
+
+        let ref test = self.tests[idx].lock().unwrap();
+        if self.scenario_timed_out() {
+            test.give_up("Scenario timed out before the test could be retried".to_string());
+            return;
+        }
+        let test_max_time = self.make_timeout(test.timeout());
+        test.retry(test_max_time);
+    }
+
     fn scenario_timed_out(&self) -> bool {
         let now = time::Instant::now();
         let scenario_elapsed_time = now.duration_since(self.start_time.lock().unwrap().clone());
@@ -652,8 +1053,31 @@ impl Scenario {
     /// Start running a scenario
     ///
     /// Start running a scenario.  If `working_directory` is specified,
-    /// then use that for all tests that don't specify one.
-    pub fn start(&self, working_directory: &Option<String>) {
+    /// then use that for all tests that don't specify one.  The scenario
+    /// is run `count` times if given, or else as many times as Repeat= says.
+    pub fn start(&self, working_directory: &Option<String>, count: Option<u32>) {
+        // Leave a run that's already going alone, even between iterations.
+        if self.is_running() {
+            self.log("NOT starting new scenario run, because one is already underway"
+                .to_string());
+            return;
+        }
+        *(self.iterations.lock().unwrap()) =
+            Iterations::new(count.unwrap_or(self.repeat), self.repeat_until_failure);
+        self.run(working_directory);
+    }
+
+    /// Run the next iteration of a repeated scenario, if one is due.
+    pub fn repeat(&self) {
+        if !self.iterations.lock().unwrap().take_pending() {
+            return;
+        }
+        let working_directory = self.working_directory.lock().unwrap().clone();
+        self.run(&working_directory);
+    }
+
+    /// Run the scenario once.
+    fn run(&self, working_directory: &Option<String>) {
         {
             let mut current_state = self.state.lock().unwrap();
             if *current_state != ScenarioState::Idle &&
@@ -663,23 +1087,43 @@ impl Scenario {
                                  *current_state));
                 return;
             }
+            self.controller.set_run_id(Some(Controller::new_run_id()));
             self.log("Starting new scenario run".to_string());
 
+            let iterations = self.iterations.lock().unwrap();
+            if iterations.is_repeating() {
+                self.broadcast(BroadcastMessageContents::Iteration(self.id().to_string(),
+                                                                   iterations.current(),
+                                                                   iterations.total()));
+            }
+
             // Reset the results so we can start afresh.
             for test in &self.tests {
                 test.lock().unwrap().pending();
             }
 
-            // Reset the number of errors to 0.
-            *(self.failures.lock().unwrap()) = 0;
+            self.resources.lock().unwrap().clear();
 
             // Save the current instant, so we can timeout as needed.
             *(self.start_time.lock().unwrap()) = time::Instant::now();
+            *(self.started.lock().unwrap()) = time::SystemTime::now();
 
             *current_state = ScenarioState::Idle;
         }
         *(self.working_directory.lock().unwrap()) = working_directory.clone();
-        self.advance();
+
+        // If any coupon can't be issued, refuse to run any tests at all.
+        // Otherwise, collect the variables each preflight command exported.
+        // advance() takes it from here once each command finishes.
+        *(self.environment.lock().unwrap()) = vec![];
+        *(self.preflight_result.lock().unwrap()) = None;
+        if !self.coupons.is_empty() {
+            *(self.state.lock().unwrap()) = ScenarioState::Preflight(0);
+            self.start_preflight(0);
+            return;
+        }
+
+        self.step();
     }
 
     // Broadcast a description of ourselves.
@@ -687,11 +1131,11 @@ impl Scenario {
         self.broadcast(BroadcastMessageContents::Describe(self.kind().to_string(),
                                                           "name".to_string(),
                                                           self.id().to_string(),
-                                                          self.name().to_string()));
+                                                          self.name.clone()));
         self.broadcast(BroadcastMessageContents::Describe(self.kind().to_string(),
                                                           "description".to_string(),
                                                           self.id().to_string(),
-                                                          self.description().to_string()));
+                                                          self.description.clone()));
 
         let test_names: Vec<String> =
             self.tests.iter().map(|x| x.lock().unwrap().id().to_string()).collect();
@@ -706,11 +1150,11 @@ impl Unit for Scenario {
     }
 
     fn name(&self) -> &str {
-        self.name.as_str()
+        self.name.default()
     }
 
     fn description(&self) -> &str {
-        self.description.as_str()
+        self.description.default()
     }
 
     fn id(&self) -> &str {
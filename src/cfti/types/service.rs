@@ -0,0 +1,339 @@
+extern crate runny;
+
+use std::cmp;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time;
+
+use cfti::types::unit::Unit;
+use cfti::controller::{Controller, BroadcastMessageContents};
+use cfti::process;
+use cfti::config;
+use cfti::testset;
+use cfti::unitfile::UnitFile;
+
+use self::runny::running::Running;
+
+/// The shortest wait before trying again to start a program that couldn't be
+/// started, so that e.g. a missing program with RestartSec=0 doesn't spin.
+const MIN_SPAWN_RETRY_MS: u64 = 100;
+
+/// The longest wait between attempts to start a program that keeps failing to start.
+const MAX_SPAWN_RETRY_SECS: u64 = 60;
+
+#[derive(Debug)]
+pub enum ServiceError {
+    FileLoadError(String),
+    MissingServiceSection,
+    MissingExecStart,
+    InvalidRestart(String),
+    ParseRestartSecError(String),
+}
+
+/// When to restart a long-running process that has exited.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RestartPolicy {
+    /// Always restart the process, no matter how it exited.
+    Always,
+
+    /// Only restart the process if it returned nonzero or was killed.
+    OnFailure,
+
+    /// Never restart the process.
+    No,
+}
+
+impl RestartPolicy {
+    pub fn from_str(s: &str) -> Option<RestartPolicy> {
+        match s.to_lowercase().as_ref() {
+            "always" => Some(RestartPolicy::Always),
+            "on-failure" => Some(RestartPolicy::OnFailure),
+            "no" => Some(RestartPolicy::No),
+            _ => None,
+        }
+    }
+
+    /// Decide whether to restart a process that exited with `result`.
+    /// A `result` of None means the process could not be started at all.
+    pub fn should_restart(&self, result: Option<i32>) -> bool {
+        match *self {
+            RestartPolicy::Always => true,
+            RestartPolicy::OnFailure => result != Some(0),
+            RestartPolicy::No => false,
+        }
+    }
+}
+
+/// How long to wait before restarting a program, given its RestartSec= and how
+/// many times in a row it couldn't be started.  Each failure to start doubles
+/// the wait, up to a minute.
+pub fn restart_delay(restart_sec: time::Duration, spawn_failures: u32) -> time::Duration {
+    if spawn_failures == 0 {
+        return restart_sec;
+    }
+    let backoff = MIN_SPAWN_RETRY_MS << cmp::min(spawn_failures - 1, 10);
+    let backoff = cmp::min(time::Duration::from_millis(backoff),
+                           time::Duration::from_secs(MAX_SPAWN_RETRY_SECS));
+    cmp::max(restart_sec, backoff)
+}
+
+#[derive(Debug)]
+pub struct Service {
+    /// id: The string that other units refer to this file as.
+    id: String,
+
+    /// name: Display name of this service.
+    name: String,
+
+    /// description: Paragraph describing this service.
+    description: String,
+
+    /// exec_start: The command to run as the service.
+    exec_start: String,
+
+    /// working_directory: The path where exec_start will be run from.
+    working_directory: Option<String>,
+
+    /// restart: What to do when the service exits.
+    restart: RestartPolicy,
+
+    /// restart_sec: How long to wait before restarting the service.
+    restart_sec: time::Duration,
+
+    /// How long we can let process.terminate() run for
+    termination_timeout: time::Duration,
+
+    /// The controller where messages come and go.
+    controller: Controller,
+
+    /// The actual, running process
+    process: Arc<Mutex<Option<Running>>>,
+
+    /// Set when the service has been asked to stop, so it won't get restarted.
+    stopping: Arc<Mutex<bool>>,
+}
+
+impl Service {
+    pub fn new(id: &str,
+               path: &str,
+               test_set: &testset::TestSet,
+               config: &config::Config)
+               -> Option<Result<Service, ServiceError>> {
+
+        // Load the .ini file
+        let unitfile = match UnitFile::new(path) {
+            Err(e) => return Some(Err(ServiceError::FileLoadError(format!("{:?}", e)))),
+            Ok(s) => s,
+        };
+
+        if !unitfile.has_section("Service") {
+            return Some(Err(ServiceError::MissingServiceSection));
+        }
+
+        // Check to see if this service is compatible with this jig.
+        match unitfile.get("Service", "Jigs") {
+            None => (),
+            Some(s) => {
+                let jigs = test_set.jigs();
+                let jig_names: Vec<String> =
+                    s.split(|c| c == ',' || c == ' ').map(|s| s.to_string()).collect();
+                let mut found_it = false;
+                for jig_name in jig_names {
+                    if jigs.get(&jig_name).is_some() {
+                        found_it = true;
+                        break;
+                    }
+                }
+                if found_it == false {
+                    test_set.debug(format!("The service '{}' is not compatible with this jig", id));
+                    return None;
+                }
+            }
+        }
+
+        let description = match unitfile.get_localized("Service", "Description", config.locale()) {
+            None => "".to_string(),
+            Some(s) => s.to_string(),
+        };
+
+        let name = match unitfile.get_localized("Service", "Name", config.locale()) {
+            None => id.to_string(),
+            Some(s) => s.to_string(),
+        };
+
+        let exec_start = match unitfile.get("Service", "ExecStart") {
+            None => return Some(Err(ServiceError::MissingExecStart)),
+            Some(s) => s.to_string(),
+        };
+
+        let working_directory = match unitfile.get("Service", "WorkingDirectory") {
+            None => config.default_working_directory().clone(),
+            Some(s) => Some(s.to_string()),
+        };
+
+        let restart = match unitfile.get("Service", "Restart") {
+            None => RestartPolicy::No,
+            Some(s) => {
+                match RestartPolicy::from_str(s) {
+                    Some(r) => r,
+                    None => return Some(Err(ServiceError::InvalidRestart(s.to_string()))),
+                }
+            }
+        };
+
+        let restart_sec = match unitfile.get("Service", "RestartSec") {
+            None => time::Duration::from_secs(1),
+            Some(s) => {
+                match s.parse() {
+                    Err(_) => return Some(Err(ServiceError::ParseRestartSecError(s.to_string()))),
+                    Ok(n) => time::Duration::from_secs(n),
+                }
+            }
+        };
+
+        Some(Ok(Service {
+            id: id.to_string(),
+            name: name,
+            description: description,
+            exec_start: exec_start,
+            working_directory: working_directory,
+            restart: restart,
+            restart_sec: restart_sec,
+            termination_timeout: config.default_termination_timeout().clone(),
+            controller: test_set.controller().clone(),
+            process: Arc::new(Mutex::new(None)),
+            stopping: Arc::new(Mutex::new(false)),
+        }))
+    }
+
+    /// Start the service, and keep it running according to its restart policy.
+    ///
+    /// The service is supervised from its own thread, and lives independently
+    /// of any scenario.  Every change in state is broadcast as a STATUS message.
+    pub fn start(&self, working_directory: &Option<String>) {
+        let working_directory = match self.working_directory {
+            Some(ref s) => Some(s.clone()),
+            None => working_directory.clone(),
+        };
+
+        let unit = self.to_simple_unit();
+        let cmd = self.exec_start.clone();
+        let restart = self.restart.clone();
+        let restart_sec = self.restart_sec;
+        let termination_timeout = self.termination_timeout;
+        let thr_process = self.process.clone();
+        let stopping = self.stopping.clone();
+
+        // How many times in a row the service couldn't be started.
+        let mut spawn_failures = 0;
+
+        let builder = thread::Builder::new().name(format!("S-{}", self.id()).into());
+        builder.spawn(move || loop {
+                let result = match process::spawn_cmd(cmd.as_str(), &unit, &working_directory, &[]) {
+                    Err(e) => {
+                        unit.broadcast(BroadcastMessageContents::Status(unit.id().to_string(),
+                                                                        "failed".to_string(),
+                                                                        format!("{:?}", e)));
+                        spawn_failures = spawn_failures + 1;
+                        None
+                    }
+                    Ok(mut running) => {
+                        spawn_failures = 0;
+                        process::log_output(running.take_output(), &unit, "stdout").unwrap();
+                        process::log_output(running.take_error(), &unit, "stderr").unwrap();
+                        let waiter = running.waiter();
+
+                        // stop() may have been called while the process was starting,
+                        // before there was anything for it to terminate.
+                        {
+                            let mut process = thr_process.lock().unwrap();
+                            if *(stopping.lock().unwrap()) {
+                                if let Err(e) = running.terminate(Some(termination_timeout)) {
+                                    unit.debug(format!("Unable to terminate service: {:?}", e));
+                                }
+                                unit.broadcast(BroadcastMessageContents::Status(
+                                    unit.id().to_string(),
+                                    "stopped".to_string(),
+                                    "".to_string()));
+                                return;
+                            }
+                            *process = Some(running);
+                        }
+
+                        unit.broadcast(BroadcastMessageContents::Status(unit.id().to_string(),
+                                                                        "running".to_string(),
+                                                                        "".to_string()));
+                        let result = waiter.result();
+                        thr_process.lock().unwrap().take();
+                        Some(result)
+                    }
+                };
+
+                if *(stopping.lock().unwrap()) {
+                    unit.broadcast(BroadcastMessageContents::Status(unit.id().to_string(),
+                                                                    "stopped".to_string(),
+                                                                    "".to_string()));
+                    return;
+                }
+
+                let reason = match result {
+                    None => "unable to start".to_string(),
+                    Some(r) => format!("exited with code {}", r),
+                };
+
+                if !restart.should_restart(result) {
+                    let state = if result == Some(0) { "exited" } else { "failed" };
+                    unit.broadcast(BroadcastMessageContents::Status(unit.id().to_string(),
+                                                                    state.to_string(),
+                                                                    reason));
+                    return;
+                }
+
+                unit.broadcast(BroadcastMessageContents::Status(unit.id().to_string(),
+                                                                "restarting".to_string(),
+                                                                reason));
+                thread::sleep(restart_delay(restart_sec, spawn_failures));
+
+                if *(stopping.lock().unwrap()) {
+                    return;
+                }
+            })
+            .unwrap();
+    }
+
+    /// Stop the service, and don't restart it.
+    ///
+    /// `stopping` is set before looking at the process, and the supervisor checks it
+    /// while holding the process lock, so a process that's just been spawned is
+    /// either terminated here or by the supervisor.
+    pub fn stop(&self) {
+        *(self.stopping.lock().unwrap()) = true;
+        if let Some(ref mut p) = *(self.process.lock().unwrap()) {
+            if let Err(e) = p.terminate(Some(self.termination_timeout)) {
+                self.debug(format!("Unable to terminate service: {:?}", e));
+            }
+        }
+    }
+}
+
+impl Unit for Service {
+    fn id(&self) -> &str {
+        self.id.as_str()
+    }
+
+    fn kind(&self) -> &str {
+        "service"
+    }
+
+    fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    fn description(&self) -> &str {
+        self.description.as_str()
+    }
+
+    fn controller(&self) -> &Controller {
+        &self.controller
+    }
+}
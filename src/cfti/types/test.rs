@@ -5,10 +5,13 @@ extern crate runny;
 use self::regex::Regex;
 use self::runny::running::Running;
 
+use std::collections::BTreeMap;
+use std::fmt;
 use std::sync::{Arc, Mutex};
 use std::time;
 use std::thread;
-use std::io::{self, BufRead};
+use std::io::{self, BufRead, Write};
+use std::path;
 
 use cfti::types::Unit;
 use cfti::controller::{Controller, BroadcastMessageContents, ControlMessageContents};
@@ -16,6 +19,16 @@ use cfti::process;
 use cfti::config;
 use cfti::testset;
 use cfti::unitfile::UnitFile;
+use cfti::locale::LocalizedString;
+use cfti::measurement::{Limit, Measurement};
+use cfti::resultpipe::ResultPipe;
+use cfti::resources::ResourceClaim;
+
+/// The most output to keep from each stream of a test run, for reports.
+const MAX_CAPTURED_OUTPUT: usize = 65536;
+
+/// The longest RetryDelay= a test may ask for, in seconds.
+const MAX_RETRY_DELAY_SECS: f64 = 3600.0;
 
 #[derive(Debug)]
 pub enum TestError {
@@ -25,6 +38,11 @@ pub enum TestError {
     ParseTimeoutError,
     InvalidType(String),
     DaemonReadyTextError,
+    InvalidLimit(String),
+    InvalidResource(String),
+    ParseRetriesError,
+    ParseRetryDelayError,
+    RetryOnError(String),
 }
 
 #[derive(Debug, PartialEq)]
@@ -33,6 +51,35 @@ enum TestType {
     Daemon,
 }
 
+/// RetryOn: Which failures are worth another attempt.
+#[derive(Debug, Clone)]
+enum RetryOn {
+    /// Any failure at all.
+    Any,
+
+    /// The test exited with one of these codes.  0 covers a test that exited
+    /// cleanly, but whose measurements were out of limits.
+    ExitCodes(Vec<i32>),
+
+    /// The test's result message matched this.
+    LastLine(Regex),
+}
+
+impl RetryOn {
+    fn matches(&self, exit_code: Option<i32>, message: &str) -> bool {
+        match *self {
+            RetryOn::Any => true,
+            RetryOn::ExitCodes(ref codes) => {
+                match exit_code {
+                    None => false,
+                    Some(c) => codes.contains(&c),
+                }
+            }
+            RetryOn::LastLine(ref r) => r.is_match(message),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum TestState {
     /// A test has yet to be run.
@@ -60,10 +107,10 @@ pub struct Test {
     id: String,
 
     /// Name: Defines the short name for this test.
-    name: String,
+    name: LocalizedString,
 
     /// Description: Defines a detailed description of this test.  May be up to one paragraph.
-    description: String,
+    description: LocalizedString,
 
     /// Requires: The name of a test that must successfully complete
     requires: Vec<String>,
@@ -74,9 +121,28 @@ pub struct Test {
     /// Provides is a list of tests that this can be referred to when "Requiring" or "Suggesting" tests.
     provides: Vec<String>,
 
+    /// Resources: Things this test can't share with other tests running at the same time.
+    resources: Vec<ResourceClaim>,
+
     /// Timeout: The maximum number of seconds that this test may be run for.
     timeout: time::Duration,
 
+    /// Retries: How many more times to run a simple test that fails, before giving up.
+    retries: u32,
+
+    /// RetryDelay: How long to wait before running the test again.
+    retry_delay: time::Duration,
+
+    /// RetryOn: Which failures to retry.
+    retry_on: RetryOn,
+
+    /// Which attempt at the test is running, starting at 1.
+    attempt: Arc<Mutex<u32>>,
+
+    /// Bumped whenever the test is skipped or reset, which cancels any retry
+    /// that's still waiting out its RetryDelay.
+    retry_generation: Arc<Mutex<u32>>,
+
     /// The maximum amount of time to allow an ExecStopSuccess to run
     exec_stop_success_timeout: time::Duration,
 
@@ -118,8 +184,271 @@ pub struct Test {
     /// The working directory for the current test.
     test_working_directory: Arc<Mutex<Option<String>>>,
 
+    /// Extra environment variables for the current test, e.g. from a coupon.
+    test_environment: Arc<Mutex<process::Environment>>,
+
     /// How long we can let process.kill() run for
     termination_timeout: time::Duration,
+
+    /// When the most recent run of this test was started.
+    start_time: Arc<Mutex<Option<time::SystemTime>>>,
+
+    /// When the most recent run of this test reached a result.
+    finish_time: Arc<Mutex<Option<time::SystemTime>>>,
+
+    /// What the most recent run of this test printed to stdout.
+    stdout: Arc<Mutex<String>>,
+
+    /// What the most recent run of this test printed to stderr.
+    stderr: Arc<Mutex<String>>,
+
+    /// Limit.[name]: Measurements, and the limits they must fall within.
+    measurements: Arc<Mutex<Measurements>>,
+
+    /// The pipe the current run writes result records to, on fd 3.
+    result_pipe: Arc<Mutex<Option<ResultPipe>>>,
+
+    /// The message from the most recent RESULT record, which takes the place of last_line.
+    result_message: Arc<Mutex<Option<String>>>,
+
+    /// Questions the current run has asked the operator.
+    prompts: Arc<Mutex<Prompts>>,
+}
+
+/// The questions a test run asks the operator, and where the answers go.
+struct Prompts {
+    /// How many prompts have been made, used to give each one a unique id.
+    count: u32,
+
+    /// The id of the prompt waiting on an answer, if there is one.
+    pending: Option<String>,
+
+    /// The valid answers to the pending prompt.  Empty for free text.
+    choices: Vec<String>,
+
+    /// The test's stdin, which answers are written to.
+    stdin: Option<Box<Write + Send>>,
+
+    /// When the run will time out.  Prompts expire along with the test.
+    deadline: Option<time::Instant>,
+}
+
+// The test's stdin can't be printed.
+impl fmt::Debug for Prompts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Prompts({:?}, {:?})", self.pending, self.choices)
+    }
+}
+
+impl Prompts {
+    fn start(&mut self, deadline: time::Instant) {
+        self.pending = None;
+        self.choices.clear();
+        self.stdin = None;
+        self.deadline = Some(deadline);
+    }
+
+    /// Forget any pending prompt, and close the test's stdin.
+    fn finish(&mut self) {
+        self.pending = None;
+        self.choices.clear();
+        self.stdin = None;
+        self.deadline = None;
+    }
+
+    /// Broadcast a "PROMPT [choices|-] [text]" record from the result pipe.
+    fn ask(&mut self, unit: &Unit, args: &str) {
+        let (choices, text) = match args.find(' ') {
+            None => (args, ""),
+            Some(i) => (&args[..i], args[i + 1..].trim()),
+        };
+        let choices: Vec<String> = match choices {
+            "" | "-" => vec![],
+            c => c.split(',').filter(|c| !c.is_empty()).map(|c| c.to_string()).collect(),
+        };
+
+        let now = time::Instant::now();
+        let timeout = match self.deadline {
+            Some(deadline) if deadline > now => deadline.duration_since(now).as_secs(),
+            _ => 0,
+        };
+
+        self.count = self.count + 1;
+        let id = format!("{}-{}", unit.id(), self.count);
+        if let Some(ref old) = self.pending {
+            unit.debug(format!("Prompt {} replaces unanswered prompt {}", id, old));
+        }
+        self.pending = Some(id.clone());
+        self.choices = choices.clone();
+        unit.broadcast(BroadcastMessageContents::Prompt(id, timeout, choices, text.to_string()));
+    }
+
+    /// Write `answer` to the test's stdin, if it's waiting on prompt `id`.
+    /// Returns false if the prompt isn't this test's.
+    fn answer(&mut self, unit: &Unit, id: &str, answer: &str) -> bool {
+        if self.pending.as_ref().map(|p| p.as_str()) != Some(id) {
+            return false;
+        }
+
+        // Choices are matched without regard to case, but the test gets its own spelling.
+        let answer = if self.choices.is_empty() {
+            answer.to_string()
+        } else {
+            match self.choices.iter().find(|c| c.to_lowercase() == answer.to_lowercase()) {
+                None => {
+                    unit.warn(format!("{} is not an answer to prompt {}, expected one of: {}",
+                                      answer,
+                                      id,
+                                      self.choices.join(", ")));
+                    return true;
+                }
+                Some(c) => c.clone(),
+            }
+        };
+
+        self.pending = None;
+        self.choices.clear();
+        match self.stdin {
+            None => unit.warn(format!("Unable to answer prompt {}: stdin is closed", id)),
+            Some(ref mut stdin) => {
+                if let Err(e) = writeln!(stdin, "{}", answer).and_then(|_| stdin.flush()) {
+                    unit.warn(format!("Unable to answer prompt {}: {}", id, e));
+                    return true;
+                }
+                unit.log(format!("Prompt {} answered: {}", id, answer));
+            }
+        }
+        true
+    }
+}
+
+/// The measurements taken by a test run, and the limits to check them against.
+#[derive(Debug)]
+struct Measurements {
+    limits: BTreeMap<String, Limit>,
+
+    /// Every measurement taken during the current run.
+    taken: Vec<Measurement>,
+
+    /// The first reason the current run broke its limits, if it did.
+    failure: Option<String>,
+}
+
+impl Measurements {
+    fn reset(&mut self) {
+        self.taken.clear();
+        self.failure = None;
+    }
+
+    /// If `line` is a measurement, check it against its limit, broadcast it,
+    /// and keep it.  Returns false if the line wasn't a measurement.
+    fn record(&mut self, unit: &Unit, line: &str) -> bool {
+        let mut measurement = match Measurement::parse(unit.id(), line) {
+            None => return false,
+            Some(Err(e)) => {
+                let msg = format!("Invalid measurement: {:?}", e);
+                unit.log(msg.clone());
+                if self.failure.is_none() {
+                    self.failure = Some(msg);
+                }
+                return true;
+            }
+            Some(Ok(m)) => m,
+        };
+
+        measurement.limit = self.limits.get(&measurement.name).cloned();
+        if !measurement.in_limits() && self.failure.is_none() {
+            self.failure = Some(format!("{} is {} {}, outside of {}",
+                                        measurement.name,
+                                        measurement.value,
+                                        measurement.unit,
+                                        measurement.limit.as_ref().unwrap()));
+        }
+        unit.broadcast_class("result", BroadcastMessageContents::Measure(measurement.clone()));
+        self.taken.push(measurement);
+        true
+    }
+
+    /// Returns why the run should fail, either because a measurement was out
+    /// of limits, or because something with a limit was never measured.
+    fn failure(&self) -> Option<String> {
+        if let Some(ref f) = self.failure {
+            return Some(f.clone());
+        }
+        for name in self.limits.keys() {
+            if !self.taken.iter().any(|m| &m.name == name) {
+                return Some(format!("{} was never measured", name));
+            }
+        }
+        None
+    }
+}
+
+/// Handle one line from a test's result pipe.  See "Test -- Result Pipe" in doc/IPC.md.
+fn result_record(line: String,
+                 unit: &Unit,
+                 measurements: &Arc<Mutex<Measurements>>,
+                 result_message: &Arc<Mutex<Option<String>>>,
+                 prompts: &Arc<Mutex<Prompts>>,
+                 working_directory: &Option<String>) {
+    let (verb, rest) = match line.find(' ') {
+        None => (line.as_str(), ""),
+        Some(i) => (&line[..i], line[i + 1..].trim()),
+    };
+
+    match verb {
+        "PROGRESS" => {
+            let (percent, message) = match rest.find(' ') {
+                None => (rest, ""),
+                Some(i) => (&rest[..i], rest[i + 1..].trim()),
+            };
+            match percent.parse::<u32>() {
+                Err(_) => unit.debug(format!("Invalid PROGRESS percentage: {}", percent)),
+                Ok(p) => {
+                    unit.broadcast(BroadcastMessageContents::Progress(unit.id().to_string(),
+                                                                      if p > 100 { 100 } else { p },
+                                                                      message.to_string()))
+                }
+            }
+        }
+        "MEASURE" => {
+            measurements.lock().unwrap().record(unit, &line);
+        }
+        "RESULT" => *(result_message.lock().unwrap()) = Some(rest.to_string()),
+        "ATTACH" => {
+            let (path, description) = match rest.find(' ') {
+                None => (rest, ""),
+                Some(i) => (&rest[..i], rest[i + 1..].trim()),
+            };
+            if path.is_empty() {
+                unit.debug(format!("ATTACH is missing a path"));
+                return;
+            }
+
+            // Relative paths are relative to where the test ran.
+            let path = match *working_directory {
+                Some(ref wd) if !path::Path::new(path).is_absolute() => {
+                    path::Path::new(wd).join(path).to_string_lossy().into_owned()
+                }
+                _ => path.to_string(),
+            };
+            unit.broadcast(BroadcastMessageContents::Attach(unit.id().to_string(),
+                                                            path,
+                                                            description.to_string()));
+        }
+        "PROMPT" => prompts.lock().unwrap().ask(unit, rest),
+        "LOG" => unit.log(rest.to_string()),
+        _ => unit.debug(format!("Unrecognized result pipe record: {}", line)),
+    }
+}
+
+/// Add `line` to the output captured for a report, unless there's already too much.
+fn capture_output(buffer: &Arc<Mutex<String>>, line: &str) {
+    let mut buffer = buffer.lock().unwrap();
+    if buffer.len() + line.len() < MAX_CAPTURED_OUTPUT {
+        buffer.push_str(line);
+        buffer.push('\n');
+    }
 }
 
 impl Test {
@@ -215,14 +544,14 @@ impl Test {
             Some(s) => Some(s.to_string()),
         };
 
-        let description = match unitfile.get("Test", "Description") {
-            None => "".to_string(),
-            Some(s) => s.to_string(),
+        let description = match unitfile.get_localized_string("Test", "Description") {
+            None => LocalizedString::new(""),
+            Some(s) => s,
         };
 
-        let name = match unitfile.get("Test", "Name") {
-            None => id.to_string(),
-            Some(s) => s.to_string(),
+        let name = match unitfile.get_localized_string("Test", "Name") {
+            None => LocalizedString::new(id),
+            Some(s) => s,
         };
 
         let timeout = match unitfile.get("Test", "Timeout") {
@@ -235,6 +564,46 @@ impl Test {
             }
         };
 
+        let retries = match unitfile.get("Test", "Retries") {
+            None => 0,
+            Some(s) => {
+                match s.trim().parse() {
+                    Err(_) => return Some(Err(TestError::ParseRetriesError)),
+                    Ok(n) => n,
+                }
+            }
+        };
+
+        let retry_delay = match unitfile.get("Test", "RetryDelay") {
+            None => time::Duration::from_secs(0),
+            Some(s) => {
+                match s.trim().parse::<f64>() {
+                    Ok(n) if n.is_finite() && n >= 0.0 && n <= MAX_RETRY_DELAY_SECS => {
+                        time::Duration::from_millis((n * 1000.0) as u64)
+                    }
+                    _ => return Some(Err(TestError::ParseRetryDelayError)),
+                }
+            }
+        };
+
+        // A list of exit codes, or else a regex to match against the result message.
+        let retry_on = match unitfile.get("Test", "RetryOn") {
+            None => RetryOn::Any,
+            Some(s) => {
+                let codes: Vec<&str> = s.split(|c| c == ',' || c == ' ')
+                    .filter(|x| !x.is_empty())
+                    .collect();
+                if !codes.is_empty() && codes.iter().all(|c| c.parse::<i32>().is_ok()) {
+                    RetryOn::ExitCodes(codes.iter().map(|c| c.parse().unwrap()).collect())
+                } else {
+                    match Regex::new(s) {
+                        Ok(r) => RetryOn::LastLine(r),
+                        Err(e) => return Some(Err(TestError::RetryOnError(format!("{}", e)))),
+                    }
+                }
+            }
+        };
+
         // Get a list of all the requirements, or make a blank list
         let requires = match unitfile.get("Test", "Requires") {
             None => Vec::new(),
@@ -280,6 +649,24 @@ impl Test {
             }
         };
 
+        let mut resources = vec![];
+        if let Some(s) = unitfile.get("Test", "Resources") {
+            for name in s.split(|c| c == ',' || c == ' ').filter(|x| !x.is_empty()) {
+                match ResourceClaim::from_str(name) {
+                    None => return Some(Err(TestError::InvalidResource(name.to_string()))),
+                    Some(r) => resources.push(r),
+                }
+            }
+        }
+
+        let mut limits = BTreeMap::new();
+        for (name, value) in unitfile.get_prefixed("Test", "Limit.") {
+            match Limit::from_str(&value) {
+                None => return Some(Err(TestError::InvalidLimit(name))),
+                Some(l) => limits.insert(name, l),
+            };
+        }
+
         Some(Ok(Test {
             id: id.to_string(),
             name: name,
@@ -288,12 +675,18 @@ impl Test {
             requires: requires,
             suggests: suggests,
             provides: provides,
+            resources: resources,
 
             test_type: test_type,
             test_daemon_ready: test_daemon_ready,
             test_process: Arc::new(Mutex::new(None)),
 
             timeout: timeout,
+            retries: retries,
+            retry_delay: retry_delay,
+            retry_on: retry_on,
+            attempt: Arc::new(Mutex::new(1)),
+            retry_generation: Arc::new(Mutex::new(0)),
             exec_start: exec_start,
             exec_stop_success: exec_stop_success,
             exec_stop_success_timeout: config.test_success_timeout(),
@@ -301,6 +694,7 @@ impl Test {
             exec_stop_failure_timeout: config.test_failure_timeout(),
             working_directory: working_directory,
             test_working_directory: Arc::new(Mutex::new(None)),
+            test_environment: Arc::new(Mutex::new(vec![])),
 
             controller: test_set.controller().clone(),
 
@@ -308,6 +702,25 @@ impl Test {
             state: Arc::new(Mutex::new(TestState::Pending)),
 
             termination_timeout: config.default_termination_timeout().clone(),
+
+            start_time: Arc::new(Mutex::new(None)),
+            finish_time: Arc::new(Mutex::new(None)),
+            stdout: Arc::new(Mutex::new(String::new())),
+            stderr: Arc::new(Mutex::new(String::new())),
+            measurements: Arc::new(Mutex::new(Measurements {
+                limits: limits,
+                taken: vec![],
+                failure: None,
+            })),
+            result_pipe: Arc::new(Mutex::new(None)),
+            result_message: Arc::new(Mutex::new(None)),
+            prompts: Arc::new(Mutex::new(Prompts {
+                count: 0,
+                pending: None,
+                choices: vec![],
+                stdin: None,
+                deadline: None,
+            })),
         }))
     }
 
@@ -315,12 +728,11 @@ impl Test {
         self.broadcast(BroadcastMessageContents::Describe(self.kind().to_string(),
                                                           "name".to_string(),
                                                           self.id().to_string(),
-                                                          self.name().to_string()));
+                                                          self.name.clone()));
         self.broadcast(BroadcastMessageContents::Describe(self.kind().to_string(),
                                                           "description".to_string(),
                                                           self.id().to_string(),
-                                                          self.description()
-                                                              .to_string()));
+                                                          self.description.clone()));
     }
 
     pub fn timeout(&self) -> time::Duration {
@@ -331,10 +743,16 @@ impl Test {
     ///
     /// Start running a test.  If `working_directory` is specified and
     /// there is no WorkingDirectory in this test, use the provided one.
+    /// Every command run on behalf of this test gets `environment` added
+    /// to its environment.
     pub fn start(&self,
                  scenario_working_directory: &Option<String>,
+                 environment: &process::Environment,
                  max_duration: time::Duration) {
         self.broadcast(BroadcastMessageContents::Running(self.id().to_string()));
+        *(self.start_time.lock().unwrap()) = Some(time::SystemTime::now());
+        *(self.finish_time.lock().unwrap()) = None;
+        *(self.attempt.lock().unwrap()) = 1;
 
         let test_working_directory = match self.working_directory {
             None => {
@@ -347,137 +765,303 @@ impl Test {
         };
 
         *(self.test_working_directory.lock().unwrap()) = test_working_directory.clone();
+        *(self.test_environment.lock().unwrap()) = environment.clone();
+        self.launch(&test_working_directory, environment, max_duration);
+    }
+
+    /// Run the test again after an attempt failed in a way that RetryOn allows,
+    /// in the same directory and with the same environment as before.
+    pub fn retry(&self, max_duration: time::Duration) {
+        let attempt = {
+            let mut attempt = self.attempt.lock().unwrap();
+            *attempt = *attempt + 1;
+            *attempt
+        };
+        *(self.finish_time.lock().unwrap()) = None;
+        self.log(format!("Starting attempt {} of {}", attempt, self.retries + 1));
+
+        let working_directory = self.test_working_directory.lock().unwrap().clone();
+        let environment = self.test_environment.lock().unwrap().clone();
+        self.launch(&working_directory, &environment, max_duration);
+    }
+
+    /// Fail a test that was waiting to be retried, but can't be.
+    pub fn give_up(&self, reason: String) {
+        *(self.state.lock().unwrap()) = TestState::Fail(reason.clone());
+        *(self.finish_time.lock().unwrap()) = Some(time::SystemTime::now());
+        self.broadcast_class("result",
+                             BroadcastMessageContents::Fail(self.id().to_string(), reason));
+        self.control_class("result", ControlMessageContents::AdvanceScenario);
+    }
+
+    /// Run one attempt at the test.
+    fn launch(&self,
+              test_working_directory: &Option<String>,
+              environment: &process::Environment,
+              max_duration: time::Duration) {
+        self.last_line.lock().unwrap().clear();
+        self.stdout.lock().unwrap().clear();
+        self.stderr.lock().unwrap().clear();
+        self.measurements.lock().unwrap().reset();
+        *(self.result_message.lock().unwrap()) = None;
+        self.prompts.lock().unwrap().start(time::Instant::now() + max_duration);
+
+        // Give ExecStart a result pipe on fd 3.  If one can't be made, the test
+        // still runs, and can report its result the old way.  The pipe must be
+        // watched before the test starts, because the test can't open it until
+        // something is reading it.
+        let mut cmd = self.exec_start.clone();
+        let mut cmd_environment = environment.clone();
+        match ResultPipe::new(self.id()) {
+            Err(e) => self.debug(format!("Unable to create result pipe: {}", e)),
+            Ok(mut pipe) => {
+                match self.watch_result_pipe(&mut pipe, test_working_directory) {
+                    Err(e) => {
+                        self.debug(format!("Unable to watch result pipe: {}", e));
+                        pipe.close();
+                    }
+                    Ok(_) => {
+                        cmd = ResultPipe::wrap_command(&cmd);
+                        cmd_environment.push(pipe.environment());
+                        *(self.result_pipe.lock().unwrap()) = Some(pipe);
+                    }
+                }
+            }
+        }
 
         match self.test_type {
-            TestType::Simple => self.start_simple(&test_working_directory, max_duration),
-            TestType::Daemon => self.start_daemon(&test_working_directory, max_duration),
+            TestType::Simple => {
+                self.start_simple(&cmd, &cmd_environment, test_working_directory, max_duration)
+            }
+            TestType::Daemon => {
+                self.start_daemon(&cmd, &cmd_environment, test_working_directory, max_duration)
+            }
         }
     }
 
-    fn start_daemon(&self, working_directory: &Option<String>, max_duration: time::Duration) {
+    /// Start reading the result pipe.
+    fn watch_result_pipe(&self,
+                         pipe: &mut ResultPipe,
+                         working_directory: &Option<String>)
+                         -> io::Result<()> {
+        let thr_measurements = self.measurements.clone();
+        let thr_result_message = self.result_message.clone();
+        let thr_prompts = self.prompts.clone();
+        let thr_working_directory = working_directory.clone();
+        pipe.watch(self, move |line, unit| {
+            result_record(line,
+                          unit,
+                          &thr_measurements,
+                          &thr_result_message,
+                          &thr_prompts,
+                          &thr_working_directory);
+            Ok(())
+        })
+    }
 
-        let result = self.state.clone();
-        let id = self.id().to_string();
+    fn start_daemon(&self,
+                    cmd: &str,
+                    environment: &process::Environment,
+                    working_directory: &Option<String>,
+                    max_duration: time::Duration) {
 
         // Indicate the daemon is beginning it startup.
         *(self.state.lock().unwrap()) = TestState::Starting;
 
         // Try to launch the daemon.  If it fails, report the error immediately and return.
         let mut running =
-            match process::spawn_cmd(self.exec_start.as_str(), self, working_directory) {
+            match process::spawn_cmd(cmd, self, working_directory, environment) {
                 Err(e) => {
                     let msg = format!("{:?}", e);
-                    *(result.lock().unwrap()) = TestState::Fail(msg.clone());
-                    BroadcastMessageContents::Fail(id, msg);
+                    *(self.state.lock().unwrap()) = TestState::Fail(msg.clone());
+                    *(self.finish_time.lock().unwrap()) = Some(time::SystemTime::now());
+                    let pipe = self.result_pipe.lock().unwrap().take();
+                    if let Some(mut pipe) = pipe {
+                        pipe.close();
+                    }
+                    self.broadcast(BroadcastMessageContents::Fail(self.id().to_string(), msg));
+                    self.control_class("result", ControlMessageContents::AdvanceScenario);
                     return;
                 }
                 Ok(o) => o,
             };
 
+        // Answers to prompts are written to stdin.
+        self.prompts.lock().unwrap().stdin = Some(Box::new(running.take_input()));
+
         // Hook up stderr right away, because we'll be looking for the output on stdout.
         // XXX stderr doesn't exist anymore.  This API needs to be brought back, though.
         // process::log_output(running, self, "stderr");
 
-        // Wait until the "match" string appears.
         let thr_waiter = running.waiter();
         let term_waiter = running.waiter();
-
         let mut buf_reader = io::BufReader::new(running.take_output());
-        if let Some(ref r) = self.test_daemon_ready {
-            // Fire off a thread to kill the process if it takes too long to start.
-            let thr_state = self.state.clone();
-            let thr_end = self.exec_stop_failure.clone();
-            let thr_end_timeout = self.exec_stop_failure_timeout.clone();
-            let thr_dir = self.test_working_directory.clone();
-            let unit = self.to_simple_unit();
-            let thr =
-                thread::spawn(move || {
-                    thread::park_timeout(max_duration);
-                    if *(thr_state.lock().unwrap()) == TestState::Starting {
-                        let msg = format!("Test daemon never came ready");
-                        *(thr_state.lock().unwrap()) = TestState::Fail(msg.clone());
-                        unit.broadcast(BroadcastMessageContents::Log(msg));
-                        thr_waiter.terminate(&None);
-
-                        if let Some(cmd) = thr_end {
-                            unit.broadcast(BroadcastMessageContents::Log(format!("Running post-test command: {}", cmd)));
-                            let dir = thr_dir.lock().unwrap();
-                            process::try_command(&unit, cmd.as_str(), &*dir, thr_end_timeout);
-                        }
-                    }
-                });
 
-            // Wait for the string to appear.
-            self.log(format!("Waiting for string: {}", r));
-            loop {
-                let mut line = String::new();
-                match buf_reader.read_line(&mut line) {
-                    Err(e) => {
-                        let msg = format!("Error in interface: {:?}", e);
-                        self.log(msg.clone());
-                        *(self.state.lock().unwrap()) = TestState::Fail(msg.clone());
-                        self.broadcast(BroadcastMessageContents::Fail(self.id().to_string(), msg));
-                        thr.thread().unpark();
-                        self.control_class("result", ControlMessageContents::AdvanceScenario);
-                        return;
-                    }
-                    Ok(0) => {
-                        let msg = format!("Test daemon exited");
-                        self.log(msg.clone());
-                        *(self.state.lock().unwrap()) = TestState::Fail(msg.clone());
-                        self.broadcast(BroadcastMessageContents::Fail(self.id().to_string(), msg));
-                        thr.thread().unpark();
-                        self.control_class("result", ControlMessageContents::AdvanceScenario);
-                        return;
-                    }
-                    Ok(_) => {
-                        self.broadcast_class("stdout", BroadcastMessageContents::Log(line.clone()));
-                        if r.is_match(line.as_str()) {
-                            *(self.state.lock().unwrap()) = TestState::Running;
-                            break;
-                        }
-                    }
-                }
-                line.clear();
-            }
-            // Now that the match string has been found (if any), mark the daemon as "Running".
-            thr.thread().unpark();
-        } else {
-            *(self.state.lock().unwrap()) = TestState::Running;
-        }
-
-        process::log_output(buf_reader, self, "stdout").unwrap();
+        // Keep the child now, so that it can be terminated even before it's ready.
         *(self.test_process.lock().unwrap()) = Some(running);
 
-        // Move the child into its own thread and wait for it to terminate.
-        // If we're still in the "Running" state when it quits, then the daemon
-        // has failed.
+        // Waiting for the "match" string can take as long as max_duration, so do
+        // it on a thread of its own, rather than holding up the scenario.
+        let ready = self.test_daemon_ready.clone();
         let thr_state = self.state.clone();
+        let thr_finish_time = self.finish_time.clone();
+        let thr_stdout = self.stdout.clone();
+        let thr_measurements = self.measurements.clone();
+        let thr_result_pipe = self.result_pipe.clone();
+        let thr_result_message = self.result_message.clone();
+        let thr_prompts = self.prompts.clone();
+        let thr_end = self.exec_stop_failure.clone();
+        let thr_end_timeout = self.exec_stop_failure_timeout.clone();
+        let thr_dir = self.test_working_directory.clone();
+        let thr_environment = self.test_environment.lock().unwrap().clone();
         let unit = self.to_simple_unit();
         thread::spawn(move || {
-            let result = term_waiter.result();
-
-            // If we're still in the "Running" state, it's a failure.
-            if *(thr_state.lock().unwrap()) == TestState::Running {
-                let msg = format!("Daemon exited: {:?}", result);
-                *(thr_state.lock().unwrap()) = TestState::Fail(msg.clone());
-                unit.broadcast(BroadcastMessageContents::Fail(unit.id().to_string(), msg));
+            if let Some(ref r) = ready {
+                // Fire off a thread to kill the process if it takes too long to start.
+                let timeout_state = thr_state.clone();
+                let timeout_finish_time = thr_finish_time.clone();
+                let timeout_unit = unit.clone();
+                let thr =
+                    thread::spawn(move || {
+                        let ref unit = timeout_unit;
+                        thread::park_timeout(max_duration);
+                        if *(timeout_state.lock().unwrap()) == TestState::Starting {
+                            let msg = format!("Test daemon never came ready");
+                            *(timeout_state.lock().unwrap()) = TestState::Fail(msg.clone());
+                            *(timeout_finish_time.lock().unwrap()) = Some(time::SystemTime::now());
+                            unit.broadcast(BroadcastMessageContents::Log(msg));
+                            thr_waiter.terminate(&None);
+
+                            if let Some(cmd) = thr_end {
+                                let msg = format!("Running post-test command: {}", cmd);
+                                unit.broadcast(BroadcastMessageContents::Log(msg));
+                                let dir = thr_dir.lock().unwrap();
+                                process::try_command(unit,
+                                                     cmd.as_str(),
+                                                     &*dir,
+                                                     &thr_environment,
+                                                     thr_end_timeout);
+                            }
+                        }
+                    });
+
+                // Wait for the string to appear.
+                unit.log(format!("Waiting for string: {}", r));
+                loop {
+                    let mut line = String::new();
+                    match buf_reader.read_line(&mut line) {
+                        Err(e) => {
+                            let msg = format!("Error in interface: {:?}", e);
+                            unit.log(msg.clone());
+                            *(thr_state.lock().unwrap()) = TestState::Fail(msg.clone());
+                            *(thr_finish_time.lock().unwrap()) = Some(time::SystemTime::now());
+                            unit.broadcast(BroadcastMessageContents::Fail(unit.id().to_string(),
+                                                                          msg));
+                            thr.thread().unpark();
+                            unit.control_class("result", ControlMessageContents::AdvanceScenario);
+                            return;
+                        }
+                        Ok(0) => {
+                            let msg = format!("Test daemon exited");
+                            unit.log(msg.clone());
+                            *(thr_state.lock().unwrap()) = TestState::Fail(msg.clone());
+                            *(thr_finish_time.lock().unwrap()) = Some(time::SystemTime::now());
+                            unit.broadcast(BroadcastMessageContents::Fail(unit.id().to_string(),
+                                                                          msg));
+                            thr.thread().unpark();
+                            unit.control_class("result", ControlMessageContents::AdvanceScenario);
+                            return;
+                        }
+                        Ok(_) => {
+                            let trimmed = line.trim_right_matches(&['\r', '\n'][..]).to_string();
+                            capture_output(&thr_stdout, &trimmed);
+                            thr_measurements.lock().unwrap().record(&unit, &trimmed);
+                            unit.broadcast_class("stdout",
+                                                 BroadcastMessageContents::Log(line.clone()));
+                            if r.is_match(line.as_str()) {
+                                let mut state = thr_state.lock().unwrap();
+                                // If it was terminated or timed out in the meantime, that stands.
+                                if *state != TestState::Starting {
+                                    return;
+                                }
+                                *state = TestState::Running;
+                                break;
+                            }
+                        }
+                    }
+                    line.clear();
+                }
+                // Now that the match string has been found (if any), mark the daemon as "Running".
+                thr.thread().unpark();
             } else {
-                unit.broadcast(BroadcastMessageContents::Pass(unit.id().to_string(),
-                                                              "Okay".to_string()));
+                *(thr_state.lock().unwrap()) = TestState::Running;
             }
-        });
 
-        // Now that the test is running as a daemon, advance to the next scenario.
-        self.control_class("result", ControlMessageContents::AdvanceScenario);
+            let out_stdout = thr_stdout.clone();
+            let out_measurements = thr_measurements.clone();
+            process::watch_output(buf_reader, &unit, move |msg, unit| {
+                    capture_output(&out_stdout, &msg);
+                    out_measurements.lock().unwrap().record(unit, &msg);
+                    unit.broadcast_class("stdout", BroadcastMessageContents::Log(msg));
+                    Ok(())
+                })
+                .unwrap();
+
+            // Move the child into its own thread and wait for it to terminate.
+            // If we're still in the "Running" state when it quits, then the daemon
+            // has failed.
+            let exit_unit = unit.clone();
+            thread::spawn(move || {
+                let ref unit = exit_unit;
+                let result = term_waiter.result();
+                let pipe = thr_result_pipe.lock().unwrap().take();
+                if let Some(mut pipe) = pipe {
+                    pipe.close();
+                }
+                thr_prompts.lock().unwrap().finish();
+
+                // If we're still in the "Running" state, it's a failure.  Any other
+                // failure was reported when it happened.
+                let state = thr_state.lock().unwrap().clone();
+                if state == TestState::Running {
+                    let msg = format!("Daemon exited: {:?}", result);
+                    *(thr_state.lock().unwrap()) = TestState::Fail(msg.clone());
+                    *(thr_finish_time.lock().unwrap()) = Some(time::SystemTime::now());
+                    unit.broadcast(BroadcastMessageContents::Fail(unit.id().to_string(), msg));
+                } else if state == TestState::Pass {
+                    let msg = match *(thr_result_message.lock().unwrap()) {
+                        None => "Okay".to_string(),
+                        Some(ref m) => m.clone(),
+                    };
+                    unit.broadcast(BroadcastMessageContents::Pass(unit.id().to_string(), msg));
+                }
+            });
+
+            // Now that the test is running as a daemon, advance to the next scenario.
+            unit.control_class("result", ControlMessageContents::AdvanceScenario);
+        });
     }
 
-    fn start_simple(&self, working_directory: &Option<String>, max_duration: time::Duration) {
+    fn start_simple(&self,
+                    cmd: &str,
+                    environment: &process::Environment,
+                    working_directory: &Option<String>,
+                    max_duration: time::Duration) {
         // Try to create a command.  If this fails, then the command completion will be called,
         // so we can just ignore the error.
-        let ref cmd = self.exec_start;
         let last_line = self.last_line.clone();
         let result = self.state.clone();
+        let finish_time = self.finish_time.clone();
+        let measurements = self.measurements.clone();
+        let result_pipe = self.result_pipe.clone();
+        let result_message = self.result_message.clone();
+        let prompts = self.prompts.clone();
+        let attempt = self.attempt.clone();
+        let retries = self.retries;
+        let retry_delay = self.retry_delay;
+        let retry_generation = self.retry_generation.clone();
+        let generation = *(self.retry_generation.lock().unwrap());
+        let retry_on = self.retry_on.clone();
         let unit = self.to_simple_unit();
 
         // Mark the test as "Running"
@@ -486,20 +1070,78 @@ impl Test {
         // Clone the Option<Running> so we can clean it up when it exits.
         let thr_process = self.test_process.clone();
         let mut running =
-            match process::try_command_completion(cmd.as_str(),
+            match process::try_command_completion(cmd,
                                                   working_directory,
+                                                  environment,
                                                   max_duration,
                                                   move |res: Result<(),
                                                                     process::CommandError>| {
-                let msg = match res {
+                *(finish_time.lock().unwrap()) = Some(time::SystemTime::now());
+
+                // Let the result pipe catch up, so that no records are missed.
+                let pipe = result_pipe.lock().unwrap().take();
+                if let Some(mut pipe) = pipe {
+                    pipe.close();
+                }
+                prompts.lock().unwrap().finish();
+
+                // A RESULT record takes the place of the last line printed.
+                let message = match *(result_message.lock().unwrap()) {
+                    None => last_line.lock().unwrap().to_string(),
+                    Some(ref m) => m.clone(),
+                };
+
+                let exit_code = match res {
+                    Ok(_) => Some(0),
+                    Err(process::CommandError::ReturnCodeError(c)) => Some(c),
+                    Err(_) => None,
+                };
+
+                // A test that exits cleanly still fails if its measurements were out of limits.
+                let res = match res {
                     Ok(_) => {
-                        *(result.lock().unwrap()) = TestState::Pass;
-                        BroadcastMessageContents::Pass(unit.id().to_string(),
-                                                       last_line.lock().unwrap().to_string())
+                        match measurements.lock().unwrap().failure() {
+                            None => Ok(()),
+                            Some(reason) => Err(reason),
+                        }
                     }
                     Err(e) => {
-                        let msg = last_line.lock().unwrap().to_string();
                         unit.log(format!("Process exited: {:?}", e));
+                        Err(message.clone())
+                    }
+                };
+
+                // If there are attempts left, and RetryOn allows it, ask the scenario to run
+                // the test again.  Only the final attempt counts as a failure.
+                if let Err(ref reason) = res {
+                    let attempt = *(attempt.lock().unwrap());
+                    if attempt <= retries && retry_on.matches(exit_code, &message) {
+                        thr_process.lock().unwrap().take();
+                        unit.broadcast_class("result",
+                                             BroadcastMessageContents::Retry(unit.id().to_string(),
+                                                                             attempt,
+                                                                             retries + 1,
+                                                                             reason.clone()));
+
+                        // Wait out RetryDelay on a thread of its own, so that this one is free
+                        // to go.  If the test has been skipped or reset since this attempt was
+                        // launched, e.g. because the scenario was aborted, the retry is called off.
+                        thread::spawn(move || {
+                            thread::sleep(retry_delay);
+                            if *(retry_generation.lock().unwrap()) == generation {
+                                unit.control_class("result", ControlMessageContents::RetryTest);
+                            }
+                        });
+                        return;
+                    }
+                }
+
+                let msg = match res {
+                    Ok(_) => {
+                        *(result.lock().unwrap()) = TestState::Pass;
+                        BroadcastMessageContents::Pass(unit.id().to_string(), message)
+                    }
+                    Err(msg) => {
                         *(result.lock().unwrap()) = TestState::Fail(msg.clone());
                         BroadcastMessageContents::Fail(unit.id().to_string(), msg)
                     }
@@ -517,17 +1159,28 @@ impl Test {
                 Ok(o) => o,
             };
 
+        // Answers to prompts are written to stdin.
+        self.prompts.lock().unwrap().stdin = Some(Box::new(running.take_input()));
+
         let thr_last_line = self.last_line.clone();
+        let thr_stdout = self.stdout.clone();
+        let thr_measurements = self.measurements.clone();
         process::watch_output(running.take_output(), self, move |msg, unit| {
-                *(thr_last_line.lock().unwrap()) = msg.clone();
+                // Measurements don't count as the last line, since that's the result message.
+                if !thr_measurements.lock().unwrap().record(unit, &msg) {
+                    *(thr_last_line.lock().unwrap()) = msg.clone();
+                }
+                capture_output(&thr_stdout, &msg);
                 unit.broadcast_class("stdout", BroadcastMessageContents::Log(msg));
                 Ok(())
             })
             .unwrap();
 
         let thr_last_line = self.last_line.clone();
+        let thr_stderr = self.stderr.clone();
         process::watch_output(running.take_error(), self, move |msg, unit| {
                 *(thr_last_line.lock().unwrap()) = msg.clone();
+                capture_output(&thr_stderr, &msg);
                 unit.broadcast_class("stderr", BroadcastMessageContents::Log(msg));
                 Ok(())
             })
@@ -558,6 +1211,7 @@ impl Test {
                     process::try_command(self,
                                          cmd,
                                          working_directory,
+                                         &*self.test_environment.lock().unwrap(),
                                          self.exec_stop_failure_timeout);
                 }
             }
@@ -567,6 +1221,7 @@ impl Test {
                     process::try_command(self,
                                          cmd,
                                          working_directory,
+                                         &*self.test_environment.lock().unwrap(),
                                          self.exec_stop_success_timeout);
                 }
             }
@@ -578,12 +1233,23 @@ impl Test {
         match self.test_type {
             TestType::Simple => self.stop(&self.test_working_directory.lock().unwrap().clone()),
             TestType::Daemon => {
-                // If the daemon is still running, then good!  It passed.
-                let (cmd, timeout) = if *(self.state.lock().unwrap()) == TestState::Running {
-                    *(self.state.lock().unwrap()) = TestState::Pass;
-                    (self.exec_stop_success.clone(), self.exec_stop_success_timeout)
-                } else {
-                    (self.exec_stop_failure.clone(), self.exec_stop_failure_timeout)
+                // If the daemon is still running, then good!  It passed, as long
+                // as its measurements were within their limits.
+                let running = *(self.state.lock().unwrap()) == TestState::Running;
+                let failure = self.measurements.lock().unwrap().failure();
+                let (cmd, timeout) = match (running, failure) {
+                    (true, None) => {
+                        *(self.state.lock().unwrap()) = TestState::Pass;
+                        *(self.finish_time.lock().unwrap()) = Some(time::SystemTime::now());
+                        (self.exec_stop_success.clone(), self.exec_stop_success_timeout)
+                    }
+                    (true, Some(msg)) => {
+                        *(self.state.lock().unwrap()) = TestState::Fail(msg.clone());
+                        *(self.finish_time.lock().unwrap()) = Some(time::SystemTime::now());
+                        self.broadcast(BroadcastMessageContents::Fail(self.id().to_string(), msg));
+                        (self.exec_stop_failure.clone(), self.exec_stop_failure_timeout)
+                    }
+                    (false, _) => (self.exec_stop_failure.clone(), self.exec_stop_failure_timeout),
                 };
 
                 // Terminate the process, if it exists.
@@ -596,7 +1262,8 @@ impl Test {
                 if let Some(c) = cmd {
                     self.log(format!("Running post-test command: {}", c));
                     let ref dir = self.test_working_directory.lock().unwrap();
-                    process::try_command(self, c.as_str(), dir, timeout);
+                    let ref environment = self.test_environment.lock().unwrap();
+                    process::try_command(self, c.as_str(), dir, environment, timeout);
                 }
             }
         }
@@ -606,12 +1273,63 @@ impl Test {
         self.state.lock().unwrap().clone()
     }
 
+    /// Daemon tests keep running in the background once they're ready.
+    pub fn is_daemon(&self) -> bool {
+        self.test_type == TestType::Daemon
+    }
+
     pub fn skip(&self) {
         *(self.state.lock().unwrap()) = TestState::Skip;
+        self.cancel_retry();
     }
 
     pub fn pending(&self) {
         *(self.state.lock().unwrap()) = TestState::Pending;
+        self.cancel_retry();
+        *(self.start_time.lock().unwrap()) = None;
+        *(self.finish_time.lock().unwrap()) = None;
+        self.last_line.lock().unwrap().clear();
+        self.stdout.lock().unwrap().clear();
+        self.stderr.lock().unwrap().clear();
+        self.measurements.lock().unwrap().reset();
+    }
+
+    /// Call off a retry that's waiting out its RetryDelay, if there is one.
+    fn cancel_retry(&self) {
+        let mut generation = self.retry_generation.lock().unwrap();
+        *generation = generation.wrapping_add(1);
+    }
+
+    pub fn start_time(&self) -> Option<time::SystemTime> {
+        self.start_time.lock().unwrap().clone()
+    }
+
+    pub fn finish_time(&self) -> Option<time::SystemTime> {
+        self.finish_time.lock().unwrap().clone()
+    }
+
+    /// The last line the most recent run printed.
+    pub fn last_line(&self) -> String {
+        self.last_line.lock().unwrap().clone()
+    }
+
+    pub fn stdout(&self) -> String {
+        self.stdout.lock().unwrap().clone()
+    }
+
+    pub fn stderr(&self) -> String {
+        self.stderr.lock().unwrap().clone()
+    }
+
+    /// If this test is waiting on prompt `id`, send it `answer`.
+    /// Returns false if the prompt belongs to some other test.
+    pub fn answer(&self, id: &str, answer: &str) -> bool {
+        self.prompts.lock().unwrap().answer(self, id, answer)
+    }
+
+    /// Every measurement the most recent run took, in order.
+    pub fn measurements(&self) -> Vec<Measurement> {
+        self.measurements.lock().unwrap().taken.clone()
     }
 
     pub fn requirements(&self) -> &Vec<String> {
@@ -625,6 +1343,10 @@ impl Test {
     pub fn provides(&self) -> &Vec<String> {
         &self.provides
     }
+
+    pub fn resources(&self) -> &Vec<ResourceClaim> {
+        &self.resources
+    }
 }
 
 impl Unit for Test {
@@ -633,11 +1355,11 @@ impl Unit for Test {
     }
 
     fn name(&self) -> &str {
-        self.name.as_str()
+        self.name.default()
     }
 
     fn description(&self) -> &str {
-        self.description.as_str()
+        self.description.default()
     }
 
     fn id(&self) -> &str {
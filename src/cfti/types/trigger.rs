@@ -60,12 +60,12 @@ impl Trigger {
             return Some(Err(TriggerError::MissingTriggerSection));
         }
 
-        let description = match unitfile.get("Trigger", "Description") {
+        let description = match unitfile.get_localized("Trigger", "Description", config.locale()) {
             None => None,
             Some(s) => Some(s.to_string()),
         };
 
-        let name = match unitfile.get("Trigger", "Name") {
+        let name = match unitfile.get_localized("Trigger", "Name", config.locale()) {
             None => id.to_string(),
             Some(s) => s.to_string(),
         };
@@ -132,9 +132,9 @@ impl Trigger {
         let response = match verb.as_str() {
             "start" => {
                 if words.len() > 0 {
-                    ControlMessageContents::StartScenario(Some(words[0].clone()))
+                    ControlMessageContents::StartScenario(Some(words[0].clone()), None)
                 } else {
-                    ControlMessageContents::StartScenario(None)
+                    ControlMessageContents::StartScenario(None, None)
                 }
             }
             "stop" => ControlMessageContents::AbortTests,
@@ -159,7 +159,7 @@ impl Trigger {
         };
 
         let mut cmd =
-            match process::spawn_cmd(self.exec_start.as_str(), self, &working_directory) {
+            match process::spawn_cmd(self.exec_start.as_str(), self, &working_directory, &[]) {
                 Err(e) => return Err(TriggerError::TriggerSpawnError(e)),
                 Ok(o) => o,
             };
@@ -0,0 +1,826 @@
+extern crate tar;
+
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{Cursor, Read, Write};
+use std::mem;
+use std::os::unix;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{self, SystemTime, UNIX_EPOCH};
+
+use cfti::types::unit::Unit;
+use cfti::controller::{Controller, BroadcastMessageContents, ControlMessageContents};
+use cfti::config;
+use cfti::signing;
+use cfti::testset;
+use cfti::unitfile::UnitFile;
+
+/// The name of the manifest file that must be at the root of every bundle.
+const MANIFEST_NAME: &'static str = "MANIFEST";
+
+/// Appended to a bundle's filename to find its detached signature.
+const SIGNATURE_SUFFIX: &'static str = ".sig";
+
+#[derive(Debug)]
+pub enum UpdaterError {
+    FileLoadError(String),
+    MissingUpdaterSection,
+    MissingPath,
+    ParsePollIntervalError(String),
+    MissingPublicKeys,
+    PublicKeyInvalid(String),
+    SignatureMissing(String),
+    SignatureInvalid(String),
+    SigningKeyError(String),
+    BundleUnreadable(String),
+    BundleInvalid(String),
+    NothingStaged,
+    InstallFailed(String),
+}
+
+#[derive(Debug)]
+pub struct Updater {
+    /// id: The string that other units refer to this file as.
+    id: String,
+
+    /// name: Display name of this updater.
+    name: String,
+
+    /// description: Paragraph describing this updater.
+    description: String,
+
+    /// path: The directory to watch for update bundles, e.g. a USB mount point.
+    path: String,
+
+    /// poll_interval: How often to look in `path` for new bundles.
+    poll_interval: time::Duration,
+
+    /// public_keys: Ed25519 keys that are trusted to sign bundles.
+    public_keys: Vec<Vec<u8>>,
+
+    /// The configuration directory that bundles get installed into.
+    config_dir: String,
+
+    /// Bundles that have already been looked at, so they aren't applied twice.
+    seen: Arc<Mutex<HashSet<String>>>,
+
+    /// A validated bundle, unpacked into its own directory and waiting to be swapped into place.
+    staged: Arc<Mutex<Option<PathBuf>>>,
+
+    /// The controller where messages come and go.
+    controller: Controller,
+}
+
+impl Updater {
+    pub fn new(id: &str,
+               path: &str,
+               test_set: &testset::TestSet,
+               config: &config::Config)
+               -> Option<Result<Updater, UpdaterError>> {
+
+        // Load the .ini file
+        let unitfile = match UnitFile::new(path) {
+            Err(e) => return Some(Err(UpdaterError::FileLoadError(format!("{:?}", e)))),
+            Ok(s) => s,
+        };
+
+        if !unitfile.has_section("Updater") {
+            return Some(Err(UpdaterError::MissingUpdaterSection));
+        }
+
+        // Check to see if this updater is compatible with this jig.
+        match unitfile.get("Updater", "Jigs") {
+            None => (),
+            Some(s) => {
+                let jigs = test_set.jigs();
+                let jig_names: Vec<String> =
+                    s.split(|c| c == ',' || c == ' ').map(|s| s.to_string()).collect();
+                let mut found_it = false;
+                for jig_name in jig_names {
+                    if jigs.get(&jig_name).is_some() {
+                        found_it = true;
+                        break;
+                    }
+                }
+                if found_it == false {
+                    test_set.debug(format!("The updater '{}' is not compatible with this jig", id));
+                    return None;
+                }
+            }
+        }
+
+        let description = match unitfile.get_localized("Updater", "Description", config.locale()) {
+            None => "".to_string(),
+            Some(s) => s.to_string(),
+        };
+
+        let name = match unitfile.get_localized("Updater", "Name", config.locale()) {
+            None => id.to_string(),
+            Some(s) => s.to_string(),
+        };
+
+        let watch_path = match unitfile.get("Updater", "Path") {
+            None => return Some(Err(UpdaterError::MissingPath)),
+            Some(s) => s.to_string(),
+        };
+
+        let poll_interval = match unitfile.get("Updater", "PollInterval") {
+            None => time::Duration::from_secs(5),
+            Some(s) => {
+                match s.parse() {
+                    Err(_) => return Some(Err(UpdaterError::ParsePollIntervalError(s.to_string()))),
+                    Ok(n) => time::Duration::from_secs(n),
+                }
+            }
+        };
+
+        // Every bundle must be signed, so an updater without keys is useless.
+        let public_keys = match unitfile.get("Updater", "PublicKeys") {
+            None => return Some(Err(UpdaterError::MissingPublicKeys)),
+            Some(s) => {
+                let mut keys = vec![];
+                for key in s.split(|c| c == ',' || c == ' ').filter(|x| !x.is_empty()) {
+                    match Self::load_key(key) {
+                        Err(e) => return Some(Err(e)),
+                        Ok(k) => keys.push(k),
+                    }
+                }
+                if keys.is_empty() {
+                    return Some(Err(UpdaterError::MissingPublicKeys));
+                }
+                keys
+            }
+        };
+
+        Some(Ok(Updater {
+            id: id.to_string(),
+            name: name,
+            description: description,
+            path: watch_path,
+            poll_interval: poll_interval,
+            public_keys: public_keys,
+            config_dir: test_set.config_dir().to_string(),
+            seen: Arc::new(Mutex::new(HashSet::new())),
+            staged: Arc::new(Mutex::new(None)),
+            controller: test_set.controller().clone(),
+        }))
+    }
+
+    /// Start watching for update bundles.
+    ///
+    /// Bundles are validated and unpacked next to the configuration directory
+    /// as soon as they appear.  Once a bundle is staged, the TestSet is asked
+    /// to install it, which it will do once no scenario is running.
+    pub fn start(&self) {
+        let unit = self.to_simple_unit();
+        let watch_path = self.path.clone();
+        let poll_interval = self.poll_interval;
+        let config_dir = self.config_dir.clone();
+        let public_keys = self.public_keys.clone();
+        let seen = self.seen.clone();
+        let staged = self.staged.clone();
+
+        let builder = thread::Builder::new().name(format!("U-{}", self.id()).into());
+        builder.spawn(move || loop {
+                thread::sleep(poll_interval);
+
+                for bundle in Self::find_bundles(&watch_path) {
+                    // Only look at each bundle once.  If it's changed, its key will change too.
+                    let key = Self::bundle_key(&bundle);
+                    if seen.lock().unwrap().contains(&key) {
+                        continue;
+                    }
+                    seen.lock().unwrap().insert(key);
+
+                    let bundle_name = bundle.to_str().unwrap_or("").to_string();
+                    unit.broadcast(BroadcastMessageContents::Update(unit.id().to_string(),
+                                                                    "found".to_string(),
+                                                                    bundle_name.clone()));
+
+                    match Self::stage(&bundle, &public_keys, &config_dir) {
+                        Err(e) => {
+                            unit.broadcast(BroadcastMessageContents::Update(unit.id()
+                                                                                .to_string(),
+                                                                            "fail".to_string(),
+                                                                            format!("{}: {:?}",
+                                                                                    bundle_name,
+                                                                                    e)));
+                        }
+                        Ok(dir) => {
+                            // A newer bundle replaces one that hasn't been installed yet.
+                            let replaced = mem::replace(&mut *staged.lock().unwrap(), Some(dir));
+                            if let Some(old) = replaced {
+                                fs::remove_dir_all(old).ok();
+                            }
+                            unit.broadcast(BroadcastMessageContents::Update(unit.id()
+                                                                                .to_string(),
+                                                                            "staged".to_string(),
+                                                                            bundle_name));
+                            unit.control(ControlMessageContents::InstallUpdate);
+                        }
+                    }
+                }
+            })
+            .unwrap();
+    }
+
+    fn find_bundles(watch_path: &str) -> Vec<PathBuf> {
+        let mut bundles = vec![];
+        let entries = match fs::read_dir(watch_path) {
+            // The path may simply not be mounted right now.
+            Err(_) => return bundles,
+            Ok(e) => e,
+        };
+
+        for entry in entries {
+            let path = match entry {
+                Err(_) => continue,
+                Ok(e) => e.path(),
+            };
+            if path.is_file() && path.extension().map(|x| x == "tar").unwrap_or(false) {
+                bundles.push(path);
+            }
+        }
+        bundles.sort();
+        bundles
+    }
+
+    /// A key is either a hex string, or the path to a file containing one.
+    fn load_key(key: &str) -> Result<Vec<u8>, UpdaterError> {
+        if let Ok(k) = signing::from_hex(key) {
+            if k.len() == 32 {
+                return Ok(k);
+            }
+        }
+        match signing::load_public_key(key) {
+            Ok(k) => Ok(k),
+            Err(e) => Err(UpdaterError::PublicKeyInvalid(format!("{}: {}", key, e))),
+        }
+    }
+
+    fn signature_path(bundle: &Path) -> PathBuf {
+        PathBuf::from(format!("{}{}", bundle.to_str().unwrap_or(""), SIGNATURE_SUFFIX))
+    }
+
+    fn bundle_key(bundle: &Path) -> String {
+        // Include the signature, so a bundle gets another look if its
+        // signature shows up after it does.
+        let mut key = bundle.to_str().unwrap_or("").to_string();
+        for path in &[bundle.to_path_buf(), Self::signature_path(bundle)] {
+            let (len, modified) = match fs::metadata(path) {
+                Err(_) => (0, None),
+                Ok(m) => (m.len(), m.modified().ok()),
+            };
+            key.push_str(format!(":{}:{:?}", len, modified).as_str());
+        }
+        key
+    }
+
+    /// Make sure `contents` was signed by one of `public_keys`.
+    ///
+    /// The signature lives next to the bundle in "[bundle].sig", and is
+    /// a hex-encoded Ed25519 signature of the entire bundle file.
+    fn check_signature(bundle: &Path,
+                       contents: &[u8],
+                       public_keys: &Vec<Vec<u8>>)
+                       -> Result<(), UpdaterError> {
+        let sig_path = Self::signature_path(bundle);
+        let mut sig_hex = String::new();
+        match File::open(&sig_path) {
+            Err(_) => {
+                return Err(UpdaterError::SignatureMissing(sig_path.to_str()
+                    .unwrap_or("")
+                    .to_string()))
+            }
+            Ok(mut f) => {
+                if let Err(e) = f.read_to_string(&mut sig_hex) {
+                    return Err(UpdaterError::SignatureInvalid(format!("{:?}", e)));
+                }
+            }
+        }
+
+        let sig = match signing::from_hex(sig_hex.trim()) {
+            Err(e) => return Err(UpdaterError::SignatureInvalid(format!("{}", e))),
+            Ok(s) => s,
+        };
+
+        for public_key in public_keys {
+            if signing::verify(public_key, contents, &sig).is_ok() {
+                return Ok(());
+            }
+        }
+        Err(UpdaterError::SignatureInvalid("not signed by a trusted key".to_string()))
+    }
+
+    /// Sign `bundle` with the private key in `key_path`, writing "[bundle].sig".
+    pub fn sign_bundle(bundle: &str, key_path: &str) -> Result<String, UpdaterError> {
+        let key = match signing::SigningKey::load(key_path) {
+            Err(e) => return Err(UpdaterError::SigningKeyError(format!("{}", e))),
+            Ok(k) => k,
+        };
+
+        let mut contents = vec![];
+        match File::open(bundle) {
+            Err(e) => return Err(UpdaterError::BundleUnreadable(format!("{:?}", e))),
+            Ok(mut f) => {
+                if let Err(e) = f.read_to_end(&mut contents) {
+                    return Err(UpdaterError::BundleUnreadable(format!("{:?}", e)));
+                }
+            }
+        }
+
+        let sig_path = Self::signature_path(Path::new(bundle));
+        let result = File::create(&sig_path)
+            .and_then(|mut f| writeln!(f, "{}", signing::to_hex(&key.sign(&contents))));
+        if let Err(e) = result {
+            return Err(UpdaterError::SigningKeyError(format!("{:?}", e)));
+        }
+        Ok(signing::to_hex(&key.public_key()))
+    }
+
+    /// Check `bundle`'s signature, then unpack it into a staging directory
+    /// of its own next to `config_dir` and validate it.
+    ///
+    /// Nothing is written to disk unless the signature is good.  Each bundle
+    /// gets a fresh directory, so staging one never touches another that's
+    /// being installed.
+    fn stage(bundle: &Path,
+             public_keys: &Vec<Vec<u8>>,
+             config_dir: &str)
+             -> Result<PathBuf, UpdaterError> {
+        // Read the bundle only once, so it can't change between checking and unpacking.
+        let mut contents = vec![];
+        match File::open(bundle) {
+            Err(e) => return Err(UpdaterError::BundleUnreadable(format!("{:?}", e))),
+            Ok(mut f) => {
+                if let Err(e) = f.read_to_end(&mut contents) {
+                    return Err(UpdaterError::BundleUnreadable(format!("{:?}", e)));
+                }
+            }
+        }
+        try!(Self::check_signature(bundle, &contents, public_keys));
+
+        let staging = Self::unique_path(config_dir.trim_right_matches('/'), "staging-");
+        if let Err(e) = fs::create_dir_all(&staging) {
+            return Err(UpdaterError::InstallFailed(format!("{:?}", e)));
+        }
+
+        if let Err(e) = Self::unpack(&contents, &staging) {
+            fs::remove_dir_all(&staging).ok();
+            return Err(e);
+        }
+
+        if let Err(e) = Self::check_manifest(&staging) {
+            fs::remove_dir_all(&staging).ok();
+            return Err(e);
+        }
+
+        Ok(staging)
+    }
+
+    fn unpack(contents: &[u8], staging: &Path) -> Result<(), UpdaterError> {
+        let mut archive = tar::Archive::new(Cursor::new(contents));
+        let entries = match archive.entries() {
+            Ok(e) => e,
+            Err(e) => return Err(UpdaterError::BundleUnreadable(format!("{:?}", e))),
+        };
+
+        for entry in entries {
+            let mut entry = match entry {
+                Ok(e) => e,
+                Err(e) => return Err(UpdaterError::BundleUnreadable(format!("{:?}", e))),
+            };
+
+            let entry_path = match entry.path() {
+                Ok(p) => p.to_path_buf(),
+                Err(e) => return Err(UpdaterError::BundleInvalid(format!("{:?}", e))),
+            };
+
+            // Only allow plain files and directories that stay inside the bundle.
+            for component in entry_path.components() {
+                match component {
+                    Component::Normal(_) | Component::CurDir => (),
+                    _ => {
+                        return Err(UpdaterError::BundleInvalid(format!("invalid path {:?}",
+                                                                       entry_path)))
+                    }
+                }
+            }
+            let entry_type = entry.header().entry_type();
+            if !entry_type.is_file() && !entry_type.is_dir() {
+                return Err(UpdaterError::BundleInvalid(format!("{:?} is not a regular file",
+                                                               entry_path)));
+            }
+
+            if let Err(e) = entry.unpack_in(staging) {
+                return Err(UpdaterError::BundleUnreadable(format!("{:?}", e)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Make sure the manifest lists every file in the bundle, and that each hash matches.
+    ///
+    /// Each line of the manifest is "sha256 [hex digest] [path]".  Blank lines
+    /// and lines starting with "#" are ignored.
+    fn check_manifest(staging: &Path) -> Result<(), UpdaterError> {
+        let mut manifest = String::new();
+        match File::open(staging.join(MANIFEST_NAME)) {
+            Err(_) => return Err(UpdaterError::BundleInvalid("missing MANIFEST".to_string())),
+            Ok(mut f) => {
+                if let Err(e) = f.read_to_string(&mut manifest) {
+                    return Err(UpdaterError::BundleInvalid(format!("MANIFEST: {:?}", e)));
+                }
+            }
+        };
+
+        let mut listed = HashSet::new();
+        for line in manifest.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with("#") {
+                continue;
+            }
+
+            let words: Vec<&str> = line.splitn(3, ' ').collect();
+            if words.len() != 3 || words[0] != "sha256" {
+                return Err(UpdaterError::BundleInvalid(format!("bad MANIFEST line: {}", line)));
+            }
+            let (expected, file_name) = (words[1], words[2]);
+
+            let mut contents = vec![];
+            match File::open(staging.join(file_name)) {
+                Err(_) => return Err(UpdaterError::BundleInvalid(format!("missing {}", file_name))),
+                Ok(mut f) => {
+                    if let Err(e) = f.read_to_end(&mut contents) {
+                        return Err(UpdaterError::BundleInvalid(format!("{}: {:?}", file_name, e)));
+                    }
+                }
+            }
+
+            if signing::to_hex(&signing::sha256(&contents)) != expected.to_lowercase() {
+                return Err(UpdaterError::BundleInvalid(format!("hash mismatch on {}", file_name)));
+            }
+            listed.insert(file_name.to_string());
+        }
+
+        if listed.is_empty() {
+            return Err(UpdaterError::BundleInvalid("MANIFEST is empty".to_string()));
+        }
+
+        // Don't let anything sneak in that isn't in the manifest.
+        for file_name in Self::list_files(staging, "") {
+            if file_name != MANIFEST_NAME && !listed.contains(&file_name) {
+                return Err(UpdaterError::BundleInvalid(format!("{} is not in MANIFEST",
+                                                               file_name)));
+            }
+        }
+        Ok(())
+    }
+
+    fn list_files(dir: &Path, prefix: &str) -> Vec<String> {
+        let mut files = vec![];
+        let entries = match fs::read_dir(dir) {
+            Err(_) => return files,
+            Ok(e) => e,
+        };
+        for entry in entries {
+            let entry = match entry {
+                Err(_) => continue,
+                Ok(e) => e,
+            };
+            let name = format!("{}{}", prefix, entry.file_name().to_str().unwrap_or(""));
+            if entry.path().is_dir() {
+                files.append(&mut Self::list_files(&entry.path(), &format!("{}/", name)));
+            } else {
+                files.push(name);
+            }
+        }
+        files
+    }
+
+    /// A path next to `config_dir` that nothing is using yet, such as
+    /// "[config_dir].v[nanoseconds]".
+    fn unique_path(config_dir: &str, kind: &str) -> PathBuf {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(time::Duration::new(0, 0));
+        let mut n = now.as_secs() * 1_000_000_000 + now.subsec_nanos() as u64;
+        loop {
+            let path = PathBuf::from(format!("{}.{}{}", config_dir, kind, n));
+            if !path.exists() {
+                return path;
+            }
+            n = n + 1;
+        }
+    }
+
+    /// Point the symlink `link` at `target`, replacing whatever it pointed at before
+    /// in a single rename.  `target` must be in the same directory as `link`.
+    fn swap_link(link: &Path, target: &Path) -> Result<(), UpdaterError> {
+        let new_link = PathBuf::from(format!("{}.link", link.to_str().unwrap_or("")));
+        fs::remove_file(&new_link).ok();
+
+        // Link by name, so the link still works if the whole directory gets moved.
+        let target_name = target.file_name().unwrap_or(target.as_os_str());
+        if let Err(e) = unix::fs::symlink(target_name, &new_link) {
+            return Err(UpdaterError::InstallFailed(format!("{:?}", e)));
+        }
+        if let Err(e) = fs::rename(&new_link, link) {
+            fs::remove_file(&new_link).ok();
+            return Err(UpdaterError::InstallFailed(format!("{:?}", e)));
+        }
+        Ok(())
+    }
+
+    /// Where the symlink `link` points, as a path next to it.
+    fn link_target(link: &Path) -> Option<PathBuf> {
+        match fs::read_link(link) {
+            Err(_) => None,
+            Ok(target) => Some(link.parent().unwrap_or(Path::new("")).join(target)),
+        }
+    }
+
+    /// Swap the staged bundle into place as the new configuration directory.
+    ///
+    /// The configuration directory is a symlink to a versioned directory
+    /// named "[config_dir].v[version]", so the swap is a single rename and
+    /// there's never a moment without one.  If it's still a plain directory,
+    /// it's moved aside into a version of its own first.  The old version is
+    /// kept, with "[config_dir].previous" pointing to it, so that it can be
+    /// rolled back to by hand.  This must only be called when no scenario is running.
+    pub fn install(&self) -> Result<(), UpdaterError> {
+        let staged = match self.staged.lock().unwrap().take() {
+            None => return Err(UpdaterError::NothingStaged),
+            Some(s) => s,
+        };
+
+        let config_dir_name = self.config_dir.trim_right_matches('/');
+        let config_dir = PathBuf::from(config_dir_name);
+        let previous_link = PathBuf::from(format!("{}.previous", config_dir_name));
+
+        let version = Self::unique_path(config_dir_name, "v");
+        if let Err(e) = fs::rename(&staged, &version) {
+            fs::remove_dir_all(&staged).ok();
+            return Err(UpdaterError::InstallFailed(format!("{:?}", e)));
+        }
+
+        let current = match Self::link_target(&config_dir) {
+            Some(t) => t,
+            None => {
+                let original = Self::unique_path(config_dir_name, "v");
+                if let Err(e) = fs::rename(&config_dir, &original) {
+                    fs::remove_dir_all(&version).ok();
+                    return Err(UpdaterError::InstallFailed(format!("{:?}", e)));
+                }
+                original
+            }
+        };
+
+        if let Err(e) = Self::swap_link(&config_dir, &version) {
+            // Put the old configuration back if it was moved aside above.
+            if !config_dir.exists() {
+                fs::rename(&current, &config_dir).ok();
+            }
+            fs::remove_dir_all(&version).ok();
+            return Err(e);
+        }
+
+        // Keep the old version around for rolling back, and drop the one before it.
+        let old_previous = Self::link_target(&previous_link);
+        if let Err(e) = Self::swap_link(&previous_link, &current) {
+            self.debug(format!("Unable to keep the previous configuration: {:?}", e));
+            return Ok(());
+        }
+        if let Some(old_previous) = old_previous {
+            if old_previous != current && old_previous != version {
+                fs::remove_dir_all(old_previous).ok();
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Unit for Updater {
+    fn id(&self) -> &str {
+        self.id.as_str()
+    }
+
+    fn kind(&self) -> &str {
+        "updater"
+    }
+
+    fn name(&self) -> &str {
+        self.name.as_str()
+    }
+
+    fn description(&self) -> &str {
+        self.description.as_str()
+    }
+
+    fn controller(&self) -> &Controller {
+        &self.controller
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::tar::{Builder, EntryType, Header};
+    use std::env;
+
+    /// An empty directory to unpack into, named after the test.
+    fn scratch(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("cfti-test-updater-{}", name));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Add an entry to `builder` with `path` written into the header as-is,
+    /// since the tar crate won't write a path that escapes the archive itself.
+    fn append(builder: &mut Builder<Vec<u8>>, path: &str, entry_type: EntryType, data: &[u8]) {
+        let mut header = Header::new_gnu();
+        header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+        header.set_entry_type(entry_type);
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append(&header, data).unwrap();
+    }
+
+    fn archive(entries: &[(&str, EntryType, &[u8])]) -> Vec<u8> {
+        let mut builder = Builder::new(vec![]);
+        for &(path, entry_type, data) in entries {
+            append(&mut builder, path, entry_type, data);
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn manifest_line(path: &str, data: &[u8]) -> String {
+        format!("sha256 {} {}\n", signing::to_hex(&signing::sha256(data)), path)
+    }
+
+    fn is_invalid<T: ::std::fmt::Debug>(result: Result<T, UpdaterError>) -> bool {
+        match result {
+            Err(UpdaterError::BundleInvalid(_)) => true,
+            r => panic!("expected BundleInvalid, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn unpack_plain_files() {
+        let staging = scratch("plain");
+        let contents = archive(&[("tests", EntryType::Directory, b""),
+                                 ("tests/power.test", EntryType::Regular, b"[Test]\n"),
+                                 ("./MANIFEST", EntryType::Regular, b"")]);
+        assert!(Updater::unpack(&contents, &staging).is_ok());
+        assert!(staging.join("tests/power.test").is_file());
+        fs::remove_dir_all(&staging).unwrap();
+    }
+
+    #[test]
+    fn unpack_rejects_parent_dir() {
+        let staging = scratch("parent/inner");
+        let contents = archive(&[("../escaped", EntryType::Regular, b"x")]);
+        assert!(is_invalid(Updater::unpack(&contents, &staging)));
+        assert!(!staging.join("../escaped").exists());
+
+        let contents = archive(&[("tests/../../escaped", EntryType::Regular, b"x")]);
+        assert!(is_invalid(Updater::unpack(&contents, &staging)));
+        assert!(!staging.join("../escaped").exists());
+        fs::remove_dir_all(staging.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn unpack_rejects_absolute_path() {
+        let staging = scratch("absolute");
+        let target = env::temp_dir().join("cfti-test-updater-absolute-target");
+        let contents = archive(&[(target.to_str().unwrap(), EntryType::Regular, b"x")]);
+        assert!(is_invalid(Updater::unpack(&contents, &staging)));
+        assert!(!target.exists());
+        fs::remove_dir_all(&staging).unwrap();
+    }
+
+    #[test]
+    fn unpack_rejects_links() {
+        let staging = scratch("links");
+        let contents = archive(&[("passwd", EntryType::Symlink, b"")]);
+        assert!(is_invalid(Updater::unpack(&contents, &staging)));
+        assert!(fs::symlink_metadata(staging.join("passwd")).is_err());
+
+        let contents = archive(&[("passwd", EntryType::Link, b"")]);
+        assert!(is_invalid(Updater::unpack(&contents, &staging)));
+        fs::remove_dir_all(&staging).unwrap();
+    }
+
+    /// Write `files` into a fresh staging directory, and check its manifest.
+    fn check(name: &str, files: &[(&str, &[u8])], manifest: &str) -> Result<(), UpdaterError> {
+        let staging = scratch(name);
+        for &(path, data) in files {
+            let path = staging.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            File::create(&path).unwrap().write_all(data).unwrap();
+        }
+        File::create(staging.join(MANIFEST_NAME)).unwrap().write_all(manifest.as_bytes()).unwrap();
+        let result = Updater::check_manifest(&staging);
+        fs::remove_dir_all(&staging).unwrap();
+        result
+    }
+
+    /// Write a bundle and sign it with a new key, returning the bundle's path
+    /// and the public key.
+    fn signed_bundle(name: &str, contents: &[u8]) -> (PathBuf, Vec<u8>) {
+        let bundle = env::temp_dir().join(format!("cfti-test-updater-{}.tar", name));
+        File::create(&bundle).unwrap().write_all(contents).unwrap();
+        let key_path = signing::tests::generate_key_file(&format!("updater-{}", name));
+        let public_key = Updater::sign_bundle(bundle.to_str().unwrap(), &key_path).unwrap();
+        fs::remove_file(&key_path).unwrap();
+        (bundle, signing::from_hex(&public_key).unwrap())
+    }
+
+    fn remove_bundle(bundle: &Path) {
+        fs::remove_file(bundle).unwrap();
+        fs::remove_file(Updater::signature_path(bundle)).ok();
+    }
+
+    #[test]
+    fn signature_round_trip() {
+        let contents = archive(&[("power.test", EntryType::Regular, b"[Test]\n")]);
+        let (bundle, public_key) = signed_bundle("sig-ok", &contents);
+        assert!(Updater::check_signature(&bundle, &contents, &vec![public_key]).is_ok());
+        remove_bundle(&bundle);
+    }
+
+    #[test]
+    fn signature_rejects_changed_bundle() {
+        let contents = archive(&[("power.test", EntryType::Regular, b"[Test]\n")]);
+        let (bundle, public_key) = signed_bundle("sig-changed", &contents);
+        let mut tampered = contents.clone();
+        tampered[512] ^= 1;
+        match Updater::check_signature(&bundle, &tampered, &vec![public_key]) {
+            Err(UpdaterError::SignatureInvalid(_)) => (),
+            r => panic!("expected SignatureInvalid, got {:?}", r),
+        }
+        remove_bundle(&bundle);
+    }
+
+    #[test]
+    fn signature_rejects_wrong_key() {
+        let contents = archive(&[("power.test", EntryType::Regular, b"[Test]\n")]);
+        let (bundle, _) = signed_bundle("sig-wrong-key", &contents);
+        let (other, other_public_path) = signing::tests::generate_key("updater-sig-other");
+        fs::remove_file(&other_public_path).unwrap();
+        match Updater::check_signature(&bundle, &contents, &vec![other.public_key()]) {
+            Err(UpdaterError::SignatureInvalid(_)) => (),
+            r => panic!("expected SignatureInvalid, got {:?}", r),
+        }
+        remove_bundle(&bundle);
+    }
+
+    #[test]
+    fn signature_missing() {
+        let contents = archive(&[("power.test", EntryType::Regular, b"[Test]\n")]);
+        let (bundle, public_key) = signed_bundle("sig-missing", &contents);
+        fs::remove_file(Updater::signature_path(&bundle)).unwrap();
+        match Updater::check_signature(&bundle, &contents, &vec![public_key]) {
+            Err(UpdaterError::SignatureMissing(_)) => (),
+            r => panic!("expected SignatureMissing, got {:?}", r),
+        }
+        remove_bundle(&bundle);
+    }
+
+    #[test]
+    fn manifest_lists_every_file() {
+        let files: &[(&str, &[u8])] = &[("power.test", b"[Test]\n"), ("bin/power", b"#!/bin/sh\n")];
+        let manifest = format!("# Generated\n\n{}{}",
+                               manifest_line("power.test", b"[Test]\n"),
+                               manifest_line("bin/power", b"#!/bin/sh\n"));
+        assert!(check("manifest-ok", files, &manifest).is_ok());
+    }
+
+    #[test]
+    fn manifest_rejects_unlisted_file() {
+        let files: &[(&str, &[u8])] = &[("power.test", b"[Test]\n"), ("bin/extra", b"rm -rf /\n")];
+        let manifest = manifest_line("power.test", b"[Test]\n");
+        assert!(is_invalid(check("manifest-unlisted", files, &manifest)));
+    }
+
+    #[test]
+    fn manifest_rejects_bad_hash() {
+        let files: &[(&str, &[u8])] = &[("power.test", b"[Test]\nExecStart=evil\n")];
+        let manifest = manifest_line("power.test", b"[Test]\n");
+        assert!(is_invalid(check("manifest-hash", files, &manifest)));
+    }
+
+    #[test]
+    fn manifest_rejects_missing_or_empty() {
+        let files: &[(&str, &[u8])] = &[("power.test", b"[Test]\n")];
+        assert!(is_invalid(check("manifest-empty", files, "# nothing\n")));
+        assert!(is_invalid(check("manifest-missing-file",
+                                 files,
+                                 &format!("{}{}",
+                                          manifest_line("power.test", b"[Test]\n"),
+                                          manifest_line("gone.test", b"")))));
+        assert!(is_invalid(check("manifest-bad-line", files, "power.test\n")));
+    }
+}
@@ -2,6 +2,8 @@ extern crate systemd_parser;
 use std::io::Read;
 use std::fs::File;
 
+use cfti::locale::{self, LocalizedString};
+
 #[derive(Debug)]
 pub enum UnitFileError {
     FileUnreadable(String),
@@ -11,6 +13,7 @@ pub enum UnitFileError {
 
 pub struct UnitFile {
     unitfile: systemd_parser::items::SystemdUnit,
+    contents: String,
 }
 
 impl UnitFile {
@@ -31,7 +34,10 @@ impl UnitFile {
             Err(e) => return Err(UnitFileError::FileParseError(format!("{:?}", e))),
         };
 
-        Ok(UnitFile { unitfile: unit_file })
+        Ok(UnitFile {
+            unitfile: unit_file,
+            contents: contents,
+        })
     }
 
     pub fn has_section(&self, name: &str) -> bool {
@@ -58,4 +64,125 @@ impl UnitFile {
 
         coll.value()
     }
-}
\ No newline at end of file
+
+    /// Look up `key`, preferring a translation for `locale` if there is one.
+    ///
+    /// Translations are written as e.g. "Name[zh_CN]=".  For a locale of
+    /// "zh_CN.UTF-8", this tries "Name[zh_CN]", then "Name[zh]", and
+    /// finally falls back to plain "Name".
+    pub fn get_localized(&self, section: &str, key: &str, locale: &Option<String>) -> Option<&str> {
+        for candidate in locale::locale_fallbacks(locale) {
+            if let Some(s) = self.get(section, format!("{}[{}]", key, candidate).as_str()) {
+                return Some(s);
+            }
+        }
+        self.get(section, key)
+    }
+
+    /// Look up `key` along with every one of its translations, so that the
+    /// language can be chosen later on by each interface.  The default is the
+    /// untranslated "Key=" value.
+    pub fn get_localized_string(&self, section: &str, key: &str) -> Option<LocalizedString> {
+        let mut localized = match self.get(section, key) {
+            None => return None,
+            Some(s) => LocalizedString::new(s),
+        };
+
+        // The parser doesn't list keys, so find "Key[locale]=" lines by hand.
+        let mut current_section = "".to_string();
+        for line in self.contents.lines() {
+            let line = line.trim();
+            if line.starts_with("[") && line.ends_with("]") {
+                current_section = line[1..line.len() - 1].to_string();
+                continue;
+            }
+            if current_section != section || !line.starts_with(key) {
+                continue;
+            }
+
+            let rest = &line[key.len()..];
+            if !rest.starts_with("[") {
+                continue;
+            }
+            let close = match rest.find("]") {
+                None => continue,
+                Some(i) => i,
+            };
+            let translation_locale = &rest[1..close];
+            let rest = rest[close + 1..].trim_left();
+            if !rest.starts_with("=") {
+                continue;
+            }
+            let translated_key = format!("{}[{}]", key, translation_locale);
+            if let Some(value) = self.get(section, translated_key.as_str()) {
+                localized.add_translation(translation_locale, value);
+            }
+        }
+        Some(localized)
+    }
+
+    /// Find every "[prefix][name]=[value]" line in `section`, and return the
+    /// names and values, e.g. ("vdd_3v3", "3.2..3.4") for "Limit.vdd_3v3=3.2..3.4".
+    pub fn get_prefixed(&self, section: &str, prefix: &str) -> Vec<(String, String)> {
+        let mut found = vec![];
+        let mut current_section = "".to_string();
+        for line in self.contents.lines() {
+            let line = line.trim();
+            if line.starts_with("[") && line.ends_with("]") {
+                current_section = line[1..line.len() - 1].to_string();
+                continue;
+            }
+            if current_section != section || !line.starts_with(prefix) {
+                continue;
+            }
+
+            let rest = &line[prefix.len()..];
+            let equals = match rest.find("=") {
+                None => continue,
+                Some(i) => i,
+            };
+            let name = rest[..equals].trim();
+            if name.is_empty() {
+                continue;
+            }
+            found.push((name.to_string(), rest[equals + 1..].trim().to_string()));
+        }
+        found
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+
+    fn unit_file(name: &str, contents: &str) -> UnitFile {
+        let path = env::temp_dir().join(format!("jig-20-unitfile-{}.test", name));
+        fs::File::create(&path).unwrap().write_all(contents.as_bytes()).unwrap();
+        let unit_file = UnitFile::new(path.to_str().unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+        unit_file
+    }
+
+    #[test]
+    fn get_prefixed() {
+        let unit_file = unit_file("prefixed",
+                                  "[Test]\nName=Power\nLimit.vdd=3.2..3.4\nLimit.temp=..85\n\
+                                   [Other]\nLimit.vdd=0..1\n");
+        assert_eq!(unit_file.get_prefixed("Test", "Limit."),
+                   vec![("vdd".to_string(), "3.2..3.4".to_string()),
+                        ("temp".to_string(), "..85".to_string())]);
+        assert_eq!(unit_file.get_prefixed("Other", "Limit."),
+                   vec![("vdd".to_string(), "0..1".to_string())]);
+        assert!(unit_file.get_prefixed("Missing", "Limit.").is_empty());
+    }
+
+    #[test]
+    fn get_prefixed_skips_empty_names() {
+        let unit_file = unit_file("empty", "[Test]\nLimit.=1..2\nLimit.ok=1..2\n");
+        assert_eq!(unit_file.get_prefixed("Test", "Limit."),
+                   vec![("ok".to_string(), "1..2".to_string())]);
+    }
+}
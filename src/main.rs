@@ -4,13 +4,14 @@ extern crate clap;
 
 mod cfti;
 use std::thread;
+use std::process;
 use std::io::Write;
 
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
 use self::termcolor::{BufferWriter, Color, ColorChoice, ColorSpec, WriteColor};
-use clap::{Arg, App};
+use clap::{Arg, App, SubCommand};
 
 fn main() {
     // The signal handler must come first, so that the same mask gets
@@ -55,9 +56,123 @@ fn main() {
             .value_name("SECONDS")
             .default_value("60")
             .help("The default number of seconds to allow scenarios to run, if unspecified"))
+        .arg(Arg::with_name("VERIFY_COUPON")
+            .long("verify-coupon")
+            .value_name("CERTIFICATE")
+            .requires("PUBLIC_KEY")
+            .help("Verify a coupon certificate against a public key, and then exit"))
+        .arg(Arg::with_name("PUBLIC_KEY")
+            .long("public-key")
+            .value_name("KEY_FILE")
+            .help("A file containing the hex-encoded public key to verify coupons with"))
+        .arg(Arg::with_name("SIGN_BUNDLE")
+            .long("sign-bundle")
+            .value_name("BUNDLE")
+            .requires("SIGNING_KEY")
+            .help("Sign an updater bundle, writing the signature to BUNDLE.sig, and then exit"))
+        .arg(Arg::with_name("SIGNING_KEY")
+            .long("signing-key")
+            .value_name("KEY_FILE")
+            .help("A PKCS#8 Ed25519 private key to sign updater bundles with"))
+        .arg(Arg::with_name("RESULTS_DB")
+            .long("results-db")
+            .value_name("DB_FILE")
+            .help("Record every scenario run into this SQLite database"))
+        .arg(Arg::with_name("REPORT_DIR")
+            .long("report-dir")
+            .value_name("DIRECTORY")
+            .help("Write a JUnit report for each scenario run into this directory"))
+        .subcommand(SubCommand::with_name("results")
+            .about("Show the most recent runs recorded in the --results-db database")
+            .arg(Arg::with_name("COUNT")
+                .short("n")
+                .long("count")
+                .value_name("COUNT")
+                .default_value("20")
+                .help("The number of runs to show"))
+            .arg(Arg::with_name("SCENARIO")
+                .long("scenario")
+                .value_name("SCENARIO")
+                .help("Only show runs of this scenario")))
         .get_matches();
 
-    // config.set_locale(matches.value_of("LOCALE"));
+    if let Some(certificate) = matches.value_of("VERIFY_COUPON") {
+        let key = matches.value_of("PUBLIC_KEY").unwrap();
+        match cfti::types::Coupon::verify_certificate(certificate, key) {
+            Ok(_) => {
+                println!("{}: certificate is valid", certificate);
+                return;
+            }
+            Err(e) => {
+                println!("{}: certificate is NOT valid: {:?}", certificate, e);
+                process::exit(1);
+            }
+        }
+    }
+
+    if let Some(bundle) = matches.value_of("SIGN_BUNDLE") {
+        let key = matches.value_of("SIGNING_KEY").unwrap();
+        match cfti::types::Updater::sign_bundle(bundle, key) {
+            Ok(public_key) => {
+                println!("{}: signed with public key {}", bundle, public_key);
+                return;
+            }
+            Err(e) => {
+                println!("{}: unable to sign bundle: {:?}", bundle, e);
+                process::exit(1);
+            }
+        }
+    }
+
+    if let Some(results) = matches.subcommand_matches("results") {
+        let path = match matches.value_of("RESULTS_DB") {
+            Some(p) => p,
+            None => {
+                println!("The results subcommand requires --results-db");
+                process::exit(1);
+            }
+        };
+        let count = match results.value_of("COUNT").unwrap().parse() {
+            Ok(n) => n,
+            Err(e) => {
+                println!("Invalid count: {:?}", e);
+                process::exit(1);
+            }
+        };
+        let runs = cfti::results::ResultsDb::open_for_query(path)
+            .and_then(|db| db.recent_runs(count, results.value_of("SCENARIO")));
+        match runs {
+            Ok(runs) => {
+                println!("RUN\tSCENARIO\tJIG\tSTARTED\tDURATION\tRESULT\tFAILED");
+                for run in runs {
+                    let duration = match run.duration {
+                        None => "-".to_string(),
+                        Some(d) => format!("{:.1}", d),
+                    };
+                    let result = match run.result {
+                        None => "-".to_string(),
+                        Some(r) => r.to_string(),
+                    };
+                    println!("{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                             run.id,
+                             run.scenario,
+                             run.jig.unwrap_or("-".to_string()),
+                             run.started as u64,
+                             duration,
+                             result,
+                             run.failed.join(","));
+                }
+                return;
+            }
+            Err(e) => {
+                println!("{}: unable to read results: {:?}", path, e);
+                process::exit(1);
+            }
+        }
+    }
+
+    config.set_locale(matches.value_of("LOCALE"));
+    config.set_report_directory(matches.value_of("REPORT_DIR"));
     config.set_timeout(matches.value_of("TIMEOUT").unwrap().parse().unwrap());
 
     let default_cwd = match matches.value_of("DEFAULT_WORKING_DIRECTORY") {
@@ -66,8 +181,21 @@ fn main() {
     };
     config.set_default_working_directory(default_cwd);
 
+    // Tidy up result pipes left behind by an earlier jig-20 that was killed.
+    cfti::resultpipe::remove_stale();
+
     let mut controller = cfti::controller::Controller::new().unwrap();
 
+    if let Some(path) = matches.value_of("RESULTS_DB") {
+        match cfti::results::ResultsDb::open(path) {
+            Ok(db) => db.start(&controller),
+            Err(e) => {
+                println!("{}: unable to open results database: {:?}", path, e);
+                process::exit(1);
+            }
+        }
+    }
+
     // Add a simple logger to show us debug data.
     let bufwtr = BufferWriter::stderr(ColorChoice::Always);
     controller.listen(move |msg| {